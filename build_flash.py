@@ -1,10 +1,18 @@
+import subprocess
+
+layout = subprocess.run(
+    ['cargo', 'run', '-q', '--manifest-path', 'stm32/memory-map/Cargo.toml', '--bin', 'layout'],
+    check=True, capture_output=True, text=True).stdout
+regions = {name: (int(offset, 16), int(size, 16)) for name, offset, size in
+           (line.split() for line in layout.splitlines())}
+
 f = open('stm32_bootloader/target/bootloader.bin', 'rb')
 array = bytearray(f.read())
 f.close()
 
 # fill up to app
 s = len(array)
-for x in range(s, 0x5000):
+for x in range(s, regions['app'][0]):
     array.append(0xFF)
 
 f = open('stm32/target/app.bin', 'rb')
@@ -0,0 +1,285 @@
+//! Address claiming in the manner of J1939, without any io. A node claims an address on
+//! start, the claim holds if no other node claims the same address within
+//! `CLAIM_TIMEOUT`. On a conflict the node with the lower serial keeps the address and
+//! announces it again, the other one moves on to the next free address. Address 0 stays
+//! unused, a claim of it tells that the node found no free address. Addresses above
+//! `MAX_ADDRESS` are not for nodes.
+
+use crate::frame_id::SubId;
+use crate::frames::claim::Claim;
+use crate::frames::serial::Serial;
+use crate::frames::{Frame, Type};
+use crate::identifier::MAX_ADDRESS;
+
+/// How long a claim has to stay uncontested, in milliseconds.
+pub const CLAIM_TIMEOUT: u64 = 250;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum State {
+    Idle,
+    Claiming {
+        since: u64,
+    },
+    Claimed,
+    /// Every address is taken by a node with a lower serial.
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddressClaimer {
+    serial: Serial,
+    address: u8,
+    /// Addresses other nodes claimed, one bit each.
+    taken: [u32; 8],
+    state: State,
+}
+
+impl AddressClaimer {
+    pub fn new(serial: Serial, preferred: u8) -> Self {
+        Self {
+            serial,
+            address: preferred.clamp(1, MAX_ADDRESS),
+            taken: [0; 8],
+            state: State::Idle,
+        }
+    }
+
+    /// An address derived from the serial, so that nodes rarely start with the same one.
+    pub fn preferred_address(serial: &Serial) -> u8 {
+        crc8_fast::calc(&serial.0, 0).clamp(1, MAX_ADDRESS)
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    #[inline]
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// The `SubId` to send with, once the address is claimed.
+    pub fn sub_id(&self) -> Option<SubId> {
+        match self.state {
+            State::Claimed => Some(SubId::for_node(&self.serial, self.address)),
+            _ => None,
+        }
+    }
+
+    /// Starts over claiming the current address.
+    pub fn start(&mut self, now: u64) -> Frame {
+        self.taken = [0; 8];
+        self.state = State::Claiming { since: now };
+        self.claim()
+    }
+
+    /// Takes `address` without waiting, for an address given by the host. Nodes that
+    /// claimed it before settle the conflict as usual.
+    pub fn assign(&mut self, address: u8) -> Frame {
+        self.address = address.clamp(1, MAX_ADDRESS);
+        self.state = State::Claimed;
+        self.claim()
+    }
+
+    /// Completes the claim once `CLAIM_TIMEOUT` passed without a conflict.
+    pub fn poll(&mut self, now: u64) {
+        if let State::Claiming { since } = self.state {
+            if now.saturating_sub(since) >= CLAIM_TIMEOUT {
+                self.state = State::Claimed;
+            }
+        }
+    }
+
+    /// Handles a received frame, returns the claim to send if it has to be announced.
+    pub fn on_frame(&mut self, frame: &Frame, now: u64) -> Option<Frame> {
+        let other = match frame {
+            Frame::AddressClaim(Type::Remote) => {
+                return match self.state {
+                    State::Claiming { .. } | State::Claimed => Some(self.claim()),
+                    State::Failed => {
+                        Some(Frame::AddressClaim(Type::Data(Claim::new(self.serial, 0))))
+                    }
+                    State::Idle => None,
+                }
+            }
+            Frame::AddressClaim(Type::Data(v)) if v.serial != self.serial && v.address != 0 => v,
+            _ => return None,
+        };
+
+        if !matches!(self.state, State::Claiming { .. } | State::Claimed)
+            || other.address != self.address
+        {
+            self.set_taken(other.address);
+            return None;
+        }
+
+        if self.serial.0 < other.serial.0 {
+            // defend
+            return Some(self.claim());
+        }
+        self.set_taken(other.address);
+        match self.next_free() {
+            Some(address) => {
+                self.address = address;
+                self.state = State::Claiming { since: now };
+                Some(self.claim())
+            }
+            None => {
+                self.state = State::Failed;
+                Some(Frame::AddressClaim(Type::Data(Claim::new(self.serial, 0))))
+            }
+        }
+    }
+
+    fn claim(&self) -> Frame {
+        Frame::AddressClaim(Type::Data(Claim::new(self.serial, self.address)))
+    }
+
+    fn set_taken(&mut self, address: u8) {
+        self.taken[address as usize / 32] |= 1 << (address % 32);
+    }
+
+    fn is_taken(&self, address: u8) -> bool {
+        self.taken[address as usize / 32] & (1 << (address % 32)) != 0
+    }
+
+    /// The first address after the current one that nobody claimed, wrapping around.
+    fn next_free(&self) -> Option<u8> {
+        (1..=MAX_ADDRESS)
+            .map(|n| (((self.address as u16 - 1 + n as u16) % MAX_ADDRESS as u16) + 1) as u8)
+            .find(|&a| !self.is_taken(a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serial(n: u8) -> Serial {
+        Serial([1, 2, 3, 4, n])
+    }
+
+    fn claim(serial: Serial, address: u8) -> Frame {
+        Frame::AddressClaim(Type::Data(Claim::new(serial, address)))
+    }
+
+    #[test]
+    fn uncontested() {
+        let mut c = AddressClaimer::new(serial(1), 10);
+        assert_eq!(c.sub_id(), None);
+        assert_eq!(c.start(0), claim(serial(1), 10));
+
+        c.poll(CLAIM_TIMEOUT - 1);
+        assert_eq!(c.state(), State::Claiming { since: 0 });
+        c.poll(CLAIM_TIMEOUT);
+        assert_eq!(c.state(), State::Claimed);
+        assert_eq!(c.sub_id(), Some(SubId::for_node(&serial(1), 10)));
+
+        assert_eq!(
+            c.on_frame(&Frame::AddressClaim(Type::Remote), 300),
+            Some(claim(serial(1), 10))
+        );
+        // claims of other addresses don't matter
+        assert_eq!(c.on_frame(&claim(serial(0), 11), 300), None);
+        assert_eq!(c.state(), State::Claimed);
+    }
+
+    #[test]
+    fn conflicts() {
+        // the lower serial defends
+        let mut c = AddressClaimer::new(serial(1), 10);
+        c.start(0);
+        assert_eq!(
+            c.on_frame(&claim(serial(2), 10), 100),
+            Some(claim(serial(1), 10))
+        );
+        c.poll(CLAIM_TIMEOUT);
+        assert_eq!(c.state(), State::Claimed);
+        assert_eq!(
+            c.on_frame(&claim(serial(2), 10), 300),
+            Some(claim(serial(1), 10))
+        );
+        assert_eq!(c.state(), State::Claimed);
+
+        // the higher one yields, skipping taken addresses, and claims again from scratch
+        let mut c = AddressClaimer::new(serial(3), 10);
+        c.start(0);
+        c.on_frame(&claim(serial(9), 11), 50);
+        assert_eq!(
+            c.on_frame(&claim(serial(1), 10), 100),
+            Some(claim(serial(3), 12))
+        );
+        c.poll(CLAIM_TIMEOUT);
+        assert_eq!(c.state(), State::Claiming { since: 100 });
+        c.poll(100 + CLAIM_TIMEOUT);
+        assert_eq!(c.sub_id(), Some(SubId::for_node(&serial(3), 12)));
+
+        // losing a claimed address
+        assert_eq!(
+            c.on_frame(&claim(serial(0), 12), 500),
+            Some(claim(serial(3), 13))
+        );
+        assert_eq!(c.sub_id(), None);
+    }
+
+    #[test]
+    fn wraps_and_fails() {
+        let mut c = AddressClaimer::new(serial(5), 255);
+        assert_eq!(c.address(), MAX_ADDRESS);
+        c.start(0);
+        assert_eq!(
+            c.on_frame(&claim(serial(1), MAX_ADDRESS), 0),
+            Some(claim(serial(5), 1))
+        );
+
+        for address in 2..MAX_ADDRESS {
+            c.on_frame(&claim(serial(1), address), 0);
+        }
+        assert_eq!(
+            c.on_frame(&claim(serial(1), 1), 0),
+            Some(claim(serial(5), 0))
+        );
+        assert_eq!(c.state(), State::Failed);
+        assert_eq!(
+            c.on_frame(&Frame::AddressClaim(Type::Remote), 0),
+            Some(claim(serial(5), 0))
+        );
+    }
+
+    #[test]
+    fn assign() {
+        let mut c = AddressClaimer::new(serial(1), 10);
+        assert_eq!(c.assign(20), claim(serial(1), 20));
+        assert_eq!(c.sub_id(), Some(SubId::for_node(&serial(1), 20)));
+    }
+
+    /// Nodes that all prefer the same address end up with different ones.
+    #[test]
+    fn bus() {
+        let mut nodes: [_; 4] =
+            core::array::from_fn(|n| AddressClaimer::new(serial(4 - n as u8), 7));
+        let mut queue = heapless::Deque::<(usize, Frame), 64>::new();
+        for (n, node) in nodes.iter_mut().enumerate() {
+            queue.push_back((n, node.start(0))).unwrap();
+        }
+
+        let mut now = 0;
+        while nodes.iter().any(|v| v.sub_id().is_none()) {
+            while let Some((from, frame)) = queue.pop_front() {
+                for (n, node) in nodes.iter_mut().enumerate() {
+                    if n != from {
+                        if let Some(answer) = node.on_frame(&frame, now) {
+                            queue.push_back((n, answer)).unwrap();
+                        }
+                    }
+                }
+            }
+            now += 10;
+            nodes.iter_mut().for_each(|v| v.poll(now));
+        }
+
+        let addresses: heapless::Vec<u8, 4> = nodes.iter().map(|v| v.address()).collect();
+        assert_eq!(addresses, [10, 9, 8, 7]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Which node acts on a received frame. `to` is the destination of the frame's `Header`, the
+//! node a frame is for in the legacy layout, or the node it is from for what the nodes send.
+//! `SubId(0)` is the broadcast, nodes without an address send with it as well. Nodes compare
+//! it with their `SubId` as `Layout::carried` gives it.
+
+use crate::frame_id::{FrameId, SubId};
+use crate::frames::error::{Code, Error};
+use crate::frames::Routing;
+use crate::identifier::{Header, Layout};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Target {
+    /// Every node handles it whatever the `SubId`, the data names the node if it matters.
+    All,
+    /// Requests: the node with the `SubId`, every node for the broadcast.
+    NodeOrAll,
+    /// Commands: only the node with the `SubId`, broadcasting them is an error.
+    Node,
+    /// Sent by the nodes, other nodes leave them alone.
+    Host,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    Act,
+    Ignore,
+    /// Answer with the error frame.
+    Reject(Error),
+}
+
+/// What the node with the `SubId` `own` does with `frame` that came with `to`.
+pub fn check(frame: &impl Routing, to: SubId, own: SubId) -> Verdict {
+    let reject = |code| Verdict::Reject(Error::new(frame.frame_id(), to, code));
+    match frame.target() {
+        Target::All => Verdict::Act,
+        Target::NodeOrAll if to == SubId(0) || to == own => Verdict::Act,
+        Target::Node if to == SubId(0) => reject(Code::Broadcast),
+        Target::Node if own.is_valid() && to == own => Verdict::Act,
+        Target::Node if own.is_valid() && to.dyn_id() == own.dyn_id() => reject(Code::WrongSubId),
+        _ => Verdict::Ignore,
+    }
+}
+
+/// Acceptance filters for the node with `own`, as `(id, mask)` of the 29 bit identifier.
+/// They let through what `check` doesn't ignore: the broadcast, frames with the node's
+/// address, whatever the rest of the `SubId`, and the `Target::All` frames by their frame id
+/// alone.
+pub fn acceptance_filters(own: SubId, layout: Layout) -> heapless::Vec<(u32, u32), 5> {
+    // the destination, in the legacy layout the whole `SubId` for the broadcast
+    let (broadcast, address) = match layout {
+        Layout::Addressed => (0xFF << 8, 0xFF << 8),
+        Layout::Legacy => (
+            FrameId::sub_id_mask(),
+            FrameId::sub_id_mask() & !(FrameId::sub_id_mask() << 8),
+        ),
+    };
+    let to = |sub_id| layout.encode(&Header::new(FrameId::Serial, SubId::HOST, sub_id));
+
+    let mut filters = heapless::Vec::new();
+    filters
+        .push((to(SubId::BROADCAST) & broadcast, broadcast))
+        .unwrap();
+    if own.is_valid() {
+        filters.push((to(own) & address, address)).unwrap();
+    }
+    let mask = layout.frame_id_mask();
+    for id in FrameId::ALL.iter().filter(|v| v.to_all()) {
+        let raw = layout.encode(&Header::new(*id, SubId::HOST, SubId::BROADCAST));
+        filters.push((raw & mask, mask)).unwrap();
+    }
+    filters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::serial::Serial;
+    use crate::frames::{Frame, Type};
+
+    #[test]
+    fn check_sub_id() {
+        let own = SubId::for_node(&Serial([1, 2, 3, 4, 5]), 7);
+        let other = SubId::for_node(&Serial([1, 2, 3, 4, 6]), 8);
+        let same_address = SubId::from([own.split()[0] ^ 1, 7]);
+        let start = Frame::FirmwareStartUpdate;
+        let status = Frame::Status(Type::Remote);
+
+        assert_eq!(check(&start, own, own), Verdict::Act);
+        assert_eq!(check(&start, other, own), Verdict::Ignore);
+        assert_eq!(
+            check(&start, SubId(0), own),
+            Verdict::Reject(Error::new(start.id(), SubId(0), Code::Broadcast))
+        );
+        assert_eq!(
+            check(&start, same_address, own),
+            Verdict::Reject(Error::new(start.id(), same_address, Code::WrongSubId))
+        );
+        // a node without an address can't tell
+        assert_eq!(check(&start, other, SubId(0)), Verdict::Ignore);
+
+        assert_eq!(check(&status, SubId(0), own), Verdict::Act);
+        assert_eq!(check(&status, own, own), Verdict::Act);
+        assert_eq!(check(&status, other, own), Verdict::Ignore);
+        assert_eq!(check(&status, same_address, own), Verdict::Ignore);
+
+        let claim = Frame::AddressClaim(Type::Remote);
+        assert_eq!(check(&claim, other, own), Verdict::Act);
+        let answer = Frame::Status(Type::Data(crate::frames::status::Status::new(
+            crate::frames::status::Mode::Application,
+        )));
+        assert_eq!(check(&answer, own, own), Verdict::Ignore);
+    }
+
+    #[test]
+    fn filters() {
+        let own = SubId::for_node(&Serial([1, 2, 3, 4, 5]), 7);
+        let other = SubId::for_node(&Serial([1, 2, 3, 4, 6]), 8);
+        let sub_ids = [
+            SubId(0),
+            own,
+            SubId::from([own.split()[0] ^ 1, 7]),
+            other,
+            SubId(0xFFFF),
+        ];
+
+        for layout in [Layout::Addressed, Layout::Legacy] {
+            for own in [SubId(0), own] {
+                let filters = acceptance_filters(own, layout);
+                let accepts = |raw: u32| filters.iter().any(|(id, mask)| raw & mask == *id);
+                let mut seen = heapless::Vec::<FrameId, 32>::new();
+                crate::frames::samples(|frame| {
+                    if !seen.contains(&frame.id()) {
+                        seen.push(frame.id()).unwrap();
+                    }
+                    for sub_id in sub_ids {
+                        let raw = layout.encode(&Header::with_node(&frame, sub_id));
+                        let to = layout.decode(raw).unwrap().to;
+                        if check(&frame, to, layout.carried(own)) != Verdict::Ignore {
+                            assert!(accepts(raw), "{:?} {:?} {:?} {:?}", layout, own, frame, to);
+                        }
+                    }
+                });
+                assert_eq!(seen.len(), FrameId::ALL.len());
+                // traffic of other nodes stays out
+                let part = Header::new(FrameId::FirmwareUploadPart, SubId::HOST, other);
+                assert!(!accepts(layout.encode(&part)));
+            }
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::frames::serial::Serial;
+pub use crate::frames::FrameId;
+use core::ops::RangeInclusive;
 use num_traits::FromPrimitive;
 use num_traits::ToPrimitive;
 
@@ -5,6 +8,11 @@ use num_traits::ToPrimitive;
 pub struct SubId(pub u16);
 
 impl SubId {
+    /// Every node, or a node without an address for what the nodes send.
+    pub const BROADCAST: Self = Self(0);
+    /// The host, in `identifier::Layout::Addressed`.
+    pub const HOST: Self = Self(crate::identifier::HOST as u16);
+
     pub fn is_valid(&self) -> bool {
         self.0 != 0
     }
@@ -12,6 +20,22 @@ impl SubId {
     pub fn split(&self) -> [u8; 2] {
         self.0.to_be_bytes()
     }
+
+    /// What a node takes when it gets `dyn_id`: crc8 of its serial and the dyn_id, then
+    /// the dyn_id.
+    pub fn for_node(serial: &Serial, dyn_id: u8) -> Self {
+        let crc = crc8_fast::calc(&dyn_id.to_be_bytes(), crc8_fast::calc(&serial.0, 0));
+        Self::from([crc, dyn_id])
+    }
+
+    pub fn dyn_id(&self) -> u8 {
+        self.split()[1]
+    }
+
+    /// Whether it is the SubId of the node with `serial`.
+    pub fn belongs_to(&self, serial: &Serial) -> bool {
+        *self == Self::for_node(serial, self.dyn_id())
+    }
 }
 
 impl From<SubId> for [u8; 2] {
@@ -26,24 +50,51 @@ impl From<[u8; 2]> for SubId {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
-pub enum FrameId {
-    Serial = 8000,
-    DynId = 8001,
+/// What a frame is for, it decides the priority on the bus.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Class {
+    /// Addresses of the nodes.
+    Management,
+    /// Commands, errors and the flow control of uploads.
+    Control,
+    /// The state of the nodes.
+    Status,
+    /// Firmware images, they give way to everything else.
+    Bulk,
+}
 
-    HardwareVersion = 8010,
+impl Class {
+    /// The priority field of the addressed layout, the lower one wins.
+    pub const fn priority(&self) -> u8 {
+        match self {
+            Class::Management => 1,
+            Class::Control => 3,
+            Class::Status => 5,
+            Class::Bulk => 7,
+        }
+    }
 
-    FirmwareVersion = 8020,
-    PendingFirmwareVersion = 8021,
-    FirmwareUploadPartChangePos = 8025, // to host
-    FirmwareUploadPause = 8026,         // to host
-    FirmwareUploadPart = 8028,          // from host
-    FirmwareUploadFinished = 8029,         // from host
-    FirmwareStartUpdate = 8030,         // from host
+    /// The frame ids reserved for the class, in the order of the priorities so that the
+    /// legacy layout arbitrates the same. `frames::app::APP_IDS` sit between `Status` and
+    /// `Bulk`.
+    pub const fn ids(&self) -> RangeInclusive<u16> {
+        match self {
+            Class::Management => RangeInclusive::new(8000, 8009),
+            Class::Control => RangeInclusive::new(8010, 8029),
+            Class::Status => RangeInclusive::new(8030, 8099),
+            Class::Bulk => RangeInclusive::new(8180, 8191),
+        }
+    }
 }
 
 impl FrameId {
     const LENGTH_BIT: usize = 13;
+
+    #[inline]
+    pub const fn priority(&self) -> u8 {
+        self.class().priority()
+    }
+
     pub const fn max_id() -> u16 {
         (2usize.pow(Self::LENGTH_BIT as u32) as u16) - 1
     }
@@ -62,6 +113,11 @@ impl FrameId {
             .map(|v| (v, Self::extract_sub_id(value)))
     }
 
+    /// The bits of the `SubId` in the identifier.
+    pub const fn sub_id_mask() -> u32 {
+        0xFFFF << Self::LENGTH_BIT
+    }
+
     #[inline]
     pub fn extract_sub_id(value: u32) -> SubId {
         SubId(((value >> Self::LENGTH_BIT) & 0xFFFF) as u16)
@@ -69,7 +125,13 @@ impl FrameId {
 
     #[inline]
     pub fn as_raw(&self, sub_id: SubId) -> u32 {
-        ((sub_id.0 as u32) << Self::LENGTH_BIT) | ((self.to_u16().unwrap() & Self::max_id()) as u32)
+        Self::raw_id(self.to_u16().unwrap(), sub_id)
+    }
+
+    /// `as_raw` for any frame id, app frames' too.
+    #[inline]
+    pub fn raw_id(id: u16, sub_id: SubId) -> u32 {
+        ((sub_id.0 as u32) << Self::LENGTH_BIT) | ((id & Self::max_id()) as u32)
     }
 }
 
@@ -83,6 +145,44 @@ impl From<FrameId> for u16 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn sub_id_for_node() {
+        let serial = Serial([1, 2, 3, 4, 5]);
+        let id = SubId::for_node(&serial, 10);
+        assert_eq!(id.dyn_id(), 10);
+        assert!(id.belongs_to(&serial));
+        assert_eq!(SubId::for_node(&serial, 10), id);
+        assert!(!SubId::from([id.split()[0] ^ 1, 10]).belongs_to(&serial));
+    }
+
+    /// Lower legacy identifiers win the arbitration, so they follow the priorities.
+    #[test]
+    fn legacy_order() {
+        let classes = [
+            Class::Management,
+            Class::Control,
+            Class::Status,
+            Class::Bulk,
+        ];
+        for pair in classes.windows(2) {
+            assert!(pair[0].priority() < pair[1].priority());
+            assert!(pair[0].ids().end() < pair[1].ids().start());
+        }
+        let app = crate::frames::app::APP_IDS;
+        assert!(Class::Status.ids().end() < app.start() && app.end() < Class::Bulk.ids().start());
+
+        for a in FrameId::ALL {
+            assert!(a.class().ids().contains(&u16::from(*a)), "{:?}", a);
+            for b in FrameId::ALL {
+                for sub_id in [SubId(0), SubId(0x1234), SubId(0xFFFF)] {
+                    if a.priority() < b.priority() {
+                        assert!(a.as_raw(sub_id) < b.as_raw(sub_id), "{:?} {:?}", a, b);
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn frame_id() {
         assert_eq!(FrameId::from_u16(65535), None);
@@ -0,0 +1,271 @@
+//! Frames of a product, next to the core frames of updates and addresses. The product crate
+//! implements `AppFrame` for an enum of its frames with ids from `APP_IDS`, and nodes and
+//! hosts handle `AnyFrame` of it:
+//!
+//! ```
+//! use canbus_common::addressing::Target;
+//! use canbus_common::frame_id::Class;
+//! use canbus_common::frames::app::{AnyFrame, AppFrame};
+//! use canbus_common::frames::{ParseError, ParserType, RawType};
+//!
+//! #[derive(Debug, Copy, Clone, PartialEq)]
+//! enum Sensor {
+//!     Temperature(i16),
+//! }
+//!
+//! impl AppFrame for Sensor {
+//!     fn id(&self) -> u16 {
+//!         8100
+//!     }
+//!
+//!     fn class(&self) -> Class {
+//!         Class::Status
+//!     }
+//!
+//!     fn target(&self) -> Target {
+//!         Target::Host
+//!     }
+//!
+//!     fn parse_frame(id: u16, data: ParserType) -> Result<Self, ParseError> {
+//!         match (id, data) {
+//!             (8100, ParserType::Data(&[a, b])) => Ok(Sensor::Temperature(i16::from_be_bytes([a, b]))),
+//!             (8100, _) => Err(ParseError::WrongData),
+//!             _ => Err(ParseError::UnknownId),
+//!         }
+//!     }
+//!
+//!     fn raw(&self) -> RawType {
+//!         match self {
+//!             Sensor::Temperature(v) => RawType::new_data(v.to_be_bytes()),
+//!         }
+//!     }
+//! }
+//!
+//! let frame = AnyFrame::App(Sensor::Temperature(-40));
+//! assert_eq!(AnyFrame::parse_frame(frame.id(), ParserType::Data(&[0xFF, 0xD8])), Ok(frame));
+//! ```
+
+use crate::addressing::Target;
+use crate::frame_id::{Class, FrameId};
+use crate::frames::{Frame, ParseError, ParserType, RawType, Routing};
+use core::ops::RangeInclusive;
+
+/// The frame ids left to products, both layouts carry them. In the legacy layout they come
+/// after the core frames of `Class::Status` and before those of `Class::Bulk`.
+pub const APP_IDS: RangeInclusive<u16> = 8100..=8179;
+
+pub trait AppFrame: Sized + Copy + core::fmt::Debug + PartialEq {
+    /// One of `APP_IDS`.
+    fn id(&self) -> u16;
+    /// The priority on the bus, as for the core frames.
+    fn class(&self) -> Class;
+    /// Which nodes act on it, see `addressing::check`.
+    fn target(&self) -> Target;
+    /// `id` is one of `APP_IDS`, `ParseError::UnknownId` for those the product doesn't use.
+    fn parse_frame(id: u16, data: ParserType) -> Result<Self, ParseError>;
+    fn raw(&self) -> RawType;
+}
+
+/// For nodes and hosts without frames of their own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NoApp {}
+
+impl AppFrame for NoApp {
+    fn id(&self) -> u16 {
+        match *self {}
+    }
+
+    fn class(&self) -> Class {
+        match *self {}
+    }
+
+    fn target(&self) -> Target {
+        match *self {}
+    }
+
+    fn parse_frame(_: u16, _: ParserType) -> Result<Self, ParseError> {
+        Err(ParseError::UnknownId)
+    }
+
+    fn raw(&self) -> RawType {
+        match *self {}
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AnyFrame<A> {
+    Core(Frame),
+    App(A),
+}
+
+impl<A: AppFrame> AnyFrame<A> {
+    pub fn parse_frame(id: u16, data: ParserType) -> Result<Self, ParseError> {
+        match FrameId::try_from_u16(id) {
+            Some(id) => Frame::parse_frame(id, data).map(AnyFrame::Core),
+            None if APP_IDS.contains(&id) => A::parse_frame(id, data).map(AnyFrame::App),
+            None => Err(ParseError::UnknownId),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.frame_id()
+    }
+
+    pub fn raw(&self) -> RawType {
+        match self {
+            AnyFrame::Core(v) => v.raw_frame().1,
+            AnyFrame::App(v) => v.raw(),
+        }
+    }
+}
+
+impl<A: AppFrame> Routing for AnyFrame<A> {
+    fn frame_id(&self) -> u16 {
+        match self {
+            AnyFrame::Core(v) => v.frame_id(),
+            AnyFrame::App(v) => v.id(),
+        }
+    }
+
+    fn class(&self) -> Class {
+        match self {
+            AnyFrame::Core(v) => Routing::class(v),
+            AnyFrame::App(v) => v.class(),
+        }
+    }
+
+    fn target(&self) -> Target {
+        match self {
+            AnyFrame::Core(v) => v.target(),
+            AnyFrame::App(v) => v.target(),
+        }
+    }
+}
+
+impl<A> From<Frame> for AnyFrame<A> {
+    fn from(v: Frame) -> Self {
+        AnyFrame::Core(v)
+    }
+}
+
+/// A core frame id or one of `APP_IDS`.
+pub fn known(id: u16) -> bool {
+    FrameId::try_from_u16(id).is_some() || APP_IDS.contains(&id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addressing::{check, Verdict};
+    use crate::frame_id::SubId;
+    use crate::frames::error::{Code, Error};
+    use crate::frames::Type;
+    use crate::identifier::{Header, Layout};
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    enum Light {
+        Set(bool),
+        Level(u8),
+    }
+
+    impl AppFrame for Light {
+        fn id(&self) -> u16 {
+            match self {
+                Light::Set(_) => 8100,
+                Light::Level(_) => 8101,
+            }
+        }
+
+        fn class(&self) -> Class {
+            match self {
+                Light::Set(_) => Class::Control,
+                Light::Level(_) => Class::Status,
+            }
+        }
+
+        fn target(&self) -> Target {
+            match self {
+                Light::Set(_) => Target::Node,
+                Light::Level(_) => Target::Host,
+            }
+        }
+
+        fn parse_frame(id: u16, data: ParserType) -> Result<Self, ParseError> {
+            match (id, data) {
+                (8100, ParserType::Data(&[v])) => Ok(Light::Set(v != 0)),
+                (8101, ParserType::Data(&[v])) => Ok(Light::Level(v)),
+                (8100 | 8101, _) => Err(ParseError::WrongData),
+                _ => Err(ParseError::UnknownId),
+            }
+        }
+
+        fn raw(&self) -> RawType {
+            match self {
+                Light::Set(v) => RawType::new_data([u8::from(*v)]),
+                Light::Level(v) => RawType::new_data([*v]),
+            }
+        }
+    }
+
+    fn parse(frame: &AnyFrame<Light>) -> Result<AnyFrame<Light>, ParseError> {
+        match frame.raw() {
+            RawType::Data(v) => AnyFrame::parse_frame(frame.id(), ParserType::Data(&v)),
+            RawType::Remote(v) => AnyFrame::parse_frame(frame.id(), ParserType::Remote(v)),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for frame in [
+            AnyFrame::App(Light::Set(true)),
+            AnyFrame::App(Light::Level(80)),
+            AnyFrame::Core(Frame::Status(Type::Remote)),
+            AnyFrame::Core(Frame::FirmwareStartUpdate),
+        ] {
+            assert_eq!(parse(&frame), Ok(frame));
+        }
+
+        assert_eq!(
+            AnyFrame::<Light>::parse_frame(8102, ParserType::Data(&[1])),
+            Err(ParseError::UnknownId)
+        );
+        assert_eq!(
+            AnyFrame::<Light>::parse_frame(8100, ParserType::Remote(1)),
+            Err(ParseError::WrongData)
+        );
+        // outside of the ids left to products
+        assert_eq!(
+            AnyFrame::<Light>::parse_frame(8050, ParserType::Data(&[1])),
+            Err(ParseError::UnknownId)
+        );
+        assert_eq!(
+            AnyFrame::<NoApp>::parse_frame(8100, ParserType::Data(&[1])),
+            Err(ParseError::UnknownId)
+        );
+    }
+
+    #[test]
+    fn on_the_bus() {
+        let own = SubId::from([0, 7]);
+        let set = AnyFrame::App(Light::Set(true));
+        for layout in [Layout::Addressed, Layout::Legacy] {
+            let header = layout
+                .decode(layout.encode(&Header::with_node(&set, own)))
+                .unwrap();
+            assert_eq!((header.id, header.to), (8100, own));
+        }
+        assert_eq!(
+            Header::with_node(&set, own).priority,
+            Class::Control.priority()
+        );
+
+        assert_eq!(check(&set, own, own), Verdict::Act);
+        assert_eq!(
+            check(&set, SubId(0), own),
+            Verdict::Reject(Error::new(8100_u16, SubId(0), Code::Broadcast))
+        );
+        let level = AnyFrame::App(Light::Level(3));
+        assert_eq!(check(&level, own, own), Verdict::Ignore);
+        assert_eq!(Header::from_node(&level, own).to, SubId::HOST);
+    }
+}
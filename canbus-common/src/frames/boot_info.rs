@@ -0,0 +1,72 @@
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+pub enum BootReason {
+    Unknown = 0,
+    PowerOn = 1,
+    Pin = 2,
+    Software = 3,
+    IndependentWatchdog = 4,
+    WindowWatchdog = 5,
+    LowPower = 6,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+pub enum UpdateResult {
+    None = 0,
+    Success = 1,
+    CopyError = 2,
+    VerifyError = 3,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootInfo {
+    pub boot_reason: BootReason,
+    pub update_result: UpdateResult,
+}
+
+impl BootInfo {
+    pub fn new(boot_reason: BootReason, update_result: UpdateResult) -> Self {
+        Self {
+            boot_reason,
+            update_result,
+        }
+    }
+}
+
+impl TryFrom<[u8; 2]> for BootInfo {
+    type Error = ();
+
+    fn try_from(v: [u8; 2]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            boot_reason: BootReason::from_u8(v[0]).ok_or(())?,
+            update_result: UpdateResult::from_u8(v[1]).ok_or(())?,
+        })
+    }
+}
+
+impl From<BootInfo> for [u8; 2] {
+    fn from(v: BootInfo) -> Self {
+        [
+            v.boot_reason.to_u8().unwrap(),
+            v.update_result.to_u8().unwrap(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_info() {
+        let b = BootInfo::new(BootReason::Software, UpdateResult::Success);
+        let arr: [u8; 2] = b.into();
+        assert_eq!(arr, [3, 1]);
+        assert_eq!(BootInfo::try_from(arr), Ok(b));
+
+        assert_eq!(BootInfo::try_from([7, 0]), Err(()));
+        assert_eq!(BootInfo::try_from([0, 4]), Err(()));
+    }
+}
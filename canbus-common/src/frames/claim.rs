@@ -0,0 +1,44 @@
+use crate::frames::serial::Serial;
+
+/// A node claiming `address`, its serial decides who keeps an address claimed twice.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Claim {
+    pub serial: Serial,
+    pub address: u8,
+}
+
+impl Claim {
+    pub fn new(serial: Serial, address: u8) -> Self {
+        Self { serial, address }
+    }
+}
+
+impl From<[u8; 6]> for Claim {
+    fn from(value: [u8; 6]) -> Self {
+        Self {
+            serial: Serial::from(<[u8; 5]>::try_from(&value[0..5]).unwrap()),
+            address: value[5],
+        }
+    }
+}
+
+impl From<Claim> for [u8; 6] {
+    fn from(c: Claim) -> Self {
+        let mut data = [0_u8; 6];
+        data[..5].copy_from_slice(&c.serial.0);
+        data[5] = c.address;
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let c = Claim::new(Serial::from([1, 2, 3, 4, 5]), 10);
+        assert_eq!(<[u8; 6]>::from(c), [1, 2, 3, 4, 5, 10]);
+        assert_eq!(Claim::from([1, 2, 3, 4, 5, 10]), c);
+    }
+}
@@ -0,0 +1,238 @@
+/// Specifies every core frame once:
+///
+/// ```text
+/// /// Docs of the `Frame` variant.
+/// Name(Payload) = frame id {
+///     class: the `Class`,
+///     dlc: the data lengths, as a pattern,
+///     remote: its dlc => the `Target` of the remote frame,
+///     data: the `Target` of the data frame,
+///     samples: [payloads for the round trip test],
+/// },
+/// ```
+///
+/// Frames that may be remote have a `Type` of the payload, the others the payload itself, or
+/// none. Payloads are `Payload`s. It generates `FrameId` with `ALL`, `class` and `to_all`,
+/// `Frame` with `parse_frame`, `raw_frame`, `id` and `target`, the `samples` for tests, and a
+/// test that the samples make it through `raw_frame` and `parse_frame` and that other lengths
+/// are rejected.
+macro_rules! define_frames {
+    (
+        $(
+            $(#[$meta:meta])*
+            $name:ident $(($payload:ty))? = $id:literal {
+                class: $class:ident,
+                dlc: $dlc:pat,
+                $(remote: $remote:literal => $remote_target:ident,)?
+                data: $target:ident,
+                $(samples: [$($sample:expr),* $(,)?],)?
+            }
+        ),* $(,)?
+    ) => {
+        #[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+        pub enum FrameId {
+            $($name = $id,)*
+        }
+
+        impl FrameId {
+            /// Every core frame id, in the order they are specified.
+            pub const ALL: &'static [FrameId] = &[$(FrameId::$name,)*];
+
+            pub const fn class(&self) -> $crate::frame_id::Class {
+                match self {
+                    $(FrameId::$name => $crate::frame_id::Class::$class,)*
+                }
+            }
+
+            /// Whether the data or the remote frame with the id is `Target::All`, every node
+            /// handles it whatever the `SubId`.
+            pub const fn to_all(&self) -> bool {
+                match self {
+                    $(FrameId::$name => define_frames!(@to_all [$($remote_target)?] $target),)*
+                }
+            }
+        }
+
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        pub enum Frame {
+            $(
+                $(#[$meta])*
+                $name $(($payload))?,
+            )*
+        }
+
+        impl Frame {
+            // a `dlc` of `_` leaves the other lengths unreachable
+            #[allow(unreachable_patterns)]
+            pub fn parse_frame(
+                frame_id: FrameId,
+                data: $crate::frames::ParserType,
+            ) -> Result<Frame, $crate::frames::ParseError> {
+                match frame_id {
+                    $(FrameId::$name => {
+                        define_frames!(@parse data, $name [$($payload)?] [$($remote)?] $dlc)
+                    })*
+                }
+            }
+
+            pub fn raw_frame(&self) -> (FrameId, $crate::frames::RawType) {
+                let raw = match self {
+                    $(define_frames!(@pat $name [$($payload)?] v) => {
+                        define_frames!(@raw v [$($payload)?] [$($remote)?])
+                    })*
+                };
+                (self.id(), raw)
+            }
+
+            #[inline]
+            pub fn id(&self) -> FrameId {
+                match self {
+                    $(Frame::$name { .. } => FrameId::$name,)*
+                }
+            }
+
+            pub fn target(&self) -> $crate::addressing::Target {
+                match self {
+                    $(define_frames!(@remote_pat $name [$($payload)?] [$($remote)?] v) => {
+                        define_frames!(@target v [$($remote_target)?] $target)
+                    })*
+                }
+            }
+        }
+
+        /// Calls `f` with the samples of every frame, the remote frame too for those that
+        /// may be remote.
+        #[cfg(test)]
+        pub(crate) fn samples(mut f: impl FnMut(Frame)) {
+            $(define_frames!(@samples f $name [$($payload)?] [$($remote)?] [$($($sample),*)?]);)*
+        }
+
+        #[cfg(test)]
+        mod round_trip {
+            use super::*;
+
+            fn check(frame: Frame) {
+                let (id, raw) = frame.raw_frame();
+                assert_eq!(id, frame.id());
+                let parsed = match &raw {
+                    RawType::Data(v) => Frame::parse_frame(id, ParserType::Data(v)),
+                    RawType::Remote(len) => Frame::parse_frame(id, ParserType::Remote(*len)),
+                };
+                assert_eq!(parsed, Ok(frame), "{:?}", raw);
+            }
+
+            #[test]
+            #[allow(unreachable_patterns)]
+            fn frames() {
+                samples(check);
+                $(
+                    for len in 0..=8 {
+                        match len {
+                            $dlc => {}
+                            _ => assert_eq!(
+                                Frame::parse_frame(FrameId::$name, ParserType::Data(&[0; 8][..len])),
+                                Err(ParseError::WrongDataSize),
+                                "{:?} {}",
+                                FrameId::$name,
+                                len
+                            ),
+                        }
+                    }
+                    define_frames!(@remote_dlc $name [$($remote)?]);
+                )*
+            }
+        }
+    };
+
+    (@parse $data:ident, $name:ident [] [] $dlc:pat) => {
+        match $data {
+            $crate::frames::ParserType::Remote(_) => Err($crate::frames::ParseError::RemoteFrame),
+            $crate::frames::ParserType::Data(data) => match data.len() {
+                $dlc => Ok(Frame::$name),
+                _ => Err($crate::frames::ParseError::WrongDataSize),
+            },
+        }
+    };
+    (@parse $data:ident, $name:ident [$payload:ty] [] $dlc:pat) => {
+        match $data {
+            $crate::frames::ParserType::Remote(_) => Err($crate::frames::ParseError::RemoteFrame),
+            $crate::frames::ParserType::Data(data) => match data.len() {
+                $dlc => $crate::frames::payload::Payload::decode(data).map(Frame::$name),
+                _ => Err($crate::frames::ParseError::WrongDataSize),
+            },
+        }
+    };
+    (@parse $data:ident, $name:ident [$payload:ty] [$remote:literal] $dlc:pat) => {
+        match $data {
+            $crate::frames::ParserType::Remote($remote) => {
+                Ok(Frame::$name($crate::frames::Type::Remote))
+            }
+            $crate::frames::ParserType::Remote(_) => {
+                Err($crate::frames::ParseError::RemovedWrongDlc)
+            }
+            $crate::frames::ParserType::Data(data) => match data.len() {
+                $dlc => $crate::frames::payload::Payload::decode(data)
+                    .map(|v| Frame::$name($crate::frames::Type::Data(v))),
+                _ => Err($crate::frames::ParseError::WrongDataSize),
+            },
+        }
+    };
+
+    (@pat $name:ident [] $v:ident) => { Frame::$name };
+    (@pat $name:ident [$payload:ty] $v:ident) => { Frame::$name($v) };
+    // binds the `Type` of frames that may be remote
+    (@remote_pat $name:ident [] [] $v:ident) => { Frame::$name };
+    (@remote_pat $name:ident [$payload:ty] [] $v:ident) => { Frame::$name(_) };
+    (@remote_pat $name:ident [$payload:ty] [$remote:literal] $v:ident) => { Frame::$name($v) };
+
+    (@raw $v:ident [] []) => { $crate::frames::RawType::new_data([]) };
+    (@raw $v:ident [$payload:ty] []) => {
+        $crate::frames::RawType::Data($crate::frames::payload::Payload::encode($v))
+    };
+    (@raw $v:ident [$payload:ty] [$remote:literal]) => {
+        match $v {
+            $crate::frames::Type::Remote => $crate::frames::RawType::Remote($remote),
+            $crate::frames::Type::Data(v) => {
+                $crate::frames::RawType::Data($crate::frames::payload::Payload::encode(v))
+            }
+        }
+    };
+
+    (@target $v:ident [] $target:ident) => { $crate::addressing::Target::$target };
+    (@target $v:ident [$remote_target:ident] $target:ident) => {
+        match $v {
+            $crate::frames::Type::Remote => $crate::addressing::Target::$remote_target,
+            $crate::frames::Type::Data(_) => $crate::addressing::Target::$target,
+        }
+    };
+
+    (@to_all [] $target:ident) => {
+        matches!($crate::addressing::Target::$target, $crate::addressing::Target::All)
+    };
+    (@to_all [$remote_target:ident] $target:ident) => {
+        matches!($crate::addressing::Target::$remote_target, $crate::addressing::Target::All)
+            || matches!($crate::addressing::Target::$target, $crate::addressing::Target::All)
+    };
+
+    (@samples $f:ident $name:ident [] [] []) => { $f(Frame::$name) };
+    (@samples $f:ident $name:ident [$payload:ty] [] [$($sample:expr),*]) => {
+        $($f(Frame::$name($sample));)*
+    };
+    (@samples $f:ident $name:ident [$payload:ty] [$remote:literal] [$($sample:expr),*]) => {
+        $f(Frame::$name($crate::frames::Type::Remote));
+        $($f(Frame::$name($crate::frames::Type::Data($sample)));)*
+    };
+
+    (@remote_dlc $name:ident []) => {
+        assert_eq!(
+            Frame::parse_frame(FrameId::$name, ParserType::Remote(0)),
+            Err(ParseError::RemoteFrame)
+        )
+    };
+    (@remote_dlc $name:ident [$remote:literal]) => {
+        assert_eq!(
+            Frame::parse_frame(FrameId::$name, ParserType::Remote($remote + 1)),
+            Err(ParseError::RemovedWrongDlc)
+        )
+    };
+}
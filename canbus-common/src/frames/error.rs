@@ -0,0 +1,79 @@
+use crate::frame_id::SubId;
+use crate::frames::app;
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+pub enum Code {
+    /// A command only one node may act on came with the broadcast `SubId(0)`.
+    Broadcast = 1,
+    /// The address of the `SubId` is the node's, the rest is not: the sender mixed the
+    /// node up with another one. Only in the legacy layout, the addressed one carries no
+    /// more than the address.
+    WrongSubId = 2,
+}
+
+/// A node rejected a frame it got, sent back with the node's own `SubId`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Error {
+    /// The id of the rejected frame, a `FrameId` or an app frame's.
+    pub frame: u16,
+    /// The `SubId` it came with.
+    pub sub_id: SubId,
+    pub code: Code,
+}
+
+impl Error {
+    pub fn new(frame: impl Into<u16>, sub_id: SubId, code: Code) -> Self {
+        Self {
+            frame: frame.into(),
+            sub_id,
+            code,
+        }
+    }
+}
+
+impl TryFrom<[u8; 5]> for Error {
+    type Error = ();
+
+    fn try_from(v: [u8; 5]) -> Result<Self, ()> {
+        let frame = u16::from_be_bytes([v[0], v[1]]);
+        if !app::known(frame) {
+            return Err(());
+        }
+        Ok(Self {
+            frame,
+            sub_id: SubId::from([v[2], v[3]]),
+            code: Code::from_u8(v[4]).ok_or(())?,
+        })
+    }
+}
+
+impl From<Error> for [u8; 5] {
+    fn from(v: Error) -> Self {
+        let [a, b] = v.frame.to_be_bytes();
+        let [c, d] = v.sub_id.split();
+        [a, b, c, d, v.code.to_u8().unwrap()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_id::FrameId;
+
+    #[test]
+    fn test() {
+        let e = Error::new(FrameId::FirmwareStartUpdate, SubId(0x1234), Code::Broadcast);
+        let data = <[u8; 5]>::from(e);
+        assert_eq!(data, [0x1f, 0x4d, 0x12, 0x34, 1]);
+        assert_eq!(Error::try_from(data), Ok(e));
+
+        assert_eq!(Error::try_from([0x1f, 0x4d, 0x12, 0x34, 9]), Err(()));
+        assert_eq!(Error::try_from([0xff, 0xff, 0x12, 0x34, 1]), Err(()));
+
+        // app frames
+        let e = Error::new(8100_u16, SubId(0x1234), Code::Broadcast);
+        assert_eq!(Error::try_from(<[u8; 5]>::from(e)), Ok(e));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::frames::payload::Payload;
+use crate::frames::version::Version;
+use crate::frames::ParseError;
+use arrayvec::ArrayVec;
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+pub enum Outcome {
+    Uploaded = 1,
+    UploadFailed = 2,
+    Installed = 3,
+    CopyError = 4,
+    VerifyError = 5,
+}
+
+/// One update history entry. It doesn't fit into a frame, so it is sent in `PARTS` parts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub boot_count: u32,
+    pub from: Version,
+    pub to: Version,
+    pub outcome: Outcome,
+}
+
+impl Record {
+    pub const SIZE: usize = 24;
+    pub const PARTS: u8 = (Self::SIZE / 6) as u8;
+
+    pub fn parts(&self, index: u8) -> impl Iterator<Item = RecordPart> {
+        let data = <[u8; Self::SIZE]>::from(*self);
+        (0..Self::PARTS).map(move |part| RecordPart::Data {
+            index,
+            part,
+            data: data[(part as usize * 6)..(part as usize * 6 + 6)]
+                .try_into()
+                .unwrap(),
+        })
+    }
+}
+
+impl From<Record> for [u8; Record::SIZE] {
+    fn from(v: Record) -> Self {
+        let mut data = [0_u8; Record::SIZE];
+        data[..4].clone_from_slice(&v.boot_count.to_be_bytes());
+        data[4..12].clone_from_slice(&<[u8; 8]>::from(v.from));
+        data[12..20].clone_from_slice(&<[u8; 8]>::from(v.to));
+        data[20] = v.outcome.to_u8().unwrap();
+        data
+    }
+}
+
+impl TryFrom<&[u8; Record::SIZE]> for Record {
+    type Error = ();
+
+    fn try_from(v: &[u8; Record::SIZE]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            boot_count: u32::from_be_bytes(<[u8; 4]>::try_from(&v[..4]).unwrap()),
+            from: Version::from(<[u8; 8]>::try_from(&v[4..12]).unwrap()),
+            to: Version::from(<[u8; 8]>::try_from(&v[12..20]).unwrap()),
+            outcome: Outcome::from_u8(v[20]).ok_or(())?,
+        })
+    }
+}
+
+/// Answer to `UpdateHistoryRequest`, index 0 is the newest record.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RecordPart {
+    Data { index: u8, part: u8, data: [u8; 6] },
+    Empty { index: u8 },
+}
+
+impl RecordPart {
+    #[inline]
+    pub fn index(&self) -> u8 {
+        match self {
+            RecordPart::Data { index, .. } | RecordPart::Empty { index } => *index,
+        }
+    }
+}
+
+impl Payload for RecordPart {
+    fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        match *data {
+            [index] => Ok(RecordPart::Empty { index }),
+            [index, part, ref data @ ..] => {
+                let data = <[u8; 6]>::try_from(data).map_err(|_| ParseError::WrongDataSize)?;
+                match part < Record::PARTS {
+                    true => Ok(RecordPart::Data { index, part, data }),
+                    false => Err(ParseError::WrongData),
+                }
+            }
+            _ => Err(ParseError::WrongDataSize),
+        }
+    }
+
+    fn encode(&self) -> ArrayVec<u8, 8> {
+        match self {
+            RecordPart::Data { index, part, data } => {
+                [*index, *part].into_iter().chain(*data).collect()
+            }
+            RecordPart::Empty { index } => [*index].into_iter().collect(),
+        }
+    }
+}
+
+/// Collects the parts of one record on the receiving side.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RecordAssembler {
+    data: [u8; Record::SIZE],
+    received: u8,
+}
+
+impl RecordAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the record once all parts are there.
+    pub fn push(&mut self, part: u8, data: &[u8; 6]) -> Option<Result<Record, ()>> {
+        if part >= Record::PARTS {
+            return Some(Err(()));
+        }
+
+        self.data[(part as usize * 6)..(part as usize * 6 + 6)].clone_from_slice(data);
+        self.received |= 1 << part;
+
+        match self.received == (1 << Record::PARTS) - 1 {
+            true => Some(Record::try_from(&self.data)),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let r = Record {
+            boot_count: 12,
+            from: Version {
+                major: 1,
+                minor: 2,
+                path: 3,
+                build: 4,
+            },
+            to: Version {
+                major: 1,
+                minor: 3,
+                path: 0,
+                build: 1,
+            },
+            outcome: Outcome::Installed,
+        };
+
+        let arr: [u8; Record::SIZE] = r.into();
+        assert_eq!(Record::try_from(&arr), Ok(r));
+
+        let mut a = RecordAssembler::new();
+        let parts: heapless::Vec<RecordPart, 4> = r.parts(7).collect();
+        assert_eq!(parts.len(), Record::PARTS as usize);
+        for (n, p) in parts.iter().rev().enumerate() {
+            match p {
+                RecordPart::Data { index, part, data } => {
+                    assert_eq!(*index, 7);
+                    match n {
+                        3 => assert_eq!(a.push(*part, data), Some(Ok(r))),
+                        _ => assert_eq!(a.push(*part, data), None),
+                    }
+                }
+                RecordPart::Empty { .. } => unreachable!(),
+            }
+        }
+
+        assert_eq!(RecordAssembler::new().push(4, &[0; 6]), Some(Err(())));
+    }
+}
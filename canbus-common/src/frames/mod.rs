@@ -1,10 +1,19 @@
-use crate::frame_id::FrameId;
-use crate::frames::firmware::{UploadPart, UploadPartChangePos};
-use crate::frames::Type::{Data, Remote};
+use crate::addressing::Target;
+use crate::frame_id::Class;
 
+#[macro_use]
+mod define;
+
+pub mod app;
+pub mod boot_info;
+pub mod claim;
 pub mod dyn_id;
+pub mod error;
 pub mod firmware;
+pub mod history;
+pub mod payload;
 pub mod serial;
+pub mod status;
 pub mod version;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -13,18 +22,148 @@ pub enum Type<T> {
     Remote,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum Frame {
-    Serial(Type<serial::Serial>),
-    DynId(dyn_id::Data),
-    HardwareVersion(Type<version::Version>),
-    FirmwareVersion(Type<version::Version>),
-    PendingFirmwareVersion(Type<Option<version::Version>>),
-    FirmwareUploadPartChangePos(firmware::UploadPartChangePos),
-    FirmwareUploadPause(bool),
-    FirmwareUploadPart(firmware::UploadPart),
-    FirmwareUploadFinished,
-    FirmwareStartUpdate,
+// Grouped by `Class`, in the ids it reserves, see `Class::ids`. The ids are those of the legacy
+// layout too, where they decide the arbitration among the frames of a `SubId`.
+define_frames! {
+    // Class::Management
+    Serial(Type<serial::Serial>) = 8000 {
+        class: Management,
+        dlc: 5,
+        remote: 5 => NodeOrAll,
+        data: Host,
+        samples: [serial::Serial([1, 2, 3, 4, 5])],
+    },
+    DynId(dyn_id::Data) = 8001 {
+        class: Management,
+        dlc: 6,
+        data: All,
+        samples: [dyn_id::Data::new(serial::Serial([1, 2, 3, 4, 5]), 55)],
+    },
+    /// The remote frame asks every node to announce its claim again.
+    AddressClaim(Type<claim::Claim>) = 8002 {
+        class: Management,
+        dlc: 6,
+        remote: 6 => All,
+        data: All,
+        samples: [claim::Claim::new(serial::Serial([1, 2, 3, 4, 5]), 7)],
+    },
+    /// Makes the node with the serial forget its dyn_id and claim an address again.
+    ClearDynId(serial::Serial) = 8003 {
+        class: Management,
+        dlc: 5,
+        data: All,
+        samples: [serial::Serial([1, 2, 3, 4, 5])],
+    },
+
+    // Class::Control
+    NodeError(error::Error) = 8010 {
+        class: Control,
+        dlc: 5,
+        data: Host,
+        samples: [error::Error::new(
+            FrameId::EnterBootloader,
+            crate::frame_id::SubId(5),
+            error::Code::WrongSubId,
+        )],
+    },
+    FirmwareUploadPartChangePos(firmware::UploadPartChangePos) = 8011 {
+        class: Control,
+        dlc: 3,
+        data: Host,
+        samples: [firmware::UploadPartChangePos::new(0x123456).unwrap()],
+    },
+    FirmwareUploadPause(bool) = 8012 {
+        class: Control,
+        dlc: 1,
+        data: Host,
+        samples: [true, false],
+    },
+    FirmwareStartUpdate = 8013 {
+        class: Control,
+        dlc: _,
+        data: Node,
+    },
+    EnterBootloader = 8014 {
+        class: Control,
+        dlc: _,
+        data: Node,
+    },
+
+    // Class::Status
+    Status(Type<status::Status>) = 8030 {
+        class: Status,
+        dlc: 1,
+        remote: 1 => NodeOrAll,
+        data: Host,
+        samples: [status::Status::new(status::Mode::Application)],
+    },
+    BootInfo(Type<boot_info::BootInfo>) = 8031 {
+        class: Status,
+        dlc: 2,
+        remote: 2 => NodeOrAll,
+        data: Host,
+        samples: [boot_info::BootInfo::new(
+            boot_info::BootReason::Pin,
+            boot_info::UpdateResult::Success,
+        )],
+    },
+    HardwareVersion(Type<version::Version>) = 8032 {
+        class: Status,
+        dlc: 8,
+        remote: 8 => NodeOrAll,
+        data: Host,
+        samples: [version::Version { major: 1, minor: 2, path: 3, build: 4 }],
+    },
+    BootloaderVersion(Type<version::Version>) = 8033 {
+        class: Status,
+        dlc: 8,
+        remote: 8 => NodeOrAll,
+        data: Host,
+        samples: [version::Version { major: 1, minor: 2, path: 3, build: 4 }],
+    },
+    FirmwareVersion(Type<version::Version>) = 8034 {
+        class: Status,
+        dlc: 8,
+        remote: 8 => NodeOrAll,
+        data: Host,
+        samples: [version::Version { major: 1, minor: 2, path: 3, build: 4 }],
+    },
+    /// No data when there is no pending firmware.
+    PendingFirmwareVersion(Type<Option<version::Version>>) = 8035 {
+        class: Status,
+        dlc: 0 | 8,
+        remote: 8 => NodeOrAll,
+        data: Host,
+        samples: [None, Some(version::Version { major: 1, minor: 2, path: 3, build: 4 })],
+    },
+    UpdateHistoryRequest(u8) = 8036 {
+        class: Status,
+        dlc: 1,
+        data: NodeOrAll,
+        samples: [3],
+    },
+    UpdateHistoryRecord(history::RecordPart) = 8037 {
+        class: Status,
+        dlc: 1 | 8,
+        data: Host,
+        samples: [
+            history::RecordPart::Empty { index: 2 },
+            history::RecordPart::Data { index: 1, part: 0, data: [1, 2, 3, 4, 5, 6] },
+        ],
+    },
+
+    // Class::Bulk
+    FirmwareUploadPart(firmware::UploadPart) = 8180 {
+        class: Bulk,
+        dlc: 8,
+        data: Node,
+        samples: [firmware::UploadPart::new(0x123456, [1, 2, 3, 4, 5]).unwrap()],
+    },
+    FirmwareUploadFinished = 8181 {
+        class: Bulk,
+        dlc: _,
+        data: Node,
+    },
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -49,170 +188,33 @@ pub enum RawType {
     Remote(u8),
 }
 
-impl RawType {
-    pub fn new_data<T: IntoIterator<Item = u8>>(array: T) -> Self {
-        let mut t = arrayvec::ArrayVec::<u8, 8>::new();
-        t.extend(array);
-        Self::Data(t)
-    }
+/// What the identifier of a frame is made of, for the core frames and `app::AnyFrame`.
+pub trait Routing {
+    /// A `FrameId` or one of `app::APP_IDS`.
+    fn frame_id(&self) -> u16;
+    fn class(&self) -> Class;
+    fn target(&self) -> Target;
 }
 
-impl Frame {
-    pub fn parse_frame(frame_id: FrameId, data: ParserType) -> Result<Frame, ParseError> {
-        match frame_id {
-            FrameId::Serial => match data {
-                ParserType::Remote(len) => match len {
-                    5 => Ok(Frame::Serial(Remote)),
-                    _l => Err(ParseError::RemovedWrongDlc),
-                },
-                ParserType::Data(data) => match data.len() {
-                    5 => Ok(Frame::Serial(Data(serial::Serial::from(
-                        <[u8; 5]>::try_from(&data[0..5]).unwrap(),
-                    )))),
-                    _ => Err(ParseError::WrongDataSize),
-                },
-            },
-            FrameId::DynId => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(data) => match data.len() {
-                    6 => Ok(Frame::DynId(dyn_id::Data::from(
-                        <[u8; 6]>::try_from(&data[0..6]).unwrap(),
-                    ))),
-                    _ => Err(ParseError::WrongDataSize),
-                },
-            },
-            n @ FrameId::HardwareVersion | n @ FrameId::FirmwareVersion => {
-                fn put(n: FrameId, v: Type<version::Version>) -> Frame {
-                    match n {
-                        FrameId::HardwareVersion => Frame::HardwareVersion(v),
-                        FrameId::FirmwareVersion => Frame::FirmwareVersion(v),
-                        _ => unreachable!(),
-                    }
-                }
-                match data {
-                    ParserType::Remote(len) => match len {
-                        8 => Ok(put(n, Remote)),
-                        _ => Err(ParseError::RemovedWrongDlc),
-                    },
-                    ParserType::Data(data) => match data.len() {
-                        8 => Ok(put(
-                            n,
-                            Data(version::Version::from(
-                                <[u8; 8]>::try_from(&data[..8]).unwrap(),
-                            )),
-                        )),
-                        _ => Err(ParseError::RemovedWrongDlc),
-                    },
-                }
-            }
-            FrameId::PendingFirmwareVersion => match data {
-                ParserType::Remote(len) => match len {
-                    8 => Ok(Frame::PendingFirmwareVersion(Remote)),
-                    _ => Err(ParseError::RemovedWrongDlc),
-                },
-                ParserType::Data(data) => match data.len() {
-                    0 => Ok(Frame::PendingFirmwareVersion(Data(None))),
-                    8 => Ok(Frame::PendingFirmwareVersion(Data(Some(
-                        version::Version::from(<[u8; 8]>::try_from(&data[..8]).unwrap()),
-                    )))),
-                    _ => Err(ParseError::RemovedWrongDlc),
-                },
-            },
-            FrameId::FirmwareUploadPartChangePos => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(data) => match data.len() {
-                    3 => Ok(Frame::FirmwareUploadPartChangePos(
-                        UploadPartChangePos::from(<[u8; 3]>::try_from(&data[..3]).unwrap()),
-                    )),
-                    _ => Err(ParseError::WrongDataSize),
-                },
-            },
-            FrameId::FirmwareUploadPart => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(data) => match data.len() {
-                    8 => Ok(Frame::FirmwareUploadPart(UploadPart::from(
-                        <[u8; 8]>::try_from(&data[..8]).unwrap(),
-                    ))),
-                    _ => Err(ParseError::WrongDataSize),
-                },
-            },
-            FrameId::FirmwareStartUpdate => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(_) => Ok(Frame::FirmwareStartUpdate),
-            },
-            FrameId::FirmwareUploadPause => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(data) => match data.len() {
-                    1 => Ok(Frame::FirmwareUploadPause(data[0] != 0)),
-                    _ => Err(ParseError::WrongDataSize),
-                },
-            },
-            FrameId::FirmwareUploadFinished => match data {
-                ParserType::Remote(_) => Err(ParseError::RemoteFrame),
-                ParserType::Data(_) => Ok(Frame::FirmwareUploadFinished),
-            },
-        }
+impl Routing for Frame {
+    fn frame_id(&self) -> u16 {
+        self.id().into()
     }
 
-    pub fn raw_frame(&self) -> (FrameId, RawType) {
-        match self {
-            Frame::Serial(v) => match v {
-                Remote => (FrameId::Serial, RawType::Remote(5)),
-                Data(v) => (FrameId::Serial, RawType::new_data(v.0)),
-            },
-            Frame::DynId(v) => (FrameId::DynId, RawType::new_data(<[u8; 6]>::from(*v))),
-            n @ Frame::HardwareVersion(v) | n @ Frame::FirmwareVersion(v) => {
-                let id = match n {
-                    Frame::HardwareVersion(_) => FrameId::HardwareVersion,
-                    Frame::FirmwareVersion(_) => FrameId::FirmwareVersion,
-                    _ => unreachable!(),
-                };
-                match v {
-                    Remote => (id, RawType::Remote(8)),
-                    Data(v) => (id, RawType::new_data(<[u8; 8]>::from(*v))),
-                }
-            }
-            Frame::PendingFirmwareVersion(v) => (
-                FrameId::PendingFirmwareVersion,
-                match v {
-                    Remote => RawType::Remote(8),
-                    Data(Some(v)) => RawType::new_data(<[u8; 8]>::from(*v)),
-                    Data(None) => RawType::new_data([0_u8; 0]),
-                },
-            ),
-            Frame::FirmwareUploadPartChangePos(v) => (
-                FrameId::FirmwareUploadPartChangePos,
-                RawType::new_data(<[u8; 3]>::from(*v)),
-            ),
-            Frame::FirmwareUploadPause(v) => (
-                FrameId::FirmwareUploadPause,
-                RawType::new_data([u8::from(*v)]),
-            ),
-            Frame::FirmwareUploadPart(v) => (
-                FrameId::FirmwareUploadPart,
-                RawType::new_data(<[u8; 8]>::from(*v)),
-            ),
-            Frame::FirmwareStartUpdate => (FrameId::FirmwareStartUpdate, RawType::new_data([])),
-            Frame::FirmwareUploadFinished => {
-                (FrameId::FirmwareUploadFinished, RawType::new_data([]))
-            }
-        }
+    fn class(&self) -> Class {
+        self.id().class()
     }
 
-    #[inline]
-    pub fn id(&self) -> FrameId {
-        match self {
-            Frame::Serial(_) => FrameId::Serial,
-            Frame::DynId(_) => FrameId::DynId,
-            Frame::HardwareVersion(_) => FrameId::HardwareVersion,
-            Frame::FirmwareVersion(_) => FrameId::FirmwareVersion,
-            Frame::PendingFirmwareVersion(_) => FrameId::PendingFirmwareVersion,
-            Frame::FirmwareUploadPartChangePos(_) => FrameId::FirmwareUploadPartChangePos,
-            Frame::FirmwareUploadPause(_) => FrameId::FirmwareUploadPause,
-            Frame::FirmwareUploadPart(_) => FrameId::FirmwareUploadPart,
-            Frame::FirmwareStartUpdate => FrameId::FirmwareStartUpdate,
-            Frame::FirmwareUploadFinished => FrameId::FirmwareUploadFinished,
-        }
+    fn target(&self) -> Target {
+        Frame::target(self)
+    }
+}
+
+impl RawType {
+    pub fn new_data<T: IntoIterator<Item = u8>>(array: T) -> Self {
+        let mut t = arrayvec::ArrayVec::<u8, 8>::new();
+        t.extend(array);
+        Self::Data(t)
     }
 }
 
@@ -221,6 +223,7 @@ mod tests {
     use super::*;
     use crate::frame_id::FrameId;
     use crate::frames::ParserType;
+    use crate::frames::Type::{Data, Remote};
 
     #[test]
     fn serial() {
@@ -266,6 +269,146 @@ mod tests {
         );
     }
 
+    #[test]
+    fn clear_dyn_id() {
+        let s = serial::Serial::from([1, 2, 3, 4, 5]);
+        assert_eq!(
+            Frame::parse_frame(FrameId::ClearDynId, ParserType::Data(&[1, 2, 3, 4, 5])),
+            Ok(Frame::ClearDynId(s))
+        );
+        assert_eq!(
+            Frame::ClearDynId(s).raw_frame(),
+            (FrameId::ClearDynId, RawType::new_data([1, 2, 3, 4, 5]))
+        );
+        assert_eq!(
+            Frame::parse_frame(FrameId::ClearDynId, ParserType::Remote(5)),
+            Err(ParseError::RemoteFrame)
+        );
+    }
+
+    #[test]
+    fn error() {
+        let e = error::Error::new(
+            FrameId::EnterBootloader,
+            crate::frame_id::SubId(5),
+            error::Code::WrongSubId,
+        );
+        let data = <[u8; 5]>::from(e);
+        assert_eq!(
+            Frame::parse_frame(FrameId::NodeError, ParserType::Data(&data)),
+            Ok(Frame::NodeError(e))
+        );
+        assert_eq!(
+            Frame::NodeError(e).raw_frame(),
+            (FrameId::NodeError, RawType::new_data(data))
+        );
+        assert_eq!(
+            Frame::parse_frame(FrameId::NodeError, ParserType::Data(&[0, 0, 0, 5, 2])),
+            Err(ParseError::WrongData)
+        );
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            Frame::parse_frame(FrameId::Status, ParserType::Remote(1)),
+            Ok(Frame::Status(Remote))
+        );
+
+        assert_eq!(
+            Frame::Status(Remote).raw_frame(),
+            (FrameId::Status, RawType::Remote(1))
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::Status, ParserType::Data(&[1])),
+            Ok(Frame::Status(Data(status::Status::new(
+                status::Mode::Bootloader
+            ))))
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::Status, ParserType::Data(&[7])),
+            Err(ParseError::WrongData)
+        );
+
+        assert_eq!(
+            Frame::Status(Data(status::Status::new(status::Mode::Application))).raw_frame(),
+            (FrameId::Status, RawType::new_data([0]))
+        );
+    }
+
+    #[test]
+    fn boot_info() {
+        assert_eq!(
+            Frame::parse_frame(FrameId::BootInfo, ParserType::Remote(2)),
+            Ok(Frame::BootInfo(Remote))
+        );
+
+        let b = boot_info::BootInfo::new(
+            boot_info::BootReason::IndependentWatchdog,
+            boot_info::UpdateResult::CopyError,
+        );
+        assert_eq!(
+            Frame::parse_frame(FrameId::BootInfo, ParserType::Data(&[4, 2])),
+            Ok(Frame::BootInfo(Data(b)))
+        );
+        assert_eq!(
+            Frame::BootInfo(Data(b)).raw_frame(),
+            (FrameId::BootInfo, RawType::new_data([4, 2]))
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::BootInfo, ParserType::Data(&[4])),
+            Err(ParseError::WrongDataSize)
+        );
+    }
+
+    #[test]
+    fn address_claim() {
+        assert_eq!(
+            Frame::parse_frame(FrameId::AddressClaim, ParserType::Remote(6)),
+            Ok(Frame::AddressClaim(Remote))
+        );
+        assert_eq!(
+            Frame::AddressClaim(Remote).raw_frame(),
+            (FrameId::AddressClaim, RawType::Remote(6))
+        );
+
+        let c = claim::Claim::new(serial::Serial::from([1, 2, 3, 4, 5]), 42);
+        assert_eq!(
+            Frame::parse_frame(FrameId::AddressClaim, ParserType::Data(&[1, 2, 3, 4, 5, 42])),
+            Ok(Frame::AddressClaim(Data(c)))
+        );
+        assert_eq!(
+            Frame::AddressClaim(Data(c)).raw_frame(),
+            (FrameId::AddressClaim, RawType::new_data([1, 2, 3, 4, 5, 42]))
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::AddressClaim, ParserType::Data(&[1, 2, 3])),
+            Err(ParseError::WrongDataSize)
+        );
+    }
+
+    #[test]
+    fn enter_bootloader() {
+        assert_eq!(
+            Frame::parse_frame(FrameId::EnterBootloader, ParserType::Remote(0)),
+            Err(ParseError::RemoteFrame)
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::EnterBootloader, ParserType::Data(&[])),
+            Ok(Frame::EnterBootloader)
+        );
+
+        assert_eq!(
+            Frame::EnterBootloader.raw_frame(),
+            (FrameId::EnterBootloader, RawType::new_data([]))
+        );
+    }
+
     #[test]
     fn version() {
         fn none(id: FrameId, res: Frame) {
@@ -274,6 +417,7 @@ mod tests {
         }
         none(FrameId::FirmwareVersion, Frame::FirmwareVersion(Remote));
         none(FrameId::HardwareVersion, Frame::HardwareVersion(Remote));
+        none(FrameId::BootloaderVersion, Frame::BootloaderVersion(Remote));
         none(
             FrameId::PendingFirmwareVersion,
             Frame::PendingFirmwareVersion(Remote),
@@ -295,6 +439,7 @@ mod tests {
         };
         data(v, FrameId::FirmwareVersion, Frame::FirmwareVersion(Data(v)));
         data(v, FrameId::HardwareVersion, Frame::HardwareVersion(Data(v)));
+        data(v, FrameId::BootloaderVersion, Frame::BootloaderVersion(Data(v)));
         data(
             v,
             FrameId::PendingFirmwareVersion,
@@ -429,4 +574,52 @@ mod tests {
             (FrameId::FirmwareUploadPause, RawType::new_data([1]))
         );
     }
+
+    #[test]
+    fn update_history() {
+        assert_eq!(
+            Frame::parse_frame(FrameId::UpdateHistoryRequest, ParserType::Data(&[3])),
+            Ok(Frame::UpdateHistoryRequest(3))
+        );
+
+        assert_eq!(
+            Frame::UpdateHistoryRequest(3).raw_frame(),
+            (FrameId::UpdateHistoryRequest, RawType::new_data([3]))
+        );
+
+        let part = history::RecordPart::Data {
+            index: 3,
+            part: 1,
+            data: [1, 2, 3, 4, 5, 6],
+        };
+        assert_eq!(
+            Frame::parse_frame(
+                FrameId::UpdateHistoryRecord,
+                ParserType::Data(&[3, 1, 1, 2, 3, 4, 5, 6])
+            ),
+            Ok(Frame::UpdateHistoryRecord(part))
+        );
+        assert_eq!(
+            Frame::UpdateHistoryRecord(part).raw_frame(),
+            (
+                FrameId::UpdateHistoryRecord,
+                RawType::new_data([3, 1, 1, 2, 3, 4, 5, 6])
+            )
+        );
+
+        assert_eq!(
+            Frame::parse_frame(FrameId::UpdateHistoryRecord, ParserType::Data(&[9])),
+            Ok(Frame::UpdateHistoryRecord(history::RecordPart::Empty {
+                index: 9
+            }))
+        );
+
+        assert_eq!(
+            Frame::parse_frame(
+                FrameId::UpdateHistoryRecord,
+                ParserType::Data(&[3, 4, 1, 2, 3, 4, 5, 6])
+            ),
+            Err(ParseError::WrongData)
+        );
+    }
 }
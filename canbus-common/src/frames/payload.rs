@@ -0,0 +1,91 @@
+use crate::frames::{
+    boot_info, claim, dyn_id, error, firmware, serial, status, version, ParseError,
+};
+use arrayvec::ArrayVec;
+
+/// The data of a frame. `parse_frame` has checked its length against the frame's dlc.
+pub trait Payload: Sized {
+    fn decode(data: &[u8]) -> Result<Self, ParseError>;
+    fn encode(&self) -> ArrayVec<u8, 8>;
+}
+
+/// Payloads converted from and to `[u8; N]`, `try` for those that may reject the data.
+macro_rules! array_payload {
+    ($($t:ty: $n:literal),* $(,)?) => {$(
+        impl Payload for $t {
+            fn decode(data: &[u8]) -> Result<Self, ParseError> {
+                <[u8; $n]>::try_from(data)
+                    .map(Self::from)
+                    .map_err(|_| ParseError::WrongDataSize)
+            }
+
+            fn encode(&self) -> ArrayVec<u8, 8> {
+                <[u8; $n]>::from(*self).into_iter().collect()
+            }
+        }
+    )*};
+    (try $($t:ty: $n:literal),* $(,)?) => {$(
+        impl Payload for $t {
+            fn decode(data: &[u8]) -> Result<Self, ParseError> {
+                let data = <[u8; $n]>::try_from(data).map_err(|_| ParseError::WrongDataSize)?;
+                Self::try_from(data).map_err(|_| ParseError::WrongData)
+            }
+
+            fn encode(&self) -> ArrayVec<u8, 8> {
+                <[u8; $n]>::from(*self).into_iter().collect()
+            }
+        }
+    )*};
+}
+
+array_payload!(
+    serial::Serial: 5,
+    dyn_id::Data: 6,
+    claim::Claim: 6,
+    version::Version: 8,
+    firmware::UploadPartChangePos: 3,
+    firmware::UploadPart: 8,
+);
+
+array_payload!(try
+    status::Status: 1,
+    boot_info::BootInfo: 2,
+    error::Error: 5,
+);
+
+impl Payload for u8 {
+    fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        match *data {
+            [v] => Ok(v),
+            _ => Err(ParseError::WrongDataSize),
+        }
+    }
+
+    fn encode(&self) -> ArrayVec<u8, 8> {
+        [*self].into_iter().collect()
+    }
+}
+
+impl Payload for bool {
+    fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        u8::decode(data).map(|v| v != 0)
+    }
+
+    fn encode(&self) -> ArrayVec<u8, 8> {
+        u8::from(*self).encode()
+    }
+}
+
+/// No data for `None`.
+impl Payload for Option<version::Version> {
+    fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        match data {
+            [] => Ok(None),
+            _ => version::Version::decode(data).map(Some),
+        }
+    }
+
+    fn encode(&self) -> ArrayVec<u8, 8> {
+        self.as_ref().map(Payload::encode).unwrap_or_default()
+    }
+}
@@ -1,10 +1,39 @@
 use core::fmt;
-use core::fmt::{Debug, Display, Write};
+use core::fmt::Debug;
 use hex::ToHex;
 
+/// Answers to a broadcast `Serial` request are spread over this many slots.
+pub const REPLY_SLOTS: u8 = 32;
+/// Length of a reply slot in milliseconds, a few frames at 1 Mbit/s.
+pub const REPLY_SLOT_MS: u64 = 2;
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Serial(pub [u8; 5]);
 
+impl Serial {
+    /// Slot to answer the `round`-th `Serial` request in. It changes from round to round,
+    /// so nodes that shared a slot once rarely share it again. Not a crc, being linear it
+    /// would keep the same pairs of serials in one slot in every round.
+    pub fn reply_slot(&self, round: u8) -> u8 {
+        // FNV-1a, then the murmur3 finalizer to mix the low bits
+        let mut h: u32 = 0x811c_9dc5;
+        for b in self.0.iter().chain(&[round]) {
+            h = (h ^ *b as u32).wrapping_mul(0x0100_0193);
+        }
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0xc2b2_ae35);
+        h ^= h >> 16;
+        (h % REPLY_SLOTS as u32) as u8
+    }
+
+    /// How long to wait before answering the `round`-th `Serial` request.
+    pub fn reply_delay_ms(&self, round: u8) -> u64 {
+        self.reply_slot(round) as u64 * REPLY_SLOT_MS
+    }
+}
+
 impl From<[u8; 5]> for Serial {
     fn from(val: [u8; 5]) -> Self {
         Self(val)
@@ -36,8 +65,7 @@ impl TryFrom<&str> for Serial {
 
 impl From<&Serial> for heapless::String<10> {
     fn from(v: &Serial) -> Self {
-        let res = v.0.encode_hex::<heapless::String<10>>();
-        res
+        v.0.encode_hex::<heapless::String<10>>()
     }
 }
 
@@ -63,4 +91,30 @@ mod tests {
 
         assert_eq!(Serial::try_from("010203FFFE").unwrap().0, [1, 2, 3, 255, 254]);
     }
+
+    #[test]
+    fn reply_slot() {
+        let serials = (0..=255_u8).map(|n| Serial([1, 2, 3, 4, n]));
+        let mut used = [0_u32; REPLY_SLOTS as usize];
+        for s in serials.clone() {
+            assert!(s.reply_slot(0) < REPLY_SLOTS);
+            assert_eq!(s.reply_delay_ms(0), s.reply_slot(0) as u64 * REPLY_SLOT_MS);
+            used[s.reply_slot(0) as usize] += 1;
+        }
+        // spread over the slots, 8 per slot on average
+        assert!(used.iter().all(|&n| n > 0 && n < 20));
+
+        // nodes that shared a slot mostly don't share it in the next round
+        let serials: heapless::Vec<Serial, 256> = serials.collect();
+        let (mut shared, mut again) = (0, 0);
+        for (n, a) in serials.iter().enumerate() {
+            for b in &serials[n + 1..] {
+                if a.reply_slot(0) == b.reply_slot(0) {
+                    shared += 1;
+                    again += (a.reply_slot(1) == b.reply_slot(1)) as u32;
+                }
+            }
+        }
+        assert!(again * 8 < shared);
+    }
 }
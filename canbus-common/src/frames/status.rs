@@ -0,0 +1,51 @@
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, enum_primitive_derive::Primitive)]
+pub enum Mode {
+    Application = 0,
+    Bootloader = 1,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Status {
+    pub mode: Mode,
+}
+
+impl Status {
+    pub fn new(mode: Mode) -> Self {
+        Self { mode }
+    }
+}
+
+impl TryFrom<[u8; 1]> for Status {
+    type Error = ();
+
+    fn try_from(v: [u8; 1]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            mode: Mode::from_u8(v[0]).ok_or(())?,
+        })
+    }
+}
+
+impl From<Status> for [u8; 1] {
+    fn from(v: Status) -> Self {
+        [v.mode.to_u8().unwrap()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status() {
+        let s = Status::new(Mode::Bootloader);
+        let arr: [u8; 1] = s.into();
+        assert_eq!(arr, [1]);
+        assert_eq!(Status::try_from(arr), Ok(s));
+
+        assert_eq!(Status::try_from([0]), Ok(Status::new(Mode::Application)));
+        assert_eq!(Status::try_from([2]), Err(()));
+    }
+}
@@ -0,0 +1,253 @@
+//! Layout of the 29 bit identifier. The addressed layout, opt in:
+//!
+//! | bits | 28..26   | 25..16                    | 15..8       | 7..0   |
+//! |------|----------|---------------------------|-------------|--------|
+//! |      | priority | frame id - `FRAME_ID_BASE` | destination | source |
+//!
+//! Addresses are the dyn_ids of the nodes. `BROADCAST` as the destination is every node, as
+//! the source a node without an address. `HOST` is the host, nodes take addresses up to
+//! `MAX_ADDRESS`. The priority is the one of the frame's `Class`, the lower one wins the
+//! arbitration whatever the rest of the identifier.
+//!
+//! The legacy layout, the default, is `SubId << 13 | frame id`. The one `SubId` is the node a
+//! frame is for or from, so nodes can't address each other, and the frame id decides the
+//! arbitration among the frames of a `SubId`, see `Class::ids`. Nodes and hosts that predate
+//! the addressed layout only know this one, a bus changes to the addressed layout once all of
+//! them do.
+//!
+//! Addresses are `SubId::from([0, address])` in a `Header`, the addressed layout has no room
+//! for the crc of a `SubId`, see `Layout::carried`. Both layouts carry the core frame ids
+//! and `frames::app::APP_IDS`.
+
+use crate::addressing::Target;
+use crate::frame_id::{Class, FrameId, SubId};
+use crate::frames::{app, Routing};
+
+pub const BROADCAST: u8 = 0;
+pub const HOST: u8 = u8::MAX;
+pub const MAX_ADDRESS: u8 = HOST - 1;
+
+/// The first frame id, the addressed layout carries the frame ids from it on.
+pub const FRAME_ID_BASE: u16 = 8000;
+const FRAME_ID_BITS: u32 = 10;
+
+const PRIORITY_BITS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Layout {
+    /// Gives up the crc of the `SubId`: a node can't tell it was mixed up with another one of
+    /// the same address, there is no `Code::WrongSubId`.
+    Addressed,
+    #[default]
+    Legacy,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Header {
+    pub priority: u8,
+    /// A `FrameId` or one of `frames::app::APP_IDS`.
+    pub id: u16,
+    pub from: SubId,
+    pub to: SubId,
+}
+
+impl Header {
+    pub fn new(frame: FrameId, from: SubId, to: SubId) -> Self {
+        Self {
+            priority: frame.priority(),
+            id: frame.into(),
+            from,
+            to,
+        }
+    }
+
+    pub fn for_frame(frame: &impl Routing, from: SubId, to: SubId) -> Self {
+        Self {
+            priority: frame.class().priority(),
+            id: frame.frame_id(),
+            from,
+            to,
+        }
+    }
+
+    /// What the node with `own` sends `frame` with, answers go to the host and the rest to
+    /// every node.
+    pub fn from_node(frame: &impl Routing, own: SubId) -> Self {
+        let to = match frame.target() {
+            Target::Host => SubId::HOST,
+            _ => SubId::BROADCAST,
+        };
+        Self::for_frame(frame, own, to)
+    }
+
+    /// Between the host and the node with `sub_id`, which sends the frames for the host.
+    pub fn with_node(frame: &impl Routing, sub_id: SubId) -> Self {
+        match frame.target() {
+            Target::Host => Self::for_frame(frame, sub_id, SubId::HOST),
+            _ => Self::for_frame(frame, SubId::HOST, sub_id),
+        }
+    }
+
+    /// `None` for app frames.
+    pub fn frame_id(&self) -> Option<FrameId> {
+        FrameId::try_from_u16(self.id)
+    }
+
+    /// The node the frame is from, or for when the host sent it.
+    pub fn node(&self) -> SubId {
+        match self.from == SubId::HOST {
+            true => self.to,
+            false => self.from,
+        }
+    }
+}
+
+impl Layout {
+    /// The legacy layout keeps the `SubId` of `Header::node`.
+    pub fn encode(&self, header: &Header) -> u32 {
+        match self {
+            Layout::Addressed => {
+                let frame = header.id.wrapping_sub(FRAME_ID_BASE) as u32;
+                ((header.priority as u32 & ((1 << PRIORITY_BITS) - 1)) << (FRAME_ID_BITS + 16))
+                    | ((frame & ((1 << FRAME_ID_BITS) - 1)) << 16)
+                    | ((header.to.dyn_id() as u32) << 8)
+                    | header.from.dyn_id() as u32
+            }
+            Layout::Legacy => FrameId::raw_id(header.id, header.node()),
+        }
+    }
+
+    /// In the legacy layout the `SubId` is both `from` and `to`, and app frames get the
+    /// priority of `Class::Status`, it has none to carry.
+    pub fn decode(&self, raw: u32) -> Option<Header> {
+        match self {
+            Layout::Addressed => {
+                let id = FRAME_ID_BASE + ((raw >> 16) as u16 & ((1 << FRAME_ID_BITS) - 1));
+                app::known(id).then(|| Header {
+                    priority: (raw >> (FRAME_ID_BITS + 16)) as u8 & ((1 << PRIORITY_BITS) - 1),
+                    id,
+                    from: address(raw as u8),
+                    to: address((raw >> 8) as u8),
+                })
+            }
+            Layout::Legacy => {
+                let id = raw as u16 & FrameId::max_id();
+                let sub_id = FrameId::extract_sub_id(raw);
+                app::known(id).then(|| Header {
+                    priority: FrameId::try_from_u16(id)
+                        .map_or(Class::Status.priority(), |v| v.priority()),
+                    id,
+                    from: sub_id,
+                    to: sub_id,
+                })
+            }
+        }
+    }
+
+    /// What arrives of `sub_id`, the `SubId` a node compares received ones with. The addressed
+    /// layout drops the crc byte, only the address is left to compare.
+    pub fn carried(&self, sub_id: SubId) -> SubId {
+        match self {
+            Layout::Addressed => address(sub_id.dyn_id()),
+            Layout::Legacy => sub_id,
+        }
+    }
+
+    /// The bits of the frame id.
+    pub fn frame_id_mask(&self) -> u32 {
+        match self {
+            Layout::Addressed => ((1 << FRAME_ID_BITS) - 1) << 16,
+            Layout::Legacy => FrameId::max_id() as u32,
+        }
+    }
+}
+
+fn address(v: u8) -> SubId {
+    SubId::from([0, v])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::serial::Serial;
+    use crate::frames::{status, Frame, Type};
+
+    #[test]
+    fn addressed() {
+        let own = SubId::for_node(&Serial([1, 2, 3, 4, 5]), 7);
+        let layout = Layout::Addressed;
+        let request = Header::with_node(&Frame::FirmwareStartUpdate, own);
+        let raw = layout.encode(&request);
+        assert_eq!(raw, (3 << 26) | (13 << 16) | (7 << 8) | 0xFF);
+        assert_eq!(
+            layout.decode(raw),
+            Some(Header::new(
+                FrameId::FirmwareStartUpdate,
+                SubId::HOST,
+                layout.carried(own)
+            ))
+        );
+
+        let answer = Frame::Status(Type::Data(status::Status::new(status::Mode::Application)));
+        let header = layout
+            .decode(layout.encode(&Header::from_node(&answer, own)))
+            .unwrap();
+        assert_eq!((header.from, header.to), (layout.carried(own), SubId::HOST));
+        assert_eq!(header.node(), SubId::from([0, 7]));
+
+        let claim = layout.encode(&Header::from_node(&Frame::AddressClaim(Type::Remote), own));
+        assert_eq!(claim & 0xFFFF, 7);
+
+        // nodes to each other
+        let other = SubId::from([0, 9]);
+        let header = Header::new(FrameId::Status, own, other);
+        assert_eq!(
+            layout.decode(layout.encode(&header)),
+            Some(Header::new(FrameId::Status, layout.carried(own), other))
+        );
+
+        assert_eq!(layout.decode(1023 << 16), None);
+    }
+
+    /// Bulk transfers give way to the other frames, whichever nodes they are between.
+    #[test]
+    fn priority() {
+        let layout = Layout::Addressed;
+        let part = |from: u8, to: u8| {
+            layout.encode(&Header::new(
+                FrameId::FirmwareUploadPart,
+                SubId::from([0, from]),
+                SubId::from([0, to]),
+            ))
+        };
+        let status = layout.encode(&Header::new(FrameId::Status, SubId::HOST, SubId::HOST));
+        let claim = layout.encode(&Header::new(
+            FrameId::AddressClaim,
+            SubId::HOST,
+            SubId::HOST,
+        ));
+        assert!(part(0, 0) > status);
+        assert!(status > claim);
+        assert_eq!(layout.decode(part(1, 2)).unwrap().priority, 7);
+        assert_eq!(FrameId::FirmwareUploadPart.class(), Class::Bulk);
+    }
+
+    #[test]
+    fn legacy() {
+        let own = SubId::for_node(&Serial([1, 2, 3, 4, 5]), 7);
+        let layout = Layout::Legacy;
+        let raw = layout.encode(&Header::with_node(&Frame::FirmwareStartUpdate, own));
+        assert_eq!(raw, FrameId::FirmwareStartUpdate.as_raw(own));
+        assert_eq!(
+            layout.decode(raw),
+            Some(Header::new(FrameId::FirmwareStartUpdate, own, own))
+        );
+
+        let answer = Frame::FirmwareVersion(Type::Remote);
+        assert_eq!(
+            layout.encode(&Header::from_node(&answer, own)),
+            FrameId::FirmwareVersion.as_raw(own)
+        );
+        assert_eq!(layout.carried(own), own);
+    }
+}
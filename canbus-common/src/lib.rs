@@ -1,4 +1,8 @@
 #![no_std]
 
+pub mod address_claim;
+pub mod addressing;
 pub mod frame_id;
 pub mod frames;
+pub mod identifier;
+pub mod upload;
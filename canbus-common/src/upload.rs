@@ -0,0 +1,332 @@
+//! Host side of a firmware upload, without any io. The driver passes in the frames
+//! received from the node and the current time in milliseconds, and sends what `poll`
+//! returns.
+
+use crate::frames::firmware::UploadPart;
+use crate::frames::Frame;
+
+pub const PART_SIZE: usize = 5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timing {
+    /// Between two parts.
+    pub part_interval: u64,
+    /// Before sending again a frame the driver failed to send.
+    pub retry_delay: u64,
+    /// How long the node may keep the upload paused.
+    pub pause_timeout: u64,
+    /// How long to wait for the node to write the last page.
+    pub finish_timeout: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            part_interval: 15,
+            retry_delay: 20,
+            pause_timeout: 20_000,
+            finish_timeout: 2_000,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UploadError {
+    PauseTimeout,
+    FinishTimeout,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Step {
+    Send(Frame),
+    /// Nothing to send before this time, unless a frame comes.
+    WaitUntil(u64),
+    Done,
+    Failed(UploadError),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Parts,
+    /// `FirmwareUploadFinished` is sent, waiting for the node to write the last page.
+    Finishing {
+        since: u64,
+        paused: bool,
+    },
+    Done,
+    Failed(UploadError),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Sent {
+    Part(usize),
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadSession<'a> {
+    file: &'a [u8],
+    timing: Timing,
+    state: State,
+    next: usize,
+    next_at: u64,
+    paused_since: Option<u64>,
+    last: Option<Sent>,
+}
+
+impl<'a> UploadSession<'a> {
+    pub fn new(file: &'a [u8], timing: Timing) -> Self {
+        Self {
+            file,
+            timing,
+            state: State::Parts,
+            next: 0,
+            next_at: 0,
+            paused_since: None,
+            last: None,
+        }
+    }
+
+    #[inline]
+    pub fn parts(&self) -> usize {
+        self.file.len().div_ceil(PART_SIZE)
+    }
+
+    /// Next part to send.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    fn part(&self, n: usize) -> Frame {
+        let mut data = [0_u8; PART_SIZE];
+        let chunk = &self.file[(n * PART_SIZE)..self.file.len().min((n + 1) * PART_SIZE)];
+        data[..chunk.len()].clone_from_slice(chunk);
+        Frame::FirmwareUploadPart(UploadPart::new(n, data).unwrap())
+    }
+
+    pub fn on_frame(&mut self, frame: &Frame, now: u64) {
+        match frame {
+            Frame::FirmwareUploadPartChangePos(pos) if pos.pos() <= self.parts() => {
+                if let State::Parts | State::Finishing { .. } = self.state {
+                    self.state = State::Parts;
+                    self.next = pos.pos();
+                }
+            }
+            Frame::FirmwareUploadPause(true) => {
+                if self.paused_since.is_none() {
+                    self.paused_since = Some(now);
+                }
+                if let State::Finishing { paused, .. } = &mut self.state {
+                    *paused = true;
+                }
+            }
+            Frame::FirmwareUploadPause(false) => {
+                self.paused_since = None;
+                if let State::Finishing { paused: true, .. } = self.state {
+                    self.state = State::Done;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The last frame returned by `poll` could not be sent, it is sent again later.
+    pub fn send_failed(&mut self, now: u64) {
+        match self.last.take() {
+            Some(Sent::Part(n)) if self.state == State::Parts => self.next = n,
+            Some(Sent::Finished) => self.state = State::Parts,
+            _ => {}
+        }
+        self.next_at = now + self.timing.retry_delay;
+    }
+
+    pub fn poll(&mut self, now: u64) -> Step {
+        match self.state {
+            State::Done => return Step::Done,
+            State::Failed(e) => return Step::Failed(e),
+            State::Finishing { since, .. } => {
+                let deadline = since + self.timing.finish_timeout;
+                return match now >= deadline {
+                    true => self.fail(UploadError::FinishTimeout),
+                    false => Step::WaitUntil(deadline),
+                };
+            }
+            State::Parts => {}
+        }
+
+        if let Some(since) = self.paused_since {
+            let deadline = since + self.timing.pause_timeout;
+            return match now >= deadline {
+                true => self.fail(UploadError::PauseTimeout),
+                false => Step::WaitUntil(deadline),
+            };
+        }
+
+        if now < self.next_at {
+            return Step::WaitUntil(self.next_at);
+        }
+        self.next_at = now + self.timing.part_interval;
+
+        if self.next < self.parts() {
+            let n = self.next;
+            self.next += 1;
+            self.last = Some(Sent::Part(n));
+            return Step::Send(self.part(n));
+        }
+
+        self.state = State::Finishing {
+            since: now,
+            paused: false,
+        };
+        self.last = Some(Sent::Finished);
+        Step::Send(Frame::FirmwareUploadFinished)
+    }
+
+    fn fail(&mut self, e: UploadError) -> Step {
+        self.state = State::Failed(e);
+        Step::Failed(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::frames::firmware::UploadPartChangePos;
+    use std::vec::Vec;
+
+    const TIMING: Timing = Timing {
+        part_interval: 10,
+        retry_delay: 20,
+        pause_timeout: 1000,
+        finish_timeout: 500,
+    };
+
+    fn part_n(step: Step) -> Option<usize> {
+        match step {
+            Step::Send(Frame::FirmwareUploadPart(p)) => Some(p.position()),
+            _ => None,
+        }
+    }
+
+    fn change_pos(pos: usize) -> Frame {
+        Frame::FirmwareUploadPartChangePos(UploadPartChangePos::new(pos).unwrap())
+    }
+
+    #[test]
+    fn parts() {
+        let file: Vec<u8> = (0..12).collect();
+        let mut s = UploadSession::new(&file, TIMING);
+        assert_eq!(s.parts(), 3);
+
+        assert_eq!(
+            s.poll(0),
+            Step::Send(Frame::FirmwareUploadPart(
+                UploadPart::new(0, [0, 1, 2, 3, 4]).unwrap()
+            ))
+        );
+        assert_eq!(s.poll(5), Step::WaitUntil(10));
+        assert_eq!(part_n(s.poll(10)), Some(1));
+        // the last part is padded
+        assert_eq!(
+            s.poll(20),
+            Step::Send(Frame::FirmwareUploadPart(
+                UploadPart::new(2, [10, 11, 0, 0, 0]).unwrap()
+            ))
+        );
+        assert_eq!(s.poll(30), Step::Send(Frame::FirmwareUploadFinished));
+        assert_eq!(s.poll(31), Step::WaitUntil(530));
+
+        // the node writes the last page
+        s.on_frame(&Frame::FirmwareUploadPause(true), 40);
+        assert_eq!(s.poll(41), Step::WaitUntil(530));
+        s.on_frame(&Frame::FirmwareUploadPause(false), 60);
+        assert_eq!(s.poll(61), Step::Done);
+    }
+
+    #[test]
+    fn pause() {
+        let file = [1_u8; 100];
+        let mut s = UploadSession::new(&file, TIMING);
+
+        assert_eq!(part_n(s.poll(0)), Some(0));
+        s.on_frame(&Frame::FirmwareUploadPause(true), 5);
+        assert!(s.is_paused());
+        assert_eq!(s.poll(10), Step::WaitUntil(1005));
+        assert_eq!(s.poll(500), Step::WaitUntil(1005));
+
+        s.on_frame(&Frame::FirmwareUploadPause(false), 600);
+        assert_eq!(part_n(s.poll(600)), Some(1));
+
+        s.on_frame(&Frame::FirmwareUploadPause(true), 700);
+        assert_eq!(s.poll(1700), Step::Failed(UploadError::PauseTimeout));
+        // stays failed
+        s.on_frame(&Frame::FirmwareUploadPause(false), 1800);
+        assert_eq!(s.poll(1800), Step::Failed(UploadError::PauseTimeout));
+    }
+
+    #[test]
+    fn change_position() {
+        let file = [1_u8; 100];
+        let mut s = UploadSession::new(&file, TIMING);
+
+        for n in 0..5 {
+            assert_eq!(part_n(s.poll(n * 10)), Some(n as usize));
+        }
+        // part 2 got lost
+        s.on_frame(&change_pos(2), 45);
+        assert_eq!(s.position(), 2);
+        assert_eq!(part_n(s.poll(50)), Some(2));
+        assert_eq!(part_n(s.poll(60)), Some(3));
+
+        // out of the file
+        s.on_frame(&change_pos(21), 65);
+        assert_eq!(part_n(s.poll(70)), Some(4));
+    }
+
+    #[test]
+    fn change_position_after_finished() {
+        let file = [1_u8; 10];
+        let mut s = UploadSession::new(&file, TIMING);
+
+        assert_eq!(part_n(s.poll(0)), Some(0));
+        assert_eq!(part_n(s.poll(10)), Some(1));
+        assert_eq!(s.poll(20), Step::Send(Frame::FirmwareUploadFinished));
+
+        s.on_frame(&change_pos(1), 25);
+        assert_eq!(part_n(s.poll(30)), Some(1));
+        assert_eq!(s.poll(40), Step::Send(Frame::FirmwareUploadFinished));
+        assert_eq!(s.poll(540), Step::Failed(UploadError::FinishTimeout));
+    }
+
+    #[test]
+    fn retransmission() {
+        let file = [1_u8; 20];
+        let mut s = UploadSession::new(&file, TIMING);
+
+        assert_eq!(part_n(s.poll(0)), Some(0));
+        assert_eq!(part_n(s.poll(10)), Some(1));
+        s.send_failed(12);
+        assert_eq!(s.poll(20), Step::WaitUntil(32));
+        assert_eq!(part_n(s.poll(32)), Some(1));
+        assert_eq!(part_n(s.poll(42)), Some(2));
+        assert_eq!(part_n(s.poll(52)), Some(3));
+
+        assert_eq!(s.poll(62), Step::Send(Frame::FirmwareUploadFinished));
+        s.send_failed(63);
+        assert_eq!(s.poll(83), Step::Send(Frame::FirmwareUploadFinished));
+    }
+
+    #[test]
+    fn empty_file() {
+        let mut s = UploadSession::new(&[], TIMING);
+        assert_eq!(s.poll(0), Step::Send(Frame::FirmwareUploadFinished));
+    }
+}
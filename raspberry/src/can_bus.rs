@@ -1,21 +1,39 @@
+use crate::util::RequestOptions;
 use canbus_common::frame_id::SubId;
+use canbus_common::frames::app::{AnyFrame, AppFrame, NoApp};
 use canbus_common::frames::Frame;
+use canbus_common::identifier::{Header, Layout};
 use futures_util::StreamExt;
+use std::future::Future;
+use std::time::Duration;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_socketcan::CANSocket;
 
+/// The frame with the `SubId` of the node it is from, or for when the host sent it.
 pub fn from_can_frame(
     f: &socketcan::CANFrame,
+    layout: Layout,
 ) -> Result<(canbus_common::frames::Frame, canbus_common::frame_id::SubId), ()> {
+    match from_app_frame::<NoApp>(f, layout)? {
+        (AnyFrame::Core(frame), sub_id) => Ok((frame, sub_id)),
+        (AnyFrame::App(v), _) => match v {},
+    }
+}
+
+/// `from_can_frame` for products with frames of their own.
+pub fn from_app_frame<A: AppFrame>(
+    f: &socketcan::CANFrame,
+    layout: Layout,
+) -> Result<(AnyFrame<A>, canbus_common::frame_id::SubId), ()> {
     f.is_extended().then_some(()).ok_or(())?;
-    let id =
-        canbus_common::frame_id::FrameId::try_from_u32_with_sub_id(f.id() & socketcan::EFF_MASK)
-            .ok_or(())?;
+    let header = layout.decode(f.id() & socketcan::EFF_MASK).ok_or(())?;
 
-    let res = canbus_common::frames::Frame::parse_frame(
-        id.0,
+    let res = AnyFrame::parse_frame(
+        header.id,
         match f.is_rtr() {
             false => canbus_common::frames::ParserType::Data(f.data()),
             true => canbus_common::frames::ParserType::Remote(f.data().len() as u8),
@@ -23,17 +41,27 @@ pub fn from_can_frame(
     )
     .map_err(|_| ())?;
 
-    Ok((res, id.1))
+    Ok((res, header.node()))
 }
 
+/// The frame between the host and the node with `sub_id`, see `Header::with_node`.
 pub fn to_can_frame(
     frame: &canbus_common::frames::Frame,
     sub_id: canbus_common::frame_id::SubId,
+    layout: Layout,
+) -> socketcan::CANFrame {
+    to_app_frame(&AnyFrame::<NoApp>::Core(*frame), sub_id, layout)
+}
+
+/// `to_can_frame` for products with frames of their own.
+pub fn to_app_frame<A: AppFrame>(
+    frame: &AnyFrame<A>,
+    sub_id: canbus_common::frame_id::SubId,
+    layout: Layout,
 ) -> socketcan::CANFrame {
-    let raw = frame.raw_frame();
-    let raw_id = raw.0.as_raw(sub_id);
+    let raw_id = layout.encode(&Header::with_node(frame, sub_id));
 
-    match raw.1 {
+    match frame.raw() {
         canbus_common::frames::RawType::Data(v) => {
             socketcan::CANFrame::new(raw_id, v.as_slice(), false, false).unwrap()
         }
@@ -47,55 +75,458 @@ pub fn to_can_frame(
     }
 }
 
+/// Received frames, published to every subscriber of a transport.
+#[derive(Clone)]
+pub struct Channels {
+    layout: Layout,
+    raw: broadcast::Sender<socketcan::CANFrame>,
+    frames: broadcast::Sender<(Frame, SubId)>,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self::new(Layout::default())
+    }
+}
+
+impl Channels {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            raw: broadcast::channel(1000).0,
+            frames: broadcast::channel(1000).0,
+        }
+    }
+
+    #[inline]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn publish(&self, frame: socketcan::CANFrame) {
+        if let Ok(v) = from_can_frame(&frame, self.layout) {
+            let _ = self.frames.send(v);
+        }
+        let _ = self.raw.send(frame);
+    }
+
+    pub fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        self.frames.subscribe()
+    }
+
+    pub fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        self.raw.subscribe()
+    }
+}
+
+/// A CAN interface the host tools talk through. Whether frames sent by an endpoint are
+/// received back by it depends on the transport, see `echoes_writes`.
+pub trait CanTransport {
+    /// Of the identifiers on the bus.
+    fn layout(&self) -> Layout;
+
+    /// Frames written come back through `subscribe` and `subscribe_raw`, in the order they
+    /// were written.
+    fn echoes_writes(&self) -> bool {
+        false
+    }
+
+    /// Frames received from now on, with the `SubId` of the node, see `from_can_frame`.
+    /// Those that don't parse are skipped.
+    fn subscribe(&self) -> Receiver<(Frame, SubId)>;
+
+    /// All frames received from now on.
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame>;
+
+    fn write_raw(
+        &self,
+        frame: socketcan::CANFrame,
+    ) -> impl Future<Output = std::io::Result<()>> + Send;
+
+    fn write_frame(
+        &self,
+        frame: &Frame,
+        sub_id: SubId,
+    ) -> impl Future<Output = std::io::Result<()>> + Send {
+        self.write_raw(to_can_frame(frame, sub_id, self.layout()))
+    }
+
+    /// Frames of the product come in through `subscribe_raw` and `from_app_frame`.
+    fn write_app_frame<A: AppFrame>(
+        &self,
+        frame: &AnyFrame<A>,
+        sub_id: SubId,
+    ) -> impl Future<Output = std::io::Result<()>> + Send {
+        self.write_raw(to_app_frame(frame, sub_id, self.layout()))
+    }
+
+    /// Sends `frame` and waits for the first answer `expected` accepts, sending again up
+    /// to `options.retries` times. Unless `sub_id` is the broadcast `SubId(0)`, only
+    /// frames with `sub_id` are answers.
+    fn request<T>(
+        &self,
+        frame: &Frame,
+        sub_id: SubId,
+        options: &RequestOptions,
+        expected: impl Fn(&Frame) -> Option<T>,
+    ) -> impl Future<Output = Result<(T, SubId), RequestError>> {
+        let (frame, options) = (*frame, *options);
+        // what the answers come with
+        let sub_id = self.layout().carried(sub_id);
+        async move {
+            for _ in 0..=options.retries {
+                let mut rx = self.subscribe();
+                self.write_frame(&frame, sub_id)
+                    .await
+                    .map_err(RequestError::Io)?;
+
+                let deadline = Instant::now() + options.timeout;
+                while let Ok(res) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                    let (answer, from) = res?;
+                    // comes with the SubId of the node, which may not be the one asked
+                    if let Some(e) = device_error(&answer, &frame, sub_id) {
+                        return Err(RequestError::Device(e));
+                    }
+                    if sub_id != SubId(0) && from != sub_id {
+                        continue;
+                    }
+                    if let Some(v) = expected(&answer) {
+                        return Ok((v, from));
+                    }
+                }
+            }
+            Err(RequestError::Timeout)
+        }
+    }
+
+    /// Sends `frame` once and collects every answer `expected` accepts within `window`,
+    /// for requests that many nodes answer.
+    fn request_all<T>(
+        &self,
+        frame: &Frame,
+        sub_id: SubId,
+        window: Duration,
+        expected: impl Fn(&Frame) -> Option<T>,
+    ) -> impl Future<Output = Result<Vec<(T, SubId)>, RequestError>> {
+        let frame = *frame;
+        let sub_id = self.layout().carried(sub_id);
+        async move {
+            let mut rx = self.subscribe();
+            self.write_frame(&frame, sub_id)
+                .await
+                .map_err(RequestError::Io)?;
+
+            let mut answers = Vec::new();
+            let deadline = Instant::now() + window;
+            while let Ok(res) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                let (answer, from) = res?;
+                if sub_id != SubId(0) && from != sub_id {
+                    continue;
+                }
+                if let Some(v) = expected(&answer) {
+                    answers.push((v, from));
+                }
+            }
+            Ok(answers)
+        }
+    }
+}
+
+/// Errors reported by the nodes.
+pub use canbus_common::frames::error::Code as DeviceError;
+
+/// The error a node answered `request`, sent with `sub_id`, with.
+pub fn device_error(answer: &Frame, request: &Frame, sub_id: SubId) -> Option<DeviceError> {
+    match answer {
+        Frame::NodeError(e) if e.frame == u16::from(request.id()) && e.sub_id == sub_id => {
+            Some(e.code)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// No answer, also after the retries.
+    Timeout,
+    /// The receiver fell behind and missed this many frames.
+    Lagged(u64),
+    BusClosed,
+    Device(DeviceError),
+    Io(std::io::Error),
+}
+
+impl From<RecvError> for RequestError {
+    fn from(e: RecvError) -> Self {
+        match e {
+            RecvError::Lagged(n) => Self::Lagged(n),
+            RecvError::Closed => Self::BusClosed,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "timeout"),
+            RequestError::Lagged(n) => write!(f, "missed {} frames", n),
+            RequestError::BusClosed => write!(f, "CAN bus closed"),
+            RequestError::Device(e) => write!(f, "node error {:?}", e),
+            RequestError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 pub struct CanBus {
     handler: JoinHandle<()>,
-    broadcast_s: broadcast::Sender<(canbus_common::frames::Frame, canbus_common::frame_id::SubId)>,
+    channels: Channels,
     socket_tx: CANSocket,
 }
 
 impl CanBus {
-    pub fn open(ifname: &str) -> Result<CanBus, tokio_socketcan::Error> {
+    pub fn open(ifname: &str, layout: Layout) -> Result<CanBus, tokio_socketcan::Error> {
         let socket_tx = CANSocket::open(ifname)?;
         let socket_rx = CANSocket::open(ifname)?;
 
-        let broadcast = broadcast::channel(1000).0;
+        let channels = Channels::new(layout);
 
-        let t = tokio::spawn({ Self::receiving(socket_rx, broadcast.clone()) });
+        let t = tokio::spawn({ Self::receiving(socket_rx, channels.clone()) });
 
         Ok(Self {
             handler: t,
-            broadcast_s: broadcast,
+            channels,
             socket_tx,
         })
     }
 
-    async fn receiving(
-        mut socket: CANSocket,
-        sender: broadcast::Sender<(canbus_common::frames::Frame, canbus_common::frame_id::SubId)>,
-    ) {
+    async fn receiving(mut socket: CANSocket, channels: Channels) {
         loop {
             match socket.next().await {
-                Some(Ok(v)) => {
-                    if let Ok(v) = from_can_frame(&v) {
-                        let _ = sender.send(v);
-                    }
-                }
+                Some(Ok(v)) => channels.publish(v),
                 e => {
                     println!("receiving {:?}", e)
                 }
             };
         }
     }
+}
 
-    pub fn subscribe(&self) -> Receiver<(Frame, SubId)> {
-        self.broadcast_s.subscribe()
+impl CanTransport for CanBus {
+    fn layout(&self) -> Layout {
+        self.channels.layout()
     }
 
-    pub fn write_frame(
-        &self,
-        frame: &canbus_common::frames::Frame,
-        sub_id: canbus_common::frame_id::SubId,
-    ) -> Result<tokio_socketcan::CANWriteFuture, tokio_socketcan::Error> {
-        self.socket_tx.write_frame(to_can_frame(frame, sub_id))
+    /// The receiving socket is another one than the sending, SocketCAN loops the frames
+    /// back to it.
+    fn echoes_writes(&self) -> bool {
+        true
+    }
+
+    fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        self.channels.subscribe()
+    }
+
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        self.channels.subscribe_raw()
+    }
+
+    async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+        match self.socket_tx.write_frame(frame) {
+            Ok(f) => f.await,
+            Err(tokio_socketcan::Error::IO(e)) => Err(e),
+            Err(e) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{:?}", e),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackBus;
+    use canbus_common::frames::error::{Code, Error};
+    use canbus_common::frames::serial::Serial;
+    use canbus_common::frames::status::{Mode, Status};
+    use canbus_common::frames::Type;
+    use tokio::select;
+
+    fn status(frame: &Frame) -> Option<Status> {
+        match frame {
+            Frame::Status(Type::Data(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn request() {
+        let bus = LoopbackBus::default();
+        let (host, node) = (bus.endpoint(), bus.endpoint());
+        let options = RequestOptions {
+            timeout: Duration::from_millis(100),
+            retries: 1,
+        };
+
+        // answers every status request, the first one with another node's SubId
+        let mut node_rx = node.subscribe();
+        let device = async {
+            let mut sub_id = SubId(5);
+            while let Ok((frame, _)) = node_rx.recv().await {
+                if frame == Frame::Status(Type::Remote) {
+                    let answer = Frame::Status(Type::Data(Status::new(Mode::Application)));
+                    node.write_frame(&answer, sub_id).await.unwrap();
+                    sub_id = SubId(7);
+                }
+            }
+        };
+
+        let host_side = async {
+            let res = host
+                .request(&Frame::Status(Type::Remote), SubId(7), &options, status)
+                .await
+                .unwrap();
+            assert_eq!(res, (Status::new(Mode::Application), SubId(7)));
+
+            let res = host
+                .request(&Frame::BootInfo(Type::Remote), SubId(7), &options, |_| {
+                    Some(())
+                })
+                .await;
+            assert!(matches!(res, Err(RequestError::Timeout)));
+        };
+
+        select! {
+            _ = device => unreachable!(),
+            _ = host_side => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn device_error() {
+        let bus = LoopbackBus::default();
+        let (host, node) = (bus.endpoint(), bus.endpoint());
+        let options = RequestOptions {
+            timeout: Duration::from_millis(100),
+            retries: 0,
+        };
+
+        // a node with the same address, which tells it isn't the one asked
+        let mut node_rx = node.subscribe();
+        let device = async {
+            while let Ok((frame, to)) = node_rx.recv().await {
+                let e = Error::new(frame.id(), to, Code::WrongSubId);
+                node.write_frame(&Frame::NodeError(e), SubId(0x3307))
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let host_side = async {
+            let res = host
+                .request(
+                    &Frame::Status(Type::Remote),
+                    SubId(0x2207),
+                    &options,
+                    status,
+                )
+                .await;
+            assert!(matches!(res, Err(RequestError::Device(Code::WrongSubId))));
+        };
+
+        select! {
+            _ = device => unreachable!(),
+            _ = host_side => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn request_all() {
+        let bus = LoopbackBus::default();
+        let host = bus.endpoint();
+        let nodes = [bus.endpoint(), bus.endpoint()];
+        let mut rx: Vec<_> = nodes.iter().map(|v| v.subscribe()).collect();
+
+        let devices = async {
+            for (n, (node, rx)) in nodes.iter().zip(rx.iter_mut()).enumerate() {
+                rx.recv().await.unwrap();
+                let serial = Serial([1, 2, 3, 4, n as u8]);
+                node.write_frame(&Frame::Serial(Type::Data(serial)), SubId(0))
+                    .await
+                    .unwrap();
+            }
+        };
+        let (answers, _) = tokio::join!(
+            host.request_all(
+                &Frame::Serial(Type::Remote),
+                SubId(0),
+                Duration::from_millis(100),
+                |frame| match frame {
+                    Frame::Serial(Type::Data(v)) => Some(*v),
+                    _ => None,
+                },
+            ),
+            devices
+        );
+        let serials: Vec<_> = answers.unwrap().into_iter().map(|v| v.0 .0[4]).collect();
+        assert_eq!(serials, [0, 1]);
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    struct Relay(bool);
+
+    impl AppFrame for Relay {
+        fn id(&self) -> u16 {
+            8150
+        }
+
+        fn class(&self) -> canbus_common::frame_id::Class {
+            canbus_common::frame_id::Class::Control
+        }
+
+        fn target(&self) -> canbus_common::addressing::Target {
+            canbus_common::addressing::Target::Node
+        }
+
+        fn parse_frame(
+            id: u16,
+            data: canbus_common::frames::ParserType,
+        ) -> Result<Self, canbus_common::frames::ParseError> {
+            match (id, data) {
+                (8150, canbus_common::frames::ParserType::Data(&[v])) => Ok(Relay(v != 0)),
+                _ => Err(canbus_common::frames::ParseError::UnknownId),
+            }
+        }
+
+        fn raw(&self) -> canbus_common::frames::RawType {
+            canbus_common::frames::RawType::new_data([u8::from(self.0)])
+        }
+    }
+
+    #[tokio::test]
+    async fn app_frames() {
+        let bus = LoopbackBus::default();
+        let (host, node) = (bus.endpoint(), bus.endpoint());
+        let mut raw = node.subscribe_raw();
+        let mut frames = node.subscribe();
+
+        host.write_app_frame(&AnyFrame::App(Relay(true)), SubId(7))
+            .await
+            .unwrap();
+        let f = raw.recv().await.unwrap();
+        assert_eq!(
+            from_app_frame(&f, node.layout()),
+            Ok((AnyFrame::App(Relay(true)), SubId(7)))
+        );
+        // not one of the core frames
+        assert!(frames.try_recv().is_err());
+
+        host.write_app_frame(&AnyFrame::<Relay>::Core(Frame::EnterBootloader), SubId(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            frames.recv().await.unwrap(),
+            (Frame::EnterBootloader, SubId(7))
+        );
     }
 }
@@ -0,0 +1,238 @@
+//! Global options, from the command line or from profiles in a TOML file:
+//!
+//! ```toml
+//! transport = "socketcan:can0"
+//! timeout_ms = 2000
+//! layout = "addressed"
+//!
+//! [profiles.plant2]
+//! transport = "tcp:10.1.0.7:20000"
+//! retries = 3
+//! ```
+//!
+//! Command line options win over the profile, the profile over the top level values.
+
+use crate::util::{Error, RequestOptions};
+use canbus_common::identifier::Layout;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Transport {
+    SocketCan(String),
+    Slcan { path: String, bitrate: u32 },
+    Tcp(String),
+}
+
+impl std::str::FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("socketcan", iface)) => Ok(Self::SocketCan(iface.to_string())),
+            Some(("slcan", port)) => {
+                let (path, bitrate) = port.rsplit_once('@').unwrap_or((port, "1000000"));
+                Ok(Self::Slcan {
+                    path: path.to_string(),
+                    bitrate: bitrate
+                        .parse()
+                        .map_err(|_| format!("wrong bitrate {}", bitrate))?,
+                })
+            }
+            Some(("tcp", addr)) => Ok(Self::Tcp(addr.to_string())),
+            _ => Err(format!("unknown transport {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Of the CAN identifier, see `canbus_common::identifier`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IdLayout {
+    /// Only when every node has it
+    Addressed,
+    /// The default, nodes that predate the addressed layout know only this one
+    Legacy,
+}
+
+impl From<IdLayout> for Layout {
+    fn from(v: IdLayout) -> Self {
+        match v {
+            IdLayout::Addressed => Layout::Addressed,
+            IdLayout::Legacy => Layout::Legacy,
+        }
+    }
+}
+
+/// Options that are left unset fall back to the next source.
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+pub struct Profile {
+    /// `socketcan:<iface>`, `slcan:<serial port>@<bitrate>` or `tcp:<gateway address>`
+    #[clap(long, global = true)]
+    pub transport: Option<String>,
+    /// SocketCAN interface, same as `--transport socketcan:<iface>`
+    #[clap(long, global = true, conflicts_with = "transport")]
+    pub iface: Option<String>,
+    /// How long to wait for an answer of a node
+    #[clap(long, global = true)]
+    pub timeout_ms: Option<u64>,
+    /// How many times to repeat a request that got no answer
+    #[clap(long, global = true)]
+    pub retries: Option<u32>,
+    #[clap(long, global = true)]
+    pub format: Option<Format>,
+    /// Layout of the CAN identifier, legacy unless set, the nodes have to use the same
+    #[clap(long, global = true)]
+    pub layout: Option<IdLayout>,
+}
+
+impl Profile {
+    fn transport(&self) -> Option<String> {
+        match &self.iface {
+            Some(iface) => Some(format!("socketcan:{}", iface)),
+            None => self.transport.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    defaults: Profile,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub transport: Transport,
+    pub request: RequestOptions,
+    pub format: Format,
+    pub layout: Layout,
+}
+
+impl Options {
+    /// `config` is read when given, otherwise `~/.config/canbus/config.toml` if it exists.
+    pub fn resolve(
+        cli: Profile,
+        config: Option<PathBuf>,
+        profile: Option<&str>,
+    ) -> Result<Options, Error> {
+        let file = match config {
+            Some(path) => Some(read(&path)?),
+            None => match default_path() {
+                Some(path) if path.exists() => Some(read(&path)?),
+                _ => None,
+            },
+        }
+        .unwrap_or_default();
+
+        let selected = match profile {
+            Some(name) => file
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Config(format!("no profile {}", name)))?,
+            None => Profile::default(),
+        };
+        let sources = [cli, selected, file.defaults];
+
+        let transport = match sources.iter().find_map(|p| p.transport()) {
+            Some(t) => t.parse().map_err(Error::Config)?,
+            None => Transport::SocketCan("can0".to_string()),
+        };
+        let defaults = RequestOptions::default();
+        Ok(Options {
+            transport,
+            request: RequestOptions {
+                timeout: match sources.iter().find_map(|p| p.timeout_ms) {
+                    Some(ms) => Duration::from_millis(ms),
+                    None => defaults.timeout,
+                },
+                retries: sources
+                    .iter()
+                    .find_map(|p| p.retries)
+                    .unwrap_or(defaults.retries),
+            },
+            format: sources
+                .iter()
+                .find_map(|p| p.format)
+                .unwrap_or(Format::Text),
+            layout: sources
+                .iter()
+                .find_map(|p| p.layout)
+                .map_or(Layout::default(), Layout::from),
+        })
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|v| PathBuf::from(v).join(".config")))?;
+    Some(dir.join("canbus").join("config.toml"))
+}
+
+fn read(path: &Path) -> Result<ConfigFile, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+    toml::from_str(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let path = std::env::temp_dir().join(format!("canbus-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            transport = "socketcan:can1"
+            timeout_ms = 500
+            format = "json"
+            layout = "addressed"
+
+            [profiles.remote]
+            transport = "tcp:10.1.0.7:20000"
+            retries = 3
+            "#,
+        )
+        .unwrap();
+        let resolve = |cli, profile| Options::resolve(cli, Some(path.clone()), profile).unwrap();
+
+        let o = resolve(Profile::default(), None);
+        assert!(matches!(o.transport, Transport::SocketCan(ref v) if v == "can1"));
+        assert_eq!(
+            (o.request.timeout.as_millis(), o.request.retries, o.format),
+            (500, 0, Format::Json)
+        );
+        assert_eq!(o.layout, Layout::Addressed);
+
+        let o = resolve(Profile::default(), Some("remote"));
+        assert!(matches!(o.transport, Transport::Tcp(ref v) if v == "10.1.0.7:20000"));
+        assert_eq!((o.request.timeout.as_millis(), o.request.retries), (500, 3));
+
+        let cli = Profile {
+            iface: Some("vcan0".to_string()),
+            retries: Some(1),
+            ..Default::default()
+        };
+        let o = resolve(cli, Some("remote"));
+        assert!(matches!(o.transport, Transport::SocketCan(ref v) if v == "vcan0"));
+        assert_eq!(o.request.retries, 1);
+
+        assert!(Options::resolve(Profile::default(), Some(path.clone()), Some("none")).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Dynamic ids given to the nodes, kept in a TOML file so that every run gives a node the
+//! same id:
+//!
+//! ```toml
+//! 0102030405 = 1
+//! 0102030406 = 2
+//! ```
+
+use crate::util::Error;
+use canbus_common::frames::serial::Serial;
+use canbus_common::identifier::MAX_ADDRESS;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct DynIds {
+    path: Option<PathBuf>,
+    ids: BTreeMap<String, u8>,
+}
+
+fn key(serial: &Serial) -> String {
+    format!("{:?}", serial)
+}
+
+impl DynIds {
+    /// Loads the file when it exists.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let ids = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        Ok(Self {
+            path: Some(path),
+            ids,
+        })
+    }
+
+    /// `~/.local/state/canbus/dyn_ids.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|v| PathBuf::from(v).join(".local/state")))?;
+        Some(dir.join("canbus").join("dyn_ids.toml"))
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn get(&self, serial: &Serial) -> Option<u8> {
+        self.ids.get(&key(serial)).copied()
+    }
+
+    /// The id the node had before, or the lowest one nobody has. `None` when all
+    /// `MAX_ADDRESS` are taken.
+    pub fn allocate(&mut self, serial: &Serial) -> Option<u8> {
+        if let Some(id) = self.get(serial) {
+            return Some(id);
+        }
+        let id = (1..=MAX_ADDRESS).find(|id| !self.ids.values().any(|v| v == id))?;
+        self.ids.insert(key(serial), id);
+        Some(id)
+    }
+
+    /// Frees the id of the node, it may go to another one.
+    pub fn release(&mut self, serial: &Serial) -> Option<u8> {
+        self.ids.remove(&key(serial))
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(&self.ids).map_err(|e| Error::Config(e.to_string()))?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate() {
+        let path = std::env::temp_dir().join(format!("canbus-ids-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (a, b, c) = (
+            Serial([1, 2, 3, 4, 5]),
+            Serial([1, 2, 3, 4, 6]),
+            Serial([1, 2, 3, 4, 7]),
+        );
+
+        let mut ids = DynIds::open(&path).unwrap();
+        assert_eq!(ids.allocate(&a), Some(1));
+        assert_eq!(ids.allocate(&b), Some(2));
+        assert_eq!(ids.allocate(&a), Some(1));
+        ids.save().unwrap();
+
+        let mut ids = DynIds::open(&path).unwrap();
+        assert_eq!(ids.get(&b), Some(2));
+        assert_eq!(ids.allocate(&c), Some(3));
+        assert_eq!(ids.release(&a), Some(1));
+        assert_eq!(ids.allocate(&b), Some(2));
+        assert_eq!(ids.allocate(&a), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+}
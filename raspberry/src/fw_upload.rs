@@ -1,154 +1,104 @@
-use crate::{can_bus, util};
+use crate::can_bus::{CanTransport, RequestError};
+use crate::util;
 use canbus_common::frame_id::SubId;
-use futures_util::{StreamExt, TryFutureExt};
-use std::any::Any;
-use std::sync::Arc;
+use canbus_common::frames::Frame;
+use canbus_common::upload::{Step, Timing, UploadSession};
 use std::time::Duration;
 use tokio::select;
-use tokio::sync::{watch, Mutex};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time::Instant;
 
-pub async fn upload(can: &can_bus::CanBus, sub_id: SubId, file: &[u8]) {
-    let part: Arc<Mutex<usize>> = Default::default();
-    let pause = watch::channel(false).0;
-    let mut pause_sub = pause.subscribe();
-
-    let _res = select! {
-        res = {
-            upload_parts(sub_id, file, can, part.clone(), pause.subscribe())
-        } => res,
-        res = {
-            poll_rx(sub_id, can.subscribe(), part.clone(), pause)
-        } => res
-    };
-
-    // wait pause finish
-    select! {
-        res = async {
-            loop {
-                let p = *pause_sub.borrow();
-                match p {
-                    true => {drop(pause_sub.changed().await);},
-                    false => {break;}
-                }
-            }
-        } => res,
-        _timeout = tokio::time::sleep(Duration::from_secs(20)) => {}
-    }
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Progress {
+    /// Part `n` of `parts` was sent.
+    Sent { n: usize, parts: usize },
+    /// The node asked to go on from another part.
+    ChangePos(usize),
+    /// The node holds the upload while writing a page.
+    Paused(bool),
+    /// The tx queue was full, the frame is sent again.
+    Retry,
+    /// Missed this many frames from the bus, the session times out on what it waited for.
+    Lagged(u64),
 }
 
-async fn poll_rx(
-    id: SubId,
-    mut socket: tokio::sync::broadcast::Receiver<(canbus_common::frames::Frame, SubId)>,
-    part: Arc<Mutex<usize>>,
-    pause: watch::Sender<bool>,
+/// Drives an `UploadSession` over the bus, up to the node writing the last page.
+pub async fn upload(
+    can: &impl CanTransport,
+    sub_id: SubId,
+    file: &[u8],
+    mut progress: impl FnMut(Progress),
 ) -> Result<(), util::Error> {
-    while let Ok(frame) = socket.recv().await {
-        if id == frame.1 {
-            match frame.0 {
-                canbus_common::frames::Frame::FirmwareUploadPartChangePos(value) => {
-                    println!("change pos {:?}", value);
-                    *part.lock().await = value.pos();
-                }
-                canbus_common::frames::Frame::FirmwareUploadPause(value) => {
-                    println!("pause {:?}", value);
-                    pause.send(value).unwrap();
-                }
-                _ => {}
+    // what the frames of the node come with
+    let sub_id = can.layout().carried(sub_id);
+    let mut session = UploadSession::new(file, Timing::default());
+    let mut socket = can.subscribe();
+    let start = Instant::now();
+    let now = || start.elapsed().as_millis() as u64;
+
+    // the node rejecting the upload is an error, other frames go to the session
+    let on_frame = |session: &mut UploadSession,
+                    progress: &mut dyn FnMut(Progress),
+                    (frame, id): (Frame, SubId)|
+     -> Result<(), util::Error> {
+        if let Frame::NodeError(e) = frame {
+            if e.sub_id == sub_id {
+                return Err(util::Error::Request("upload", RequestError::Device(e.code)));
             }
         }
-    }
-
-    Ok(())
-}
-
-async fn upload_parts(
-    id: SubId,
-    file: &[u8],
-    socket: &can_bus::CanBus,
-    part: Arc<Mutex<usize>>,
-    mut pause: watch::Receiver<bool>,
-) -> Result<(), util::Error> {
-    println!("upload_parts {:?}", std::thread::current());
-    let file_len = file.len();
-    println!("file_len {:?}", file_len);
+        if id != sub_id {
+            return Ok(());
+        }
+        match frame {
+            Frame::FirmwareUploadPartChangePos(value) => progress(Progress::ChangePos(value.pos())),
+            Frame::FirmwareUploadPause(value) => progress(Progress::Paused(value)),
+            _ => {}
+        }
+        session.on_frame(&frame, now());
+        Ok(())
+    };
 
     loop {
-        let p = *pause.borrow();
-        match p {
-            true => {
-                // wait change
-                drop(pause.changed().await);
+        // frames that came while sending
+        loop {
+            match socket.try_recv() {
+                Ok(v) => on_frame(&mut session, &mut progress, v)?,
+                Err(TryRecvError::Lagged(l)) => progress(Progress::Lagged(l)),
+                Err(_) => break,
             }
-            false => {
-                // get part
-                let mut part = part.lock().await;
-                if *part > file_len / 5 {
-                    println!("{} {}", *part, file_len / 5 + 1);
-                    return Ok(());
-                }
+        }
 
-                let offset = *part * 5;
-                let data = {
-                    if offset + 5 < file_len {
-                        &file[offset..offset + 5]
-                    } else {
-                        &file[offset..]
+        match session.poll(now()) {
+            Step::Send(frame) => {
+                match can.write_frame(&frame, sub_id).await {
+                    Ok(_) => {
+                        if let Frame::FirmwareUploadPart(part) = frame {
+                            progress(Progress::Sent {
+                                n: part.position(),
+                                parts: session.parts(),
+                            });
+                        }
                     }
-                };
-
-                if data.is_empty() {
-                    break Ok(());
-                }
-
-                match socket
-                    .write_frame(
-                        &canbus_common::frames::Frame::FirmwareUploadPart(
-                            canbus_common::frames::firmware::UploadPart::new(*part, {
-                                let mut buffer = [0u8; 5];
-                                data.iter().zip(buffer.iter_mut()).for_each(|v| {
-                                    *v.1 = *v.0;
-                                });
-                                buffer
-                            })
-                            .unwrap(),
-                        ),
-                        id,
-                    )
-                    .map_err(util::Error::Socket)?
-                    .await
-                {
-                    Ok(_ok) => {
-                        println!(
-                            "part {} {} {:?}",
-                            *part,
-                            data.len(),
-                            std::str::from_utf8(data)
-                        );
-                        *part += 1;
+                    // ENOBUFS, the tx queue is full
+                    Err(err) if err.raw_os_error() == Some(105) => {
+                        progress(Progress::Retry);
+                        session.send_failed(now());
                     }
-                    Err(err) => match err.raw_os_error() {
-                        Some(105) => {
-                            println!("err 105");
-                            tokio::time::sleep(Duration::from_millis(20)).await
-                        }
-                        _ => {
-                            println!(
-                                "ee {:?} {:?} {:?}",
-                                err.raw_os_error(),
-                                err.kind(),
-                                err.type_id()
-                            );
-                            return Err(util::Error::Io(err));
-                        }
+                    Err(err) => return Err(util::Error::Io(err)),
+                }
+            }
+            Step::WaitUntil(at) => {
+                select! {
+                    res = socket.recv() => match res {
+                        Ok(v) => on_frame(&mut session, &mut progress, v)?,
+                        Err(RecvError::Lagged(l)) => progress(Progress::Lagged(l)),
+                        Err(RecvError::Closed) => return Err(util::Error::BusClosed),
                     },
+                    _timeout = tokio::time::sleep_until(start + Duration::from_millis(at)) => {}
                 }
-
-                tokio::time::sleep(Duration::from_millis(match *part % 2 {
-                    0 => 20,
-                    _ => 10,
-                }))
-                .await
             }
+            Step::Done => return Ok(()),
+            Step::Failed(e) => return Err(util::Error::Upload(e)),
         }
     }
 }
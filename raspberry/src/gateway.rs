@@ -0,0 +1,394 @@
+//! CAN over TCP, framed like cannelloni does in TCP mode: both ends send `CANNELLONIv1`,
+//! then every frame is the Linux `can_id` with its flags (big endian), the length and,
+//! unless it is a remote frame, the data.
+
+use crate::can_bus::{CanTransport, Channels};
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::Frame;
+use canbus_common::identifier::Layout;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+const HELLO: &[u8; 12] = b"CANNELLONIv1";
+
+const EFF_FLAG: u32 = 0x8000_0000;
+const RTR_FLAG: u32 = 0x4000_0000;
+
+/// Frames of a client that may still come back from the bus.
+const ECHO_WINDOW: usize = 64;
+
+pub fn encode(frame: &socketcan::CANFrame) -> Vec<u8> {
+    let mut id = frame.id();
+    if frame.is_extended() {
+        id |= EFF_FLAG;
+    }
+    if frame.is_rtr() {
+        id |= RTR_FLAG;
+    }
+
+    let mut out = Vec::with_capacity(4 + 1 + 8);
+    out.extend(id.to_be_bytes());
+    out.push(frame.data().len() as u8);
+    if !frame.is_rtr() {
+        out.extend(frame.data());
+    }
+    out
+}
+
+/// Collects the stream into frames.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn push(&mut self, data: &[u8]) -> std::io::Result<Vec<socketcan::CANFrame>> {
+        self.buf.extend(data);
+
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        while let Some(header) = self.buf.get(pos..pos + 5) {
+            let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let len = header[4] as usize;
+            let rtr = id & RTR_FLAG != 0;
+            if len > 8 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("frame length {}", len),
+                ));
+            }
+
+            let data = match rtr {
+                true => vec![0; len],
+                false => match self.buf.get(pos + 5..pos + 5 + len) {
+                    Some(v) => v.to_vec(),
+                    None => break,
+                },
+            };
+            pos += 5 + if rtr { 0 } else { len };
+
+            let frame = socketcan::CANFrame::new(id & socketcan::EFF_MASK, &data, rtr, false)
+                .map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+                })?;
+            frames.push(frame);
+        }
+        self.buf.drain(..pos);
+
+        Ok(frames)
+    }
+}
+
+async fn handshake(stream: &mut TcpStream) -> std::io::Result<()> {
+    stream.write_all(HELLO).await?;
+    let mut hello = [0_u8; HELLO.len()];
+    stream.read_exact(&mut hello).await?;
+    match &hello == HELLO {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a CAN gateway",
+        )),
+    }
+}
+
+/// Bridges `can` to TCP clients, any number of them at a time.
+pub async fn serve(can: &impl CanTransport, listener: TcpListener) -> std::io::Result<()> {
+    let mut clients = FuturesUnordered::new();
+    loop {
+        select! {
+            res = listener.accept() => {
+                let (stream, addr) = res?;
+                clients.push(client(can, stream, addr));
+            }
+            Some(_) = clients.next() => {}
+        }
+    }
+}
+
+async fn client(can: &impl CanTransport, mut stream: TcpStream, addr: SocketAddr) {
+    println!("client {}", addr);
+    if let Err(e) = handshake(&mut stream).await {
+        println!("client {}: {}", addr, e);
+        return;
+    }
+    let _ = stream.set_nodelay(true);
+
+    match bridge(can, stream).await {
+        Ok(_) => println!("client {} left", addr),
+        Err(e) => println!("client {}: {}", addr, e),
+    }
+}
+
+fn same(a: &socketcan::CANFrame, b: &socketcan::CANFrame) -> bool {
+    a.id() == b.id()
+        && a.is_extended() == b.is_extended()
+        && a.is_rtr() == b.is_rtr()
+        && a.data() == b.data()
+}
+
+async fn bridge(can: &impl CanTransport, stream: TcpStream) -> std::io::Result<()> {
+    let (mut rd, mut wr) = stream.into_split();
+    let mut rx = can.subscribe_raw();
+    let mut decoder = Decoder::default();
+    let mut buf = [0_u8; 256];
+    // the client doesn't get its own frames back. Kept only when the transport echoes them,
+    // an identical frame of a node would be taken for the echo otherwise. Identical writes
+    // of several clients come back as often, each client drops one of them.
+    let echoes = can.echoes_writes();
+    let mut written: VecDeque<socketcan::CANFrame> = VecDeque::new();
+
+    loop {
+        select! {
+            res = rd.read(&mut buf) => {
+                let len = res?;
+                if len == 0 {
+                    return Ok(());
+                }
+                for frame in decoder.push(&buf[..len])? {
+                    // the host side retransmits what gets lost
+                    match can.write_raw(frame).await {
+                        Ok(_) if echoes => {
+                            if written.len() == ECHO_WINDOW {
+                                written.pop_front();
+                            }
+                            written.push_back(frame);
+                        }
+                        Ok(_) => {}
+                        Err(e) => println!("write {}", e),
+                    }
+                }
+            }
+            res = rx.recv() => match res {
+                Ok(frame) => match written.iter().position(|v| same(v, &frame)) {
+                    // echoes come in order, those before never will
+                    Some(pos) => drop(written.drain(..=pos)),
+                    None => wr.write_all(&encode(&frame)).await?,
+                },
+                Err(RecvError::Lagged(l)) => println!("Lagged {}", l),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// A CAN bus behind a remote gateway.
+pub struct Gateway {
+    handler: JoinHandle<()>,
+    channels: Channels,
+    stream: Mutex<OwnedWriteHalf>,
+}
+
+impl Gateway {
+    pub async fn connect(addr: impl ToSocketAddrs, layout: Layout) -> std::io::Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        handshake(&mut stream).await?;
+        let _ = stream.set_nodelay(true);
+        let (rd, wr) = stream.into_split();
+
+        let channels = Channels::new(layout);
+        let handler = tokio::spawn(Self::receiving(rd, channels.clone()));
+
+        Ok(Self {
+            handler,
+            channels,
+            stream: Mutex::new(wr),
+        })
+    }
+
+    async fn receiving(mut stream: impl AsyncRead + Unpin, channels: Channels) {
+        let mut decoder = Decoder::default();
+        let mut buf = [0_u8; 256];
+        loop {
+            let frames = match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => decoder.push(&buf[..len]),
+                Err(e) => Err(e),
+            };
+            match frames {
+                Ok(frames) => frames.into_iter().for_each(|f| channels.publish(f)),
+                Err(e) => {
+                    println!("receiving {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+impl CanTransport for Gateway {
+    fn layout(&self) -> Layout {
+        self.channels.layout()
+    }
+
+    fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        self.channels.subscribe()
+    }
+
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        self.channels.subscribe_raw()
+    }
+
+    async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+        self.stream.lock().await.write_all(&encode(&frame)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackBus;
+    use canbus_common::frames::serial::Serial;
+    use canbus_common::frames::Type;
+
+    /// A bus with nothing but the gateway on it, that hands back what is written like
+    /// SocketCAN does.
+    struct Echo {
+        channels: Channels,
+    }
+
+    impl CanTransport for Echo {
+        fn layout(&self) -> Layout {
+            self.channels.layout()
+        }
+
+        fn echoes_writes(&self) -> bool {
+            true
+        }
+
+        fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+            self.channels.subscribe()
+        }
+
+        fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+            self.channels.subscribe_raw()
+        }
+
+        async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+            self.channels.publish(frame);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let data = socketcan::CANFrame::new(0x1234567, &[1, 2, 3], false, false).unwrap();
+        let remote = socketcan::CANFrame::new(0x20000, &[0; 5], true, false).unwrap();
+        assert_eq!(encode(&data), [0x81, 0x23, 0x45, 0x67, 3, 1, 2, 3]);
+        assert_eq!(encode(&remote), [0xC0, 0x02, 0x00, 0x00, 5]);
+
+        let stream = [encode(&data), encode(&remote)].concat();
+        let mut decoder = Decoder::default();
+        // split in the middle of the data
+        let mut frames = decoder.push(&stream[..6]).unwrap();
+        assert!(frames.is_empty());
+        frames.extend(decoder.push(&stream[6..]).unwrap());
+        let frames: Vec<_> = frames.iter().map(encode).collect();
+        assert_eq!(frames, [encode(&data), encode(&remote)]);
+
+        assert!(decoder.push(&[0, 0, 0, 1, 9]).is_err());
+    }
+
+    #[tokio::test]
+    async fn localhost() {
+        let bus = LoopbackBus::default();
+        let (local, node) = (bus.endpoint(), bus.endpoint());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = async {
+            let remote = Gateway::connect(addr, Layout::default()).await.unwrap();
+            let mut node_rx = node.subscribe();
+            let mut remote_rx = remote.subscribe();
+
+            let request = Frame::Serial(Type::Remote);
+            remote.write_frame(&request, SubId(0)).await.unwrap();
+            assert_eq!(node_rx.recv().await.unwrap(), (request, SubId(0)));
+
+            let answer = Frame::Serial(Type::Data(Serial([1, 2, 3, 4, 5])));
+            node.write_frame(&answer, SubId(0)).await.unwrap();
+            assert_eq!(remote_rx.recv().await.unwrap(), (answer, SubId(0)));
+        };
+
+        select! {
+            res = serve(&local, listener) => panic!("{:?}", res),
+            _ = client => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn clients() {
+        let can = Echo {
+            channels: Channels::new(Layout::default()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let clients = async {
+            let first = Gateway::connect(addr, Layout::default()).await.unwrap();
+            let second = Gateway::connect(addr, Layout::default()).await.unwrap();
+            let mut first_rx = first.subscribe();
+            let mut second_rx = second.subscribe();
+            // both bridges subscribe once the handshake is over on the gateway side
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            let request = Frame::Serial(Type::Remote);
+            first.write_frame(&request, SubId(0)).await.unwrap();
+            assert_eq!(second_rx.recv().await.unwrap(), (request, SubId(0)));
+
+            let status = Frame::Status(Type::Remote);
+            second.write_frame(&status, SubId(0)).await.unwrap();
+            // not its own request
+            assert_eq!(first_rx.recv().await.unwrap(), (status, SubId(0)));
+            assert!(first_rx.try_recv().is_err());
+        };
+
+        select! {
+            res = serve(&can, listener) => panic!("{:?}", res),
+            _ = clients => {}
+        }
+    }
+
+    /// Without echoes a frame of a node that equals one the client wrote still reaches it.
+    #[tokio::test]
+    async fn node_repeats_a_written_frame() {
+        let bus = LoopbackBus::default();
+        let (local, node) = (bus.endpoint(), bus.endpoint());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = async {
+            let remote = Gateway::connect(addr, Layout::default()).await.unwrap();
+            let mut node_rx = node.subscribe();
+            let mut remote_rx = remote.subscribe();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            let status = Frame::Status(Type::Remote);
+            remote.write_frame(&status, SubId(0)).await.unwrap();
+            assert_eq!(node_rx.recv().await.unwrap(), (status, SubId(0)));
+
+            node.write_frame(&status, SubId(0)).await.unwrap();
+            assert_eq!(remote_rx.recv().await.unwrap(), (status, SubId(0)));
+        };
+
+        select! {
+            res = serve(&local, listener) => panic!("{:?}", res),
+            _ = client => {}
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Host side of the firmware update over CAN.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), canbus_raspberry::Error> {
+//! use canbus_raspberry::node::{self, Node};
+//! use canbus_raspberry::util::RequestOptions;
+//!
+//! let transport = "socketcan:can0".parse().unwrap();
+//! let can = canbus_raspberry::open(&transport, Default::default()).await?;
+//! let options = RequestOptions::default();
+//! for (serial, _) in node::discover(&can, &options).await? {
+//!     let node = Node::assign(&can, options, serial, 10).await?;
+//!     println!("{:?} {:?}", serial, node.status().await?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod can_bus;
+pub mod config;
+pub mod dyn_ids;
+pub mod fw_upload;
+pub mod gateway;
+pub mod loopback;
+pub mod node;
+pub mod slcan;
+pub mod util;
+
+pub use util::Error;
+
+use can_bus::CanTransport;
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::Frame;
+use canbus_common::identifier::Layout;
+use config::Transport;
+use tokio::sync::broadcast::Receiver;
+
+/// One of the transports, picked at runtime.
+pub enum Bus {
+    SocketCan(can_bus::CanBus),
+    Slcan(slcan::Slcan),
+    Tcp(gateway::Gateway),
+    Loopback(loopback::Loopback),
+}
+
+pub async fn open(transport: &Transport, layout: Layout) -> Result<Bus, Error> {
+    Ok(match transport {
+        Transport::SocketCan(iface) => {
+            Bus::SocketCan(can_bus::CanBus::open(iface, layout).map_err(Error::Socket)?)
+        }
+        Transport::Slcan { path, bitrate } => {
+            Bus::Slcan(slcan::Slcan::open(path, *bitrate, layout).await?)
+        }
+        Transport::Tcp(addr) => Bus::Tcp(gateway::Gateway::connect(addr.as_str(), layout).await?),
+    })
+}
+
+impl CanTransport for Bus {
+    fn layout(&self) -> Layout {
+        match self {
+            Bus::SocketCan(v) => v.layout(),
+            Bus::Slcan(v) => v.layout(),
+            Bus::Tcp(v) => v.layout(),
+            Bus::Loopback(v) => v.layout(),
+        }
+    }
+
+    fn echoes_writes(&self) -> bool {
+        match self {
+            Bus::SocketCan(v) => v.echoes_writes(),
+            Bus::Slcan(v) => v.echoes_writes(),
+            Bus::Tcp(v) => v.echoes_writes(),
+            Bus::Loopback(v) => v.echoes_writes(),
+        }
+    }
+
+    fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        match self {
+            Bus::SocketCan(v) => v.subscribe(),
+            Bus::Slcan(v) => v.subscribe(),
+            Bus::Tcp(v) => v.subscribe(),
+            Bus::Loopback(v) => v.subscribe(),
+        }
+    }
+
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        match self {
+            Bus::SocketCan(v) => v.subscribe_raw(),
+            Bus::Slcan(v) => v.subscribe_raw(),
+            Bus::Tcp(v) => v.subscribe_raw(),
+            Bus::Loopback(v) => v.subscribe_raw(),
+        }
+    }
+
+    async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+        match self {
+            Bus::SocketCan(v) => v.write_raw(frame).await,
+            Bus::Slcan(v) => v.write_raw(frame).await,
+            Bus::Tcp(v) => v.write_raw(frame).await,
+            Bus::Loopback(v) => v.write_raw(frame).await,
+        }
+    }
+}
@@ -1,156 +1,298 @@
-mod can_bus;
-mod fw_upload;
-mod util;
-
-use canbus_common::frame_id::SubId;
+use canbus_common::frames::status::Mode;
 use canbus_common::frames::version::Version;
-use canbus_common::frames::Frame;
-use futures_util::{FutureExt, StreamExt};
-
-use tokio::select;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
-use tokio::time::{sleep, Duration};
-
-use clap::{arg, Parser};
-use crate::util::Error;
-
+use canbus_raspberry::can_bus::CanTransport;
+use canbus_raspberry::config::{self, Format, Options};
+use canbus_raspberry::dyn_ids::DynIds;
+use canbus_raspberry::fw_upload::Progress;
+use canbus_raspberry::node::{self, Node};
+use canbus_raspberry::{gateway, Error};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-enum Args {
+struct Args {
+    /// TOML file with the options, `~/.config/canbus/config.toml` by default
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    /// Profile of the config file to use
+    #[clap(long, global = true)]
+    profile: Option<String>,
+    #[clap(flatten)]
+    options: config::Profile,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Finds the nodes and lists their SubId and versions.
     ShowSerials,
+    /// Lists the addresses the nodes claimed on their own.
+    ShowClaims,
+    /// Gives every node its own dyn_id, the same as in earlier runs.
+    AssignIds,
+    /// Makes the node forget its dyn_id, it claims an address of its own again.
+    ClearId {
+        #[clap(long)]
+        serial: String,
+    },
     UpgradeFw {
         #[clap(long)]
         file_path: String,
         #[clap(long)]
         serial: String,
     },
+    BootInfo {
+        #[clap(long)]
+        serial: String,
+    },
+    History {
+        #[clap(long)]
+        serial: String,
+    },
+    /// Shares the bus with remote clients over TCP.
+    Gateway {
+        #[clap(long, default_value = "0.0.0.0:20000")]
+        listen: String,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), util::Error> {
+async fn main() -> Result<(), Error> {
     let args = Args::parse();
-    println!("{:?}", args);
+    let options = Options::resolve(args.options, args.config, args.profile.as_deref())?;
+    let can = canbus_raspberry::open(&options.transport, options.layout).await?;
+    run(&can, &options, args.command).await
+}
 
-    let can = can_bus::CanBus::open("can0").unwrap();
+fn version(v: Version) -> String {
+    format!("{}.{}.{} {}", v.major, v.minor, v.path, v.build)
+}
 
-    match args {
-        Args::ShowSerials => {
-            let mut can_receiver = can.subscribe();
-            can.write_frame(
-                &canbus_common::frames::Frame::Serial(canbus_common::frames::Type::Remote),
-                canbus_common::frame_id::SubId(0),
-            )
-                .map_err(util::Error::Socket)?
-                .await?;
+fn dyn_ids() -> Result<DynIds, Error> {
+    match DynIds::default_path() {
+        Some(path) => DynIds::open(path),
+        None => Ok(DynIds::default()),
+    }
+}
 
-            let mut list = Vec::new();
+/// Gives the node the dyn_id it got in earlier runs, or a new one.
+async fn assign<'a, T: CanTransport>(
+    can: &'a T,
+    options: &Options,
+    serial: &str,
+) -> Result<Node<'a, T>, Error> {
+    let serial =
+        canbus_common::frames::serial::Serial::try_from(serial).map_err(|_| Error::WrongSerial)?;
+    let mut ids = dyn_ids()?;
+    let dyn_id = ids.allocate(&serial).ok_or(Error::NoFreeDynId)?;
+    ids.save()?;
+    Node::assign(can, options.request, serial, dyn_id).await
+}
 
-            select! {
-                res = async {
-                    loop {
-                        match can_receiver.recv().await {
-                            Ok(frame) => {
-                                if let Frame::Serial(canbus_common::frames::Type::Data(v)) = frame.0 {
-                                    list.push(v);
-                                }
-                            },
-                            Err(RecvError::Closed) => break,
-                            _ => {},
+async fn run(can: &impl CanTransport, options: &Options, command: Command) -> Result<(), Error> {
+    let text = options.format == Format::Text;
+    match command {
+        Command::ShowSerials => {
+            let list = node::discover(can, &options.request).await?;
+            if text {
+                println!(
+                    "{:<10} {:>6} {:>16} {:>16}",
+                    "serial", "sub_id", "firmware", "hardware"
+                );
+            }
+            let mut nodes = Vec::new();
+            for (serial, sub_id) in list {
+                // without an address of its own the node can't be asked alone
+                let (firmware, hardware) = match sub_id.is_valid() {
+                    true => {
+                        let node = Node::at(can, options.request, serial, sub_id);
+                        (
+                            node.firmware_version().await.ok().map(version),
+                            node.hardware_version().await.ok().map(version),
+                        )
+                    }
+                    false => (None, None),
+                };
+                match text {
+                    true => println!(
+                        "{:?} {:>6} {:>16} {:>16}",
+                        serial,
+                        format!("{:04x}", sub_id.0),
+                        firmware.as_deref().unwrap_or("-"),
+                        hardware.as_deref().unwrap_or("-")
+                    ),
+                    false => nodes.push(json!({
+                        "serial": format!("{:?}", serial),
+                        "sub_id": sub_id.0,
+                        "firmware": firmware,
+                        "hardware": hardware,
+                    })),
+                }
+            }
+            if !text {
+                println!("{}", json!(nodes));
+            }
+        }
+        Command::ShowClaims => {
+            let list = node::claims(can, &options.request).await?;
+            match text {
+                true => {
+                    for (claim, sub_id) in list {
+                        match claim.address {
+                            0 => println!("{:?} no free address", claim.serial),
+                            address => {
+                                println!("{:?} {:>3} {:04x}", claim.serial, address, sub_id.0)
+                            }
                         }
                     }
-                    ()
-                } => res,
-                _timeout = sleep(Duration::from_millis(2000)) => ()
+                }
+                false => {
+                    let list: Vec<_> = list
+                        .iter()
+                        .map(|(claim, sub_id)| {
+                            json!({
+                                "serial": format!("{:?}", claim.serial),
+                                "address": claim.address,
+                                "sub_id": sub_id.0,
+                            })
+                        })
+                        .collect();
+                    println!("{}", json!(list));
+                }
             }
-
-            println!("Serials: {:?}", list);
-        },
-        Args::UpgradeFw { file_path, serial } => {
-            let serial = canbus_common::frames::serial::Serial::try_from(serial.as_str()).unwrap();
-            let data = std::fs::read(file_path.as_str()).unwrap();
-
-            println!("Attempt to set dyn_id");
-            can.write_frame(
-                &canbus_common::frames::Frame::DynId(canbus_common::frames::dyn_id::Data::new(
-                    serial, 10,
-                )),
-                canbus_common::frame_id::SubId(0),
-            )
-                .map_err(util::Error::Socket)?
-                .await?;
-
-            // get serial
-            let can_receiver = can.subscribe();
-            can.write_frame(
-                &canbus_common::frames::Frame::Serial(canbus_common::frames::Type::Remote),
-                canbus_common::frame_id::SubId(0),
-            )
-                .map_err(util::Error::Socket)?
-                .await?;
-            let res = util::wait_data(can_receiver, |frame| match frame {
-                Frame::Serial(canbus_common::frames::Type::Data(value)) if value == &serial => {
-                    Some(())
+        }
+        Command::AssignIds => {
+            let nodes = node::assign_all(can, &options.request, &mut dyn_ids()?).await?;
+            let mut list = Vec::new();
+            for (serial, node) in nodes {
+                let sub_id = node.map(|v| v.sub_id().0).map_err(|e| e.to_string());
+                match text {
+                    true => match sub_id {
+                        Ok(sub_id) => println!("{:?} {:04x}", serial, sub_id),
+                        Err(e) => println!("{:?} {}", serial, e),
+                    },
+                    false => list.push(match sub_id {
+                        Ok(sub_id) => {
+                            json!({ "serial": format!("{:?}", serial), "sub_id": sub_id })
+                        }
+                        Err(e) => json!({ "serial": format!("{:?}", serial), "error": e }),
+                    }),
                 }
-                _ => None,
-            })
-                .await.unwrap();
-
-            if res.1.split()[1] != 10 {
-                return Err(util::Error::Other("Unable to set dyn_id".to_string()));
             }
-            let sub_id = res.1;
+            if !text {
+                println!("{}", json!(list));
+            }
+        }
+        Command::ClearId { serial } => {
+            let serial = canbus_common::frames::serial::Serial::try_from(serial.as_str())
+                .map_err(|_| Error::WrongSerial)?;
+            canbus_raspberry::util::clear_dyn_id(can, serial).await?;
+            let mut ids = dyn_ids()?;
+            ids.release(&serial);
+            ids.save()?;
+        }
+        Command::BootInfo { serial } => {
+            let node = assign(can, options, &serial).await?;
+            let info = node.boot_info().await?;
+            let bootloader = node.bootloader_version().await.ok().map(version);
 
-            let timer = std::time::Instant::now();
+            match text {
+                true => {
+                    println!("Boot reason: {:?}", info.boot_reason);
+                    println!("Last update: {:?}", info.update_result);
+                    println!("Bootloader: {}", bootloader.as_deref().unwrap_or("unknown"));
+                }
+                false => println!(
+                    "{}",
+                    json!({
+                        "boot_reason": format!("{:?}", info.boot_reason),
+                        "update_result": format!("{:?}", info.update_result),
+                        "bootloader": bootloader,
+                    })
+                ),
+            }
+        }
+        Command::History { serial } => {
+            let node = assign(can, options, &serial).await?;
 
-            fw_upload::upload(&can, sub_id, &data).await;
+            if text {
+                println!(
+                    "{:>3} {:>6} {:>16} {:>16} outcome",
+                    "#", "boot", "from", "to"
+                );
+            }
+            let mut records = Vec::new();
+            for index in 0..=u8::MAX {
+                match (node.history_record(index).await?, text) {
+                    (Some(Ok(r)), true) => println!(
+                        "{:>3} {:>6} {:>16} {:>16} {:?}",
+                        index,
+                        r.boot_count,
+                        version(r.from),
+                        version(r.to),
+                        r.outcome
+                    ),
+                    (Some(Ok(r)), false) => records.push(json!({
+                        "index": index,
+                        "boot_count": r.boot_count,
+                        "from": version(r.from),
+                        "to": version(r.to),
+                        "outcome": format!("{:?}", r.outcome),
+                    })),
+                    (Some(Err(_)), true) => println!("{:>3} broken record", index),
+                    (Some(Err(_)), false) => {
+                        records.push(json!({ "index": index, "broken": true }))
+                    }
+                    (None, _) => break,
+                }
+            }
+            if !text {
+                println!("{}", json!(records));
+            }
+        }
+        Command::Gateway { listen } => {
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            gateway::serve(can, listener).await?;
+        }
+        Command::UpgradeFw { file_path, serial } => {
+            let data = std::fs::read(file_path.as_str())?;
 
-            can.write_frame(
-                &canbus_common::frames::Frame::FirmwareUploadFinished,
-                sub_id,
-            )
-                .map_err(util::Error::Socket)?
-                .await?;
+            eprintln!("Attempt to set dyn_id");
+            let node = assign(can, options, &serial).await?;
 
-            println!("upload finish {:?}", timer.elapsed());
-            //sleep(Duration::from_millis(10000)).await;
+            match node.status().await {
+                Ok(status) if status.mode == Mode::Bootloader => {
+                    eprintln!("Node is in bootloader, recovering");
+                }
+                Ok(_) => {}
+                Err(_) => eprintln!("Node did not report its status"),
+            }
 
-            println!("rq version");
-            let can_receiver = can.subscribe();
-            can.write_frame(
-                &canbus_common::frames::Frame::PendingFirmwareVersion(canbus_common::frames::Type::Remote),
-                sub_id,
-            )
-                .map_err(util::Error::Socket)?
+            let timer = std::time::Instant::now();
+            let uploaded = node
+                .upload(&data, |p| match p {
+                    Progress::Sent { n, parts } if n.is_multiple_of(100) || n + 1 == parts => {
+                        eprintln!("part {}/{}", n + 1, parts)
+                    }
+                    Progress::Sent { .. } => {}
+                    p => eprintln!("{:?}", p),
+                })
                 .await?;
-            let res = util::wait_data(can_receiver, |frame| {
-                println!("frame__ {:?}", frame);
-                match frame {
-                    canbus_common::frames::Frame::PendingFirmwareVersion(
-                        canbus_common::frames::Type::Data(value),
-                    ) => Some(*value),
-                    _ => None,
-                }
-            })
-                .await
-                .ok_or(util::Error::Other("Request pending version".to_string()));
-            match res {
-                Ok((Some(v), _)) => {
-                    println!("Upload successful. Start update");
-                    can.write_frame(
-                        &canbus_common::frames::Frame::FirmwareStartUpdate,
-                        sub_id,
-                    )
-                        .map_err(util::Error::Socket)?
-                        .await?;
-                }
-                Ok((None, _)) => {
-                    println!("Upload error");
-                }
-                Err(e) => {
+            eprintln!("upload finish {:?}", timer.elapsed());
 
-                }
+            node.activate().await?;
+            match text {
+                true => println!("Upload successful, starting {}", version(uploaded)),
+                false => println!(
+                    "{}",
+                    json!({
+                        "uploaded": version(uploaded),
+                        "upload_ms": timer.elapsed().as_millis() as u64,
+                    })
+                ),
             }
         }
     }
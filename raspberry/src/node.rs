@@ -0,0 +1,278 @@
+//! Requests to the nodes.
+
+use crate::can_bus::CanTransport;
+use crate::dyn_ids::DynIds;
+use crate::fw_upload::{self, Progress};
+use crate::util::{self, Error, RequestOptions};
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::boot_info::BootInfo;
+use canbus_common::frames::claim::Claim;
+use canbus_common::frames::history::{Record, RecordAssembler, RecordPart};
+use canbus_common::frames::serial::Serial;
+use canbus_common::frames::status::Status;
+use canbus_common::frames::version::Version;
+use canbus_common::frames::{Frame, Type};
+
+/// Most rounds `discover` runs.
+pub const DISCOVERY_ROUNDS: usize = 8;
+
+/// Serials of the nodes on the bus, with the `SubId` they answered with. Nodes answer in
+/// a slot picked from their serial, another one each round, so the requests are repeated
+/// until a round finds no new node; each round waits for `options.timeout`.
+pub async fn discover(
+    can: &impl CanTransport,
+    options: &RequestOptions,
+) -> Result<Vec<(Serial, SubId)>, Error> {
+    let mut list: Vec<(Serial, SubId)> = Vec::new();
+    for _ in 0..DISCOVERY_ROUNDS {
+        let answers = can
+            .request_all(
+                &Frame::Serial(Type::Remote),
+                SubId(0),
+                options.timeout,
+                |frame| match frame {
+                    Frame::Serial(Type::Data(v)) => Some(*v),
+                    _ => None,
+                },
+            )
+            .await
+            .map_err(|e| Error::Request("serial", e))?;
+
+        let known = list.len();
+        for (serial, sub_id) in answers {
+            match list.iter_mut().find(|v| v.0 == serial) {
+                Some(v) => v.1 = sub_id,
+                None => list.push((serial, sub_id)),
+            }
+        }
+        if list.len() == known {
+            break;
+        }
+    }
+    list.sort_by_key(|v| v.0 .0);
+    Ok(list)
+}
+
+/// Addresses the nodes claimed on their own, asks every node to announce its claim and
+/// listens for `options.timeout`. A node that found no free address claims address 0.
+pub async fn claims(
+    can: &impl CanTransport,
+    options: &RequestOptions,
+) -> Result<Vec<(Claim, SubId)>, Error> {
+    let answers = can
+        .request_all(
+            &Frame::AddressClaim(Type::Remote),
+            SubId(0),
+            options.timeout,
+            |frame| match frame {
+                Frame::AddressClaim(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+        .map_err(|e| Error::Request("address claim", e))?;
+
+    // the last claim of a node counts, it may have moved on during the window
+    let mut list: Vec<(Claim, SubId)> = Vec::new();
+    for (claim, _) in answers {
+        let sub_id = match claim.address {
+            0 => SubId(0),
+            address => SubId::for_node(&claim.serial, address),
+        };
+        let sub_id = can.layout().carried(sub_id);
+        list.retain(|v| v.0.serial != claim.serial);
+        list.push((claim, sub_id));
+    }
+    list.sort_by_key(|v| v.0.address);
+    Ok(list)
+}
+
+/// Gives every node on the bus its own dyn_id, the one it had in `ids` if any, and
+/// stores the new ones.
+pub async fn assign_all<'a, T: CanTransport>(
+    can: &'a T,
+    options: &RequestOptions,
+    ids: &mut DynIds,
+) -> Result<Vec<(Serial, Result<Node<'a, T>, Error>)>, Error> {
+    let mut nodes = Vec::new();
+    for (serial, _) in discover(can, options).await? {
+        let node = match ids.allocate(&serial) {
+            Some(dyn_id) => Node::assign(can, *options, serial, dyn_id).await,
+            None => Err(Error::NoFreeDynId),
+        };
+        nodes.push((serial, node));
+    }
+    ids.save()?;
+    Ok(nodes)
+}
+
+/// A node that got a dynamic id, so that its answers can be told apart.
+pub struct Node<'a, T> {
+    can: &'a T,
+    options: RequestOptions,
+    serial: Serial,
+    sub_id: SubId,
+}
+
+impl<'a, T: CanTransport> Node<'a, T> {
+    /// Gives `dyn_id` to the node with `serial` and checks the `SubId` it answers with.
+    pub async fn assign(
+        can: &'a T,
+        options: RequestOptions,
+        serial: Serial,
+        dyn_id: u8,
+    ) -> Result<Node<'a, T>, Error> {
+        let sub_id = util::set_dyn_id(can, &options, serial, dyn_id).await?;
+        Ok(Self {
+            can,
+            options,
+            serial,
+            sub_id,
+        })
+    }
+
+    /// A node that has a `SubId` already, one it claimed or got before.
+    pub fn at(can: &'a T, options: RequestOptions, serial: Serial, sub_id: SubId) -> Self {
+        Self {
+            can,
+            options,
+            serial,
+            sub_id,
+        }
+    }
+
+    #[inline]
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    #[inline]
+    pub fn sub_id(&self) -> SubId {
+        self.sub_id
+    }
+
+    async fn query<V>(
+        &self,
+        name: &'static str,
+        frame: Frame,
+        comparator: impl Fn(&Frame) -> Option<V>,
+    ) -> Result<V, Error> {
+        self.can
+            .request(&frame, self.sub_id, &self.options, comparator)
+            .await
+            .map(|v| v.0)
+            .map_err(|e| Error::Request(name, e))
+    }
+
+    /// Whether the app or the bootloader runs.
+    pub async fn status(&self) -> Result<Status, Error> {
+        self.query("status", Frame::Status(Type::Remote), |frame| match frame {
+            Frame::Status(Type::Data(v)) => Some(*v),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Why the node booted last and how the last update went.
+    pub async fn boot_info(&self) -> Result<BootInfo, Error> {
+        self.query(
+            "boot info",
+            Frame::BootInfo(Type::Remote),
+            |frame| match frame {
+                Frame::BootInfo(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Version of the running app, a node without a valid image doesn't answer.
+    pub async fn firmware_version(&self) -> Result<Version, Error> {
+        self.query(
+            "firmware version",
+            Frame::FirmwareVersion(Type::Remote),
+            |frame| match frame {
+                Frame::FirmwareVersion(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn hardware_version(&self) -> Result<Version, Error> {
+        self.query(
+            "hardware version",
+            Frame::HardwareVersion(Type::Remote),
+            |frame| match frame {
+                Frame::HardwareVersion(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn bootloader_version(&self) -> Result<Version, Error> {
+        self.query(
+            "bootloader version",
+            Frame::BootloaderVersion(Type::Remote),
+            |frame| match frame {
+                Frame::BootloaderVersion(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Version of the uploaded image waiting to be installed, `None` when there is no
+    /// valid one.
+    pub async fn pending_version(&self) -> Result<Option<Version>, Error> {
+        self.query(
+            "pending version",
+            Frame::PendingFirmwareVersion(Type::Remote),
+            |frame| match frame {
+                Frame::PendingFirmwareVersion(Type::Data(v)) => Some(*v),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Entry `index` of the update history, newest first. `None` past the last entry,
+    /// `Some(Err(_))` for a broken one.
+    pub async fn history_record(&self, index: u8) -> Result<Option<Result<Record, ()>>, Error> {
+        let assembler = std::cell::RefCell::new(RecordAssembler::new());
+        self.query(
+            "history",
+            Frame::UpdateHistoryRequest(index),
+            |frame| match frame {
+                Frame::UpdateHistoryRecord(part) if part.index() == index => match part {
+                    RecordPart::Data { part, data, .. } => {
+                        assembler.borrow_mut().push(*part, data).map(Some)
+                    }
+                    RecordPart::Empty { .. } => Some(None),
+                },
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Uploads `file` (an image with the header of `add_header.rs`) into the pending slot,
+    /// returns the version the node found in it.
+    pub async fn upload(
+        &self,
+        file: &[u8],
+        progress: impl FnMut(Progress),
+    ) -> Result<Version, Error> {
+        fw_upload::upload(self.can, self.sub_id, file, progress).await?;
+        self.pending_version().await?.ok_or(Error::BrokenImage)
+    }
+
+    /// Reboots the node into the pending image.
+    pub async fn activate(&self) -> Result<(), Error> {
+        self.can
+            .write_frame(&Frame::FirmwareStartUpdate, self.sub_id)
+            .await?;
+        Ok(())
+    }
+}
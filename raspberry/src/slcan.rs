@@ -0,0 +1,313 @@
+//! Lawicel serial-line CAN, as spoken by CANable and most other USB-CAN dongles.
+//! Frames are ASCII lines ended with `\r`, e.g. `T1234567F2AABB` is an extended data
+//! frame with id `0x1234567F` and two data bytes.
+
+use crate::can_bus::{CanTransport, Channels};
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::Frame;
+use canbus_common::identifier::Layout;
+use std::fmt::Write;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_serial::SerialStream;
+
+/// Bitrates the adapters know, the index is the argument of the `S` command.
+const BITRATES: [u32; 9] = [
+    10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000,
+];
+
+/// Longest line is an extended data frame with a timestamp.
+const MAX_LINE: usize = 1 + 8 + 1 + 16 + 4;
+
+pub fn encode(frame: &socketcan::CANFrame) -> String {
+    let mut line = String::with_capacity(MAX_LINE + 1);
+    let kind = match (frame.is_extended(), frame.is_rtr()) {
+        (false, false) => 't',
+        (true, false) => 'T',
+        (false, true) => 'r',
+        (true, true) => 'R',
+    };
+    line.push(kind);
+    match frame.is_extended() {
+        true => write!(line, "{:08X}", frame.id()),
+        false => write!(line, "{:03X}", frame.id()),
+    }
+    .unwrap();
+    write!(line, "{}", frame.data().len()).unwrap();
+    if !frame.is_rtr() {
+        for b in frame.data() {
+            write!(line, "{:02X}", b).unwrap();
+        }
+    }
+    line.push('\r');
+    line
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Frame(socketcan::CANFrame),
+    /// A command, or a transmitted frame, was accepted.
+    Ack,
+    /// A command was refused.
+    Nack,
+}
+
+/// Splits what the adapter sends into events.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    line: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn push(&mut self, byte: u8) -> Option<Event> {
+        match byte {
+            b'\r' => {
+                let event = parse(&self.line);
+                self.line.clear();
+                event
+            }
+            0x07 => {
+                self.line.clear();
+                Some(Event::Nack)
+            }
+            // broken line, drop it
+            _ if self.line.len() >= MAX_LINE => {
+                self.line.clear();
+                None
+            }
+            b => {
+                self.line.push(b);
+                None
+            }
+        }
+    }
+}
+
+fn parse(line: &[u8]) -> Option<Event> {
+    let hex = |s: &[u8]| u32::from_str_radix(std::str::from_utf8(s).ok()?, 16).ok();
+
+    let (id_len, rtr) = match line.first() {
+        None | Some(b'z') | Some(b'Z') => return Some(Event::Ack),
+        Some(b't') => (3, false),
+        Some(b'T') => (8, false),
+        Some(b'r') => (3, true),
+        Some(b'R') => (8, true),
+        // version, serial number, status flags
+        _ => return None,
+    };
+    let id = hex(line.get(1..1 + id_len)?)?;
+    let dlc = hex(line.get(1 + id_len..2 + id_len)?)? as usize;
+    if dlc > 8 {
+        return None;
+    }
+
+    let data = match rtr {
+        true => vec![0; dlc],
+        false => line
+            .get(2 + id_len..2 + id_len + dlc * 2)?
+            .chunks(2)
+            .map(|v| hex(v).map(|v| v as u8))
+            .collect::<Option<Vec<u8>>>()?,
+    };
+    socketcan::CANFrame::new(id, &data, rtr, false)
+        .ok()
+        .map(Event::Frame)
+}
+
+/// An slcan adapter on a serial port.
+pub struct Slcan {
+    handler: JoinHandle<()>,
+    channels: Channels,
+    port: Mutex<WriteHalf<SerialStream>>,
+}
+
+impl Slcan {
+    /// Opens the channel at `bitrate`, one of the standard CAN bitrates.
+    pub async fn open(path: &str, bitrate: u32, layout: Layout) -> std::io::Result<Self> {
+        let setup = BITRATES
+            .iter()
+            .position(|v| *v == bitrate)
+            .map(|n| format!("C\rS{}\rO\r", n))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unsupported bitrate {}", bitrate),
+                )
+            })?;
+
+        // the baudrate doesn't matter for USB adapters
+        let port = SerialStream::open(&tokio_serial::new(path, 115_200))?;
+        let (rx, mut tx) = tokio::io::split(port);
+        // closing a closed channel is refused, the answers are not checked
+        tx.write_all(setup.as_bytes()).await?;
+
+        let channels = Channels::new(layout);
+        let handler = tokio::spawn(Self::receiving(rx, channels.clone()));
+
+        Ok(Self {
+            handler,
+            channels,
+            port: Mutex::new(tx),
+        })
+    }
+
+    async fn receiving(mut port: ReadHalf<SerialStream>, channels: Channels) {
+        let mut decoder = Decoder::default();
+        let mut buf = [0_u8; 256];
+        loop {
+            let len = match port.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    println!("receiving {:?}", e);
+                    break;
+                }
+            };
+            for b in &buf[..len] {
+                match decoder.push(*b) {
+                    Some(Event::Frame(frame)) => channels.publish(frame),
+                    Some(Event::Nack) => println!("slcan: command refused"),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Slcan {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+impl CanTransport for Slcan {
+    fn layout(&self) -> Layout {
+        self.channels.layout()
+    }
+
+    fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        self.channels.subscribe()
+    }
+
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        self.channels.subscribe_raw()
+    }
+
+    async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+        self.port
+            .lock()
+            .await
+            .write_all(encode(&frame).as_bytes())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canbus_common::frames::Type;
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+
+    fn decode(s: &str) -> Vec<String> {
+        let mut decoder = Decoder::default();
+        s.bytes()
+            .filter_map(|b| decoder.push(b))
+            .map(|e| match e {
+                Event::Frame(f) => encode(&f),
+                e => format!("{:?}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn codec() {
+        let frame = socketcan::CANFrame::new(0x1234567F, &[0xAA, 0xBB], false, false).unwrap();
+        assert_eq!(encode(&frame), "T1234567F2AABB\r");
+        assert_eq!(decode("T1234567F2AABB\r"), ["T1234567F2AABB\r"]);
+
+        let remote = socketcan::CANFrame::new(0x0002_0000, &[0; 3], true, false).unwrap();
+        assert_eq!(encode(&remote), "R000200003\r");
+        assert_eq!(decode("R000200003\r"), ["R000200003\r"]);
+
+        let short = socketcan::CANFrame::new(0x123, &[1], false, false).unwrap();
+        assert_eq!(encode(&short), "t123101\r");
+        // with a timestamp
+        assert_eq!(decode("t1231011A2B\r"), ["t123101\r"]);
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(decode("\r\x07Z\rV1013\rT1234\r"), ["Ack", "Nack", "Ack"]);
+    }
+
+    /// Opens a pseudo terminal, returns the adapter end and the path of the host end.
+    fn pty() -> (std::fs::File, String) {
+        let (mut master, mut slave) = (0, 0);
+        let mut name = [0 as libc::c_char; 64];
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                name.as_mut_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(res, 0);
+        let path = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+        (
+            unsafe { std::fs::File::from_raw_fd(master) },
+            path.to_str().unwrap().to_string(),
+        )
+    }
+
+    fn read_line(f: &mut std::fs::File) -> String {
+        let mut line = String::new();
+        let mut b = [0_u8];
+        while b[0] != b'\r' {
+            f.read_exact(&mut b).unwrap();
+            line.push(b[0] as char);
+        }
+        line
+    }
+
+    #[tokio::test]
+    async fn fake_adapter() {
+        let (mut adapter, path) = pty();
+        let can = Slcan::open(&path, 500_000, Layout::default())
+            .await
+            .unwrap();
+        let mut rx = can.subscribe();
+
+        let request = Frame::Serial(Type::Remote);
+        can.write_frame(&request, SubId(0)).await.unwrap();
+        let (mut adapter, lines) = tokio::task::spawn_blocking(move || {
+            let lines: Vec<_> = (0..4).map(|_| read_line(&mut adapter)).collect();
+            (adapter, lines)
+        })
+        .await
+        .unwrap();
+        assert_eq!(lines[..3], ["C\r", "S6\r", "O\r"]);
+        assert_eq!(
+            lines[3],
+            encode(&crate::can_bus::to_can_frame(
+                &request,
+                SubId(0),
+                can.layout()
+            ))
+        );
+
+        // a node answers
+        let answer = Frame::Status(Type::Remote);
+        let line = encode(&crate::can_bus::to_can_frame(
+            &answer,
+            SubId(3),
+            can.layout(),
+        ));
+        adapter.write_all(line.as_bytes()).unwrap();
+        assert_eq!(rx.recv().await.unwrap(), (answer, SubId(3)));
+    }
+}
@@ -1,69 +1,118 @@
-use tokio::select;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
-use tokio::time::{sleep, Duration};
-use canbus_common::{
-    frames,
-    frame_id
-};
+use crate::can_bus::{CanTransport, RequestError};
+use canbus_common::upload::UploadError;
+use canbus_common::{frame_id, frames};
+use tokio::time::Duration;
 
 #[derive(Debug)]
 pub enum Error {
     Socket(tokio_socketcan::Error),
     Io(std::io::Error),
-    Other(String),
+    Config(String),
+    WrongSerial,
+    /// The named request failed.
+    Request(&'static str, RequestError),
+    /// The node did not take the dynamic id.
+    DynIdNotSet,
+    /// All 255 dynamic ids are given away.
+    NoFreeDynId,
+    Upload(UploadError),
+    /// The node reports no valid pending image after the upload.
+    BrokenImage,
+    BusClosed,
 }
 
-pub async fn wait_data<T, O: Fn(&canbus_common::frames::Frame) -> Option<T>>(
-    mut socket_rx: Receiver<(frames::Frame, frame_id::SubId)>,
-    comparator: O,
-) -> Option<(T, canbus_common::frame_id::SubId)> {
-    select! {
-        res = async {
-            loop {
-                match socket_rx.recv().await {
-                    Ok(frame) => {
-                        let res = comparator(&frame.0);
-                        if res.is_some() {
-                            return res.map(|o| {(o, frame.1)});
-                        }
-                    },
-                    Err(RecvError::Closed) => {
-                        return None
-                    }
-                    Err(RecvError::Lagged(l)) => {
-                        println!("Lagged {}", l);
-                        return None
-                    }
-                }
-            }
-            None
-        } => res,
-        _timeout = sleep(Duration::from_millis(2000)) => None
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Socket(e) => write!(f, "socket: {:?}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Config(e) => write!(f, "config: {}", e),
+            Error::WrongSerial => write!(f, "wrong serial"),
+            Error::Request(request, e) => write!(f, "{}: {}", request, e),
+            Error::DynIdNotSet => write!(f, "unable to set dyn_id"),
+            Error::NoFreeDynId => write!(f, "no free dyn_id"),
+            Error::Upload(e) => write!(f, "upload failed: {:?}", e),
+            Error::BrokenImage => write!(f, "uploaded image is broken"),
+            Error::BusClosed => write!(f, "CAN bus closed"),
+        }
     }
 }
 
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
         Self::Io(e)
     }
 }
 
-pub async fn get_serial(
-    can_receiver: Receiver<(frames::Frame, frame_id::SubId)>,
-) -> Result<
-    (
-        frames::serial::Serial,
-        frame_id::SubId,
-    ),
-    Error,
-> {
-    wait_data(can_receiver, |frame| match frame {
-        frames::Frame::Serial(canbus_common::frames::Type::Data(value)) => {
-            Some(*value)
+/// How long to wait for an answer and how many times to ask.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RequestOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(2000),
+            retries: 0,
         }
-        _ => None,
-    })
-        .await
-        .ok_or(Error::Other("Request serial".to_string()))
-}
\ No newline at end of file
+    }
+}
+
+/// Gives the node with `serial` the `dyn_id`, returns the `SubId` its answer came with. In the
+/// legacy layout that checks the crc of serial and dyn_id as well, the addressed layout only
+/// carries the dyn_id and takes any node answering with it.
+pub async fn set_dyn_id(
+    can: &impl CanTransport,
+    options: &RequestOptions,
+    serial: frames::serial::Serial,
+    dyn_id: u8,
+) -> Result<frame_id::SubId, Error> {
+    // a lost DynId shows as an answer with the old SubId, both are sent again
+    let once = RequestOptions {
+        retries: 0,
+        ..*options
+    };
+    for _ in 0..=options.retries {
+        can.write_frame(
+            &frames::Frame::DynId(frames::dyn_id::Data::new(serial, dyn_id)),
+            frame_id::SubId(0),
+        )
+            .await?;
+
+        let res = can
+            .request(
+                &frames::Frame::Serial(frames::Type::Remote),
+                frame_id::SubId(0),
+                &once,
+                |frame| match frame {
+                    frames::Frame::Serial(frames::Type::Data(value)) if value == &serial => Some(()),
+                    _ => None,
+                },
+            )
+            .await;
+        match res {
+            Ok((_, sub_id))
+                if sub_id == can.layout().carried(frame_id::SubId::for_node(&serial, dyn_id)) =>
+            {
+                return Ok(sub_id)
+            }
+            Ok(_) | Err(RequestError::Timeout) => {}
+            Err(e) => return Err(Error::Request("serial", e)),
+        }
+    }
+    Err(Error::DynIdNotSet)
+}
+
+/// Makes the node forget the dyn_id it stored, it claims an address of its own again.
+pub async fn clear_dyn_id(
+    can: &impl CanTransport,
+    serial: frames::serial::Serial,
+) -> Result<(), Error> {
+    can.write_frame(&frames::Frame::ClearDynId(serial), frame_id::SubId(0))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,513 @@
+use crate::node::Node;
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::Frame;
+use canbus_common::identifier::Header;
+use helpers::flash::FlashStorage;
+
+/// Nodes sharing one in-memory bus with the host. Frames are delivered right away and
+/// in order, losses come from the node knobs. What a node sends reaches the other nodes as
+/// well, e.g. address claims.
+pub struct Bus<F> {
+    pub nodes: Vec<Node<F>>,
+}
+
+impl<F: FlashStorage> Bus<F> {
+    pub fn new(nodes: Vec<Node<F>>) -> Self {
+        Self { nodes }
+    }
+
+    /// Delivers a frame the host sent with `to` to every node, returns what they answered.
+    pub fn send(&mut self, frame: &Frame, to: SubId) -> Vec<(Frame, SubId)> {
+        let mut sent = Vec::new();
+        for (n, node) in self.nodes.iter_mut().enumerate() {
+            let frames = node.on_frame(frame, to);
+            sent.extend(frames.into_iter().map(|f| (f, node.sub_id(), n)));
+        }
+        self.spread(sent)
+    }
+
+    /// Lets every node do its work up to `now`, in milliseconds.
+    pub fn poll(&mut self, now: u64) -> Vec<(Frame, SubId)> {
+        let mut sent = Vec::new();
+        for (n, node) in self.nodes.iter_mut().enumerate() {
+            let frames = node.poll(now);
+            sent.extend(frames.into_iter().map(|f| (f, node.sub_id(), n)));
+        }
+        self.spread(sent)
+    }
+
+    /// Delivers what node `n` sent to the others, until no node answers any more.
+    fn spread(&mut self, mut sent: Vec<(Frame, SubId, usize)>) -> Vec<(Frame, SubId)> {
+        let mut out = Vec::new();
+        while !sent.is_empty() {
+            let mut answers = Vec::new();
+            for (frame, sub_id, from) in &sent {
+                let to = Header::from_node(frame, *sub_id).to;
+                for (n, node) in self.nodes.iter_mut().enumerate() {
+                    if n != *from {
+                        let frames = node.on_frame(frame, to);
+                        answers.extend(frames.into_iter().map(|f| (f, node.sub_id(), n)));
+                    }
+                }
+            }
+            out.extend(sent.into_iter().map(|(frame, sub_id, _)| (frame, sub_id)));
+            sent = answers;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::SimFlash;
+    use crate::node::{Knobs, FACTORY_VERSION, VECTORS};
+    use crate::transport::SimTransport;
+    use canbus_common::address_claim::CLAIM_TIMEOUT;
+    use canbus_common::frames::boot_info::UpdateResult;
+    use canbus_common::frames::claim::Claim;
+    use canbus_common::frames::error::{Code, Error};
+    use canbus_common::frames::history::Outcome;
+    use canbus_common::frames::serial::{Serial, REPLY_SLOTS, REPLY_SLOT_MS};
+    use canbus_common::frames::status::{Mode, Status};
+    use canbus_common::frames::version::Version;
+    use canbus_common::frames::{dyn_id, Type};
+    use canbus_common::identifier::Layout;
+    use canbus_raspberry::can_bus::{CanTransport, RequestError};
+    use canbus_raspberry::node as host;
+    use canbus_raspberry::util::{self, RequestOptions};
+    use helpers::handoff::MAX_INSTALL_ATTEMPTS;
+    use memory_map::{APP, JOURNAL};
+    use std::time::Duration;
+
+    fn serial(n: u8) -> Serial {
+        Serial([1, 2, 3, 4, n])
+    }
+
+    /// Before they claimed their addresses.
+    fn nodes(count: u8, knobs: Knobs, seed: u64, layout: Layout) -> Bus<SimFlash> {
+        Bus::new(
+            (0..count)
+                .map(|n| {
+                    Node::new(serial(n), SimFlash::in_memory(), knobs, seed + n as u64)
+                        .with_layout(layout)
+                })
+                .collect(),
+        )
+    }
+
+    /// The host side of a bus, with the addresses claimed.
+    async fn transport(count: u8, knobs: Knobs, seed: u64, layout: Layout) -> SimTransport {
+        let can = SimTransport::new(nodes(count, knobs, seed, layout), layout);
+        tokio::time::sleep(Duration::from_millis(CLAIM_TIMEOUT + 1)).await;
+        can
+    }
+
+    /// Until the nodes worked off what they got, e.g. rebooted into a new image.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    fn version() -> Version {
+        Version {
+            major: 1,
+            minor: 4,
+            path: 0,
+            build: 2,
+        }
+    }
+
+    /// An image the bootloader takes, `body` behind the vectors.
+    fn app(body: impl IntoIterator<Item = u8>) -> Vec<u8> {
+        VECTORS.into_iter().chain(body).collect()
+    }
+
+    /// Same format as `stm32/add_header.rs` produces.
+    fn with_header(version: Version, image: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(((8 + image.len()) as u32).to_be_bytes());
+        data.extend(<[u8; 8]>::from(version));
+        data.extend(image);
+        let crc = crc32c_hw::compute(&data);
+        data.extend(crc.to_be_bytes());
+        data
+    }
+
+    /// Asks every node for its serial at `now`, returns the answers of the reply slots.
+    fn serials(bus: &mut Bus<SimFlash>, now: u64) -> Vec<Vec<Serial>> {
+        bus.poll(now);
+        // nobody answers right away
+        assert_eq!(bus.send(&Frame::Serial(Type::Remote), SubId(0)), []);
+        (0..REPLY_SLOTS as u64)
+            .map(|slot| {
+                bus.poll(now + slot * REPLY_SLOT_MS)
+                    .into_iter()
+                    .filter_map(|v| match v.0 {
+                        Frame::Serial(Type::Data(v)) => Some(v),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn installed(node: &Node<SimFlash>, len: usize) -> &[u8] {
+        node.flash().read(APP.offset, len).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serial_and_dyn_id() {
+        let can = transport(2, Knobs::default(), 0, Layout::Legacy).await;
+        let options = RequestOptions::default();
+
+        let found = host::discover(&can, &options).await.unwrap();
+        let found: Vec<Serial> = found.into_iter().map(|v| v.0).collect();
+        assert_eq!(found, [serial(0), serial(1)]);
+
+        let claimed = can.bus().nodes[0].sub_id();
+        let node = host::Node::assign(&can, options, serial(1), 10)
+            .await
+            .unwrap();
+        assert_eq!(can.bus().nodes[0].sub_id(), claimed);
+        assert_eq!(can.bus().nodes[1].sub_id(), node.sub_id());
+        assert_eq!(node.sub_id().split()[1], 10);
+        assert_eq!(node.status().await.unwrap(), Status::new(Mode::Application));
+    }
+
+    /// Many nodes answer a broadcast `Serial` request spread over the reply slots, and
+    /// differently in the next round.
+    #[test]
+    fn serial_reply_slots() {
+        let mut bus = nodes(64, Knobs::default(), 0, Layout::Legacy);
+        bus.poll(0);
+        bus.poll(CLAIM_TIMEOUT);
+        let all: Vec<Serial> = (0..64).map(serial).collect();
+
+        let mut rounds = Vec::new();
+        for round in 0..2 {
+            let now = CLAIM_TIMEOUT + round * REPLY_SLOTS as u64 * REPLY_SLOT_MS;
+            let slots = serials(&mut bus, now);
+            let mut answers = slots.concat();
+            answers.sort_by_key(|v| v.0);
+            assert_eq!(answers, all);
+            assert!(slots.iter().all(|v| v.len() < 12));
+            assert!(slots.iter().filter(|v| !v.is_empty()).count() > REPLY_SLOTS as usize / 2);
+            rounds.push(slots);
+        }
+        assert_ne!(rounds[0], rounds[1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_claim() {
+        let can = transport(3, Knobs::default(), 0, Layout::Legacy).await;
+        let options = RequestOptions::default();
+        let sub_ids: Vec<SubId> = can.bus().nodes.iter().map(|v| v.sub_id()).collect();
+        let addresses: Vec<u8> = sub_ids.iter().map(|v| v.dyn_id()).collect();
+        assert!(addresses.iter().all(|v| *v != 0));
+        assert!(addresses[0] != addresses[1] && addresses[1] != addresses[2]);
+        assert_ne!(addresses[0], addresses[2]);
+
+        let mut claims: Vec<(Claim, SubId)> = (0..3)
+            .map(|n| {
+                (
+                    Claim::new(serial(n), addresses[n as usize]),
+                    sub_ids[n as usize],
+                )
+            })
+            .collect();
+        claims.sort_by_key(|v| v.0.address);
+        assert_eq!(host::claims(&can, &options).await.unwrap(), claims);
+
+        // the host hands the address of node 0 to node 2 as well, node 0 keeps it with the
+        // lower serial and node 2 moves on
+        let taken = addresses[0];
+        let defended = (
+            Frame::AddressClaim(Type::Data(Claim::new(serial(0), taken))),
+            sub_ids[0],
+        );
+        let mut rx = can.subscribe();
+        can.write_frame(&Frame::DynId(dyn_id::Data::new(serial(2), taken)), SubId(0))
+            .await
+            .unwrap();
+        assert!(std::iter::from_fn(|| rx.try_recv().ok()).any(|v| v == defended));
+        tokio::time::sleep(Duration::from_millis(2 * CLAIM_TIMEOUT)).await;
+        assert_eq!(can.bus().nodes[0].sub_id().dyn_id(), taken);
+        let moved = can.bus().nodes[2].sub_id().dyn_id();
+        assert!(moved != 0 && moved != taken && moved != addresses[1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dyn_id_survives_reboots() {
+        let can = transport(1, Knobs::default(), 0, Layout::Legacy).await;
+        let options = RequestOptions::default();
+        let node = host::Node::assign(&can, options, serial(0), 10)
+            .await
+            .unwrap();
+
+        can.bus().nodes[0].reset();
+        assert_eq!(can.bus().nodes[0].sub_id(), node.sub_id());
+
+        // also in the bootloader
+        can.write_frame(&Frame::EnterBootloader, node.sub_id())
+            .await
+            .unwrap();
+        assert_eq!(node.status().await.unwrap(), Status::new(Mode::Bootloader));
+        assert_eq!(can.bus().nodes[0].sub_id(), node.sub_id());
+        can.bus().nodes[0].reset();
+
+        util::clear_dyn_id(&can, serial(0)).await.unwrap();
+        assert_eq!(can.bus().nodes[0].sub_id(), SubId(0));
+        can.bus().nodes[0].reset();
+        assert_eq!(can.bus().nodes[0].sub_id(), SubId(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update() {
+        let image = app((0..3000_u32).map(|v| (v * 7) as u8));
+        let can = transport(1, Knobs::default(), 0, Layout::Legacy).await;
+        let node = host::Node::assign(&can, RequestOptions::default(), serial(0), 1)
+            .await
+            .unwrap();
+        let to = node.sub_id();
+
+        assert_eq!(node.pending_version().await.unwrap(), None);
+        let uploaded = node
+            .upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(uploaded, version());
+
+        let mut rx = can.subscribe();
+        node.activate().await.unwrap();
+        settle().await;
+        // the new app announces itself
+        assert_eq!(
+            std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>(),
+            [
+                (Frame::Serial(Type::Data(serial(0))), to),
+                (
+                    Frame::AddressClaim(Type::Data(Claim::new(serial(0), 1))),
+                    to
+                )
+            ]
+        );
+        {
+            let bus = can.bus();
+            let sim = &bus.nodes[0];
+            assert_eq!(sim.firmware_version(), Some(version()));
+            assert_eq!(installed(sim, image.len()), &image[..]);
+            assert_eq!(sim.handoff().update_result, UpdateResult::Success);
+            assert_eq!(sim.mode(), Mode::Application);
+        }
+        assert_eq!(node.firmware_version().await.unwrap(), version());
+
+        for (n, outcome) in [Outcome::Installed, Outcome::Uploaded]
+            .into_iter()
+            .enumerate()
+        {
+            let record = node.history_record(n as u8).await.unwrap();
+            assert_eq!(record.unwrap().unwrap().outcome, outcome);
+        }
+        assert_eq!(node.pending_version().await.unwrap(), None);
+    }
+
+    /// Only the addressed node takes an upload, commands can't be broadcast.
+    #[tokio::test(start_paused = true)]
+    async fn addressing() {
+        let image = app([0x17; 1200]);
+        let can = transport(2, Knobs::default(), 0, Layout::Legacy).await;
+        let options = RequestOptions::default();
+        let node = host::Node::assign(&can, options, serial(0), 1)
+            .await
+            .unwrap();
+        let other = host::Node::assign(&can, options, serial(1), 2)
+            .await
+            .unwrap();
+
+        node.upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(node.pending_version().await.unwrap(), Some(version()));
+        assert_eq!(other.pending_version().await.unwrap(), None);
+
+        let start = Frame::FirmwareStartUpdate;
+        let refused = can
+            .request_all(&start, SubId(0), options.timeout, |frame| match frame {
+                Frame::NodeError(e) => Some(*e),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        let refused: Vec<Error> = refused.into_iter().map(|v| v.0).collect();
+        assert_eq!(
+            refused,
+            [Error::new(start.id(), SubId(0), Code::Broadcast); 2]
+        );
+        let to = node.sub_id();
+        let mixed_up = SubId::from([to.split()[0] ^ 1, to.dyn_id()]);
+        let res = can
+            .request(&start, mixed_up, &options, |_| None::<()>)
+            .await;
+        assert!(matches!(res, Err(RequestError::Device(Code::WrongSubId))));
+        assert_eq!(can.bus().nodes[0].firmware_version(), Some(FACTORY_VERSION));
+
+        node.activate().await.unwrap();
+        settle().await;
+        assert_eq!(can.bus().nodes[0].firmware_version(), Some(version()));
+        assert_eq!(can.bus().nodes[1].firmware_version(), Some(FACTORY_VERSION));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn addressed_mix_up() {
+        let image = app([0x17; 1200]);
+        let can = transport(2, Knobs::default(), 0, Layout::Addressed).await;
+        let options = RequestOptions::default();
+        let node = host::Node::assign(&can, options, serial(0), 1)
+            .await
+            .unwrap();
+        host::Node::assign(&can, options, serial(1), 2)
+            .await
+            .unwrap();
+
+        node.upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        // the same address, the node can't tell
+        let to = can.bus().nodes[0].sub_id();
+        let mixed_up = SubId::from([to.split()[0] ^ 1, to.dyn_id()]);
+        let start = Frame::FirmwareStartUpdate;
+        let res = can
+            .request(&start, mixed_up, &options, |_| None::<()>)
+            .await;
+        assert!(matches!(res, Err(RequestError::Timeout)));
+        assert_eq!(can.bus().nodes[0].firmware_version(), Some(version()));
+        assert_eq!(can.bus().nodes[1].firmware_version(), Some(FACTORY_VERSION));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn enter_bootloader() {
+        let can = transport(1, Knobs::default(), 0, Layout::Legacy).await;
+        let node = host::Node::assign(&can, RequestOptions::default(), serial(0), 1)
+            .await
+            .unwrap();
+
+        can.write_frame(&Frame::EnterBootloader, node.sub_id())
+            .await
+            .unwrap();
+        assert_eq!(node.status().await.unwrap(), Status::new(Mode::Bootloader));
+
+        // recovery takes an upload as well
+        let image = app([0x42; 1500]);
+        node.upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        node.activate().await.unwrap();
+        settle().await;
+        assert_eq!(node.status().await.unwrap(), Status::new(Mode::Application));
+        assert_eq!(node.firmware_version().await.unwrap(), version());
+    }
+
+    /// An image that doesn't verify is tried a few times, then the node stays in recovery
+    /// and reports it until a new upload.
+    #[tokio::test(start_paused = true)]
+    async fn install_gives_up() {
+        let image = app([0x42; 1500]);
+        let flash = SimFlash::in_memory().with_worn_byte(APP.offset + 700);
+        let bus = Bus::new(vec![Node::new(serial(0), flash, Knobs::default(), 0)]);
+        let can = SimTransport::new(bus, Layout::Legacy);
+        let node = host::Node::assign(&can, RequestOptions::default(), serial(0), 1)
+            .await
+            .unwrap();
+
+        let verify_errors = |node: &Node<SimFlash>| {
+            let journal = node.flash().read(JOURNAL.offset, 1024).unwrap();
+            (0..)
+                .map_while(|n| helpers::history::get(journal, n))
+                .filter(|v| v.record.outcome == Outcome::VerifyError)
+                .count()
+        };
+
+        node.upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        node.activate().await.unwrap();
+        settle().await;
+        {
+            let bus = can.bus();
+            let sim = &bus.nodes[0];
+            assert_eq!(sim.mode(), Mode::Bootloader);
+            assert_eq!(sim.handoff().update_result, UpdateResult::VerifyError);
+            assert_eq!(sim.handoff().install_attempts, MAX_INSTALL_ATTEMPTS);
+            assert_eq!(verify_errors(sim), 1);
+        }
+
+        can.bus().nodes[0].reset();
+        {
+            let bus = can.bus();
+            let sim = &bus.nodes[0];
+            assert_eq!(sim.mode(), Mode::Bootloader);
+            assert_eq!(sim.handoff().update_result, UpdateResult::VerifyError);
+            assert_eq!(verify_errors(sim), 1);
+        }
+
+        // a new upload gets its attempts
+        node.upload(&with_header(version(), &image), |_| {})
+            .await
+            .unwrap();
+        node.activate().await.unwrap();
+        settle().await;
+        assert_eq!(verify_errors(&can.bus().nodes[0]), 2);
+    }
+
+    /// With frames lost and nodes resetting an upload may fail, but whatever gets
+    /// installed must be the uploaded image.
+    #[tokio::test(start_paused = true)]
+    async fn faults_never_install_a_broken_image() {
+        let image = app((0..2000_u32).map(|v| (v * 13) as u8));
+        let file = with_header(version(), &image);
+        let options = RequestOptions {
+            retries: 5,
+            ..Default::default()
+        };
+
+        let knobs = [
+            Knobs {
+                rx_loss: 0.01,
+                ..Default::default()
+            },
+            Knobs {
+                tx_loss: 0.02,
+                ..Default::default()
+            },
+            Knobs {
+                reset_chance: 0.002,
+                ..Default::default()
+            },
+        ];
+
+        let mut installs = 0;
+        for knobs in knobs {
+            for seed in 0..10 {
+                let can = transport(1, knobs, seed, Layout::Legacy).await;
+                let node = match host::Node::assign(&can, options, serial(0), 1).await {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let _ = node.upload(&file, |_| {}).await;
+                if let Ok(Some(_)) = node.pending_version().await {
+                    node.activate().await.unwrap();
+                    settle().await;
+                }
+
+                let bus = can.bus();
+                let sim = &bus.nodes[0];
+                if let Some(v) = sim.firmware_version().filter(|v| *v != FACTORY_VERSION) {
+                    assert_eq!(v, version());
+                    assert_eq!(installed(sim, image.len()), &image[..]);
+                    installs += 1;
+                }
+            }
+        }
+        assert!(installs > 0);
+    }
+}
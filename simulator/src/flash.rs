@@ -0,0 +1,134 @@
+use helpers::flash::FlashStorage;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    OutOfRange,
+    Unaligned,
+    NotErased,
+    Io(std::io::Error),
+}
+
+/// Whole flash of a node, kept in memory and optionally mirrored into a file, so the
+/// pending slot and the journal survive restarts of the simulator.
+pub struct SimFlash {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+    /// Added to every erase and write.
+    delay: Duration,
+    /// A byte that stays erased when written, like a worn out cell.
+    worn: Option<u32>,
+}
+
+impl SimFlash {
+    pub fn in_memory() -> Self {
+        Self {
+            data: vec![0xFF; memory_map::FLASH_SIZE as usize],
+            path: None,
+            delay: Duration::ZERO,
+            worn: None,
+        }
+    }
+
+    /// Loads the file when it exists, otherwise starts erased.
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let mut data = match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        data.resize(memory_map::FLASH_SIZE as usize, 0xFF);
+
+        Ok(Self {
+            data,
+            path: Some(path),
+            delay: Duration::ZERO,
+            worn: None,
+        })
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_worn_byte(mut self, offset: u32) -> Self {
+        self.worn = Some(offset);
+        self
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<std::ops::Range<usize>, Error> {
+        let begin = offset as usize;
+        match begin.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(begin..end),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
+    fn store(&self) -> Result<(), Error> {
+        std::thread::sleep(self.delay);
+        match &self.path {
+            Some(path) => std::fs::write(path, &self.data).map_err(Error::Io),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FlashStorage for SimFlash {
+    type Error = Error;
+
+    fn page_size(&self) -> u32 {
+        memory_map::PAGE_SIZE
+    }
+
+    fn read(&self, offset: u32, len: usize) -> Result<&[u8], Self::Error> {
+        Ok(&self.data[self.range(offset, len)?])
+    }
+
+    fn erase_page(&mut self, offset: u32) -> Result<(), Self::Error> {
+        if offset & (memory_map::PAGE_SIZE - 1) != 0 {
+            return Err(Error::Unaligned);
+        }
+        let range = self.range(offset, memory_map::PAGE_SIZE as usize)?;
+        self.data[range].fill(0xFF);
+        self.store()
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        if offset & 1 != 0 || data.len() & 1 != 0 {
+            return Err(Error::Unaligned);
+        }
+        let range = self.range(offset, data.len())?;
+        if self.data[range.clone()].iter().any(|v| *v != 0xFF) {
+            return Err(Error::NotErased);
+        }
+        self.data[range.clone()].clone_from_slice(data);
+        if let Some(worn) = self.worn.filter(|v| range.contains(&(*v as usize))) {
+            self.data[worn as usize] = 0xFF;
+        }
+        self.store()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("sim-flash-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut f = SimFlash::open(path.clone()).unwrap();
+        assert_eq!(f.read(1024, 2).unwrap(), &[0xFF, 0xFF]);
+        f.write(1024, &[1, 2]).unwrap();
+        assert!(matches!(f.write(1024, &[1, 2]), Err(Error::NotErased)));
+        // by half-words, like the stm32 writer
+        assert!(matches!(f.write(1026, &[3]), Err(Error::Unaligned)));
+
+        let f = SimFlash::open(path.clone()).unwrap();
+        assert_eq!(f.read(1024, 2).unwrap(), &[1, 2]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+//! Emulated nodes speaking the same protocol as the stm32 app, for testing the host side
+//! without hardware.
+
+pub mod bus;
+pub mod flash;
+pub mod node;
+#[cfg(test)]
+mod transport;
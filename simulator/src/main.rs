@@ -0,0 +1,127 @@
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::{Frame, ParserType, RawType};
+use canbus_common::identifier::{Header, Layout};
+use canbus_simulator::bus::Bus;
+use canbus_simulator::flash::SimFlash;
+use canbus_simulator::node::{Knobs, Node};
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Emulates nodes on a SocketCAN interface, e.g. `ip link add dev vcan0 type vcan`.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(long, default_value = "vcan0")]
+    iface: String,
+    #[clap(long, default_value_t = 1)]
+    nodes: u8,
+    /// Keeps the flash of node N in `<dir>/node-N.bin`, in memory otherwise.
+    #[clap(long)]
+    flash_dir: Option<PathBuf>,
+    /// Chance for a node to miss a frame.
+    #[clap(long, default_value_t = 0.0)]
+    rx_loss: f64,
+    /// Chance to lose a frame sent by a node.
+    #[clap(long, default_value_t = 0.0)]
+    tx_loss: f64,
+    /// Chance for a node to reset on a received frame.
+    #[clap(long, default_value_t = 0.0)]
+    reset_chance: f64,
+    /// Added to every flash erase and write.
+    #[clap(long, default_value_t = 0)]
+    flash_delay_ms: u64,
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+    /// Identifiers in the addressed layout, in the legacy `SubId << 13 | frame id` one
+    /// otherwise.
+    #[clap(long)]
+    addressed_ids: bool,
+}
+
+/// The frame with the destination it came with.
+fn from_can_frame(f: &socketcan::CANFrame, layout: Layout) -> Option<(Frame, SubId)> {
+    f.is_extended().then_some(())?;
+    let header = layout.decode(f.id() & socketcan::EFF_MASK)?;
+    let frame = Frame::parse_frame(
+        header.frame_id()?,
+        match f.is_rtr() {
+            false => ParserType::Data(f.data()),
+            true => ParserType::Remote(f.data().len() as u8),
+        },
+    )
+    .ok()?;
+    Some((frame, header.to))
+}
+
+/// The frame as the node with `sub_id` sends it.
+fn to_can_frame(frame: &Frame, sub_id: SubId, layout: Layout) -> socketcan::CANFrame {
+    let raw = frame.raw_frame();
+    let raw_id = layout.encode(&Header::from_node(frame, sub_id));
+    match raw.1 {
+        RawType::Data(v) => socketcan::CANFrame::new(raw_id, v.as_slice(), false, false),
+        RawType::Remote(len) => {
+            socketcan::CANFrame::new(raw_id, &vec![0; len as usize], true, false)
+        }
+    }
+    .unwrap()
+}
+
+fn main() {
+    let args = Args::parse();
+    let layout = match args.addressed_ids {
+        true => Layout::Addressed,
+        false => Layout::Legacy,
+    };
+    let knobs = Knobs {
+        rx_loss: args.rx_loss,
+        tx_loss: args.tx_loss,
+        reset_chance: args.reset_chance,
+    };
+
+    let nodes = (0..args.nodes)
+        .map(|n| {
+            let mut serial = helpers::DEVICE_SERIAL;
+            serial.0[4] = serial.0[4].wrapping_add(n);
+
+            let flash = match &args.flash_dir {
+                Some(dir) => SimFlash::open(dir.join(format!("node-{}.bin", n))).unwrap(),
+                None => SimFlash::in_memory(),
+            };
+            let flash = flash.with_delay(Duration::from_millis(args.flash_delay_ms));
+
+            println!("node {} serial {:?}", n, serial);
+            Node::new(serial, flash, knobs, args.seed.wrapping_add(n as u64)).with_layout(layout)
+        })
+        .collect();
+    let mut bus = Bus::new(nodes);
+
+    let socket = socketcan::CANSocket::open(&args.iface).unwrap();
+    socket.set_read_timeout(Duration::from_millis(1)).unwrap();
+
+    let start = Instant::now();
+    loop {
+        let mut out = match socket.read_frame() {
+            Ok(f) => match from_can_frame(&f, layout) {
+                Some((frame, to)) => bus.send(&frame, to),
+                None => Vec::new(),
+            },
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Vec::new()
+            }
+            Err(e) => panic!("{}", e),
+        };
+        out.extend(bus.poll(start.elapsed().as_millis() as u64));
+
+        for (frame, sub_id) in out {
+            if let Err(e) = socket.write_frame(&to_can_frame(&frame, sub_id, layout)) {
+                eprintln!("write {:?}: {}", frame, e);
+            }
+        }
+    }
+}
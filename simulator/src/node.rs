@@ -0,0 +1,374 @@
+use canbus_common::address_claim::AddressClaimer;
+use canbus_common::addressing::{self, Verdict};
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::boot_info::BootReason;
+use canbus_common::frames::history::{Outcome, Record, RecordPart};
+use canbus_common::frames::serial::Serial;
+use canbus_common::frames::status::{Mode, Status};
+use canbus_common::frames::version::Version;
+use canbus_common::frames::{Frame, Type};
+use canbus_common::identifier::Layout;
+use helpers::app_image::{self, AppInfo};
+use helpers::boot::Decision;
+use helpers::config::Config;
+use helpers::flash::FlashStorage;
+use helpers::handoff::Handoff;
+use helpers::update_receiver::{Action, UpdateReceiver};
+use memory_map::{APP, APP_INFO, CONFIG, JOURNAL, PENDING, RAM_BASE, RAM_SIZE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub const BOOTLOADER_VERSION: Version = Version {
+    major: 0,
+    minor: 1,
+    path: 0,
+    build: 0,
+};
+
+/// The app a blank node comes with, as boards leave production with one.
+pub const FACTORY_VERSION: Version = Version {
+    major: 1,
+    minor: 0,
+    path: 0,
+    build: 0,
+};
+
+/// Start of every image the nodes run, the bootloader checks it before the jump: the stack
+/// pointer at the end of RAM and the reset handler in the app slot.
+pub const VECTORS: [u8; 8] = {
+    let sp = (RAM_BASE + RAM_SIZE).to_le_bytes();
+    let reset = (APP.address() + 0x101).to_le_bytes();
+    [
+        sp[0], sp[1], sp[2], sp[3], reset[0], reset[1], reset[2], reset[3],
+    ]
+};
+
+/// Faults injected into a node.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Knobs {
+    /// Chance to miss a frame.
+    pub rx_loss: f64,
+    /// Chance to lose a frame the node sends.
+    pub tx_loss: f64,
+    /// Chance to reset on a received frame.
+    pub reset_chance: f64,
+}
+
+/// One emulated node: the app, or the bootloader recovery mode, booted by the bootloader's
+/// `helpers::boot`. Images are not run, an app with valid vectors always comes up.
+/// Time is in milliseconds, as `poll` passes it.
+pub struct Node<F> {
+    serial: Serial,
+    flash: F,
+    knobs: Knobs,
+    rng: StdRng,
+    mode: Mode,
+    sub_id: SubId,
+    layout: Layout,
+    update: UpdateReceiver,
+    handoff: Handoff,
+    claim: AddressClaimer,
+    now: u64,
+    /// Sent with the next `poll`.
+    outbox: Vec<Frame>,
+    /// `Serial` requests so far, the round of the reply slot.
+    serial_requests: u8,
+    /// When the answer to a `Serial` request is due.
+    serial_reply: Option<u64>,
+}
+
+impl<F: FlashStorage> Node<F> {
+    pub fn new(serial: Serial, flash: F, knobs: Knobs, seed: u64) -> Self {
+        let mut node = Self {
+            serial,
+            flash,
+            knobs,
+            rng: StdRng::seed_from_u64(seed),
+            mode: Mode::Application,
+            sub_id: SubId(0),
+            layout: Layout::Legacy,
+            update: UpdateReceiver::new(PENDING.offset..PENDING.end()),
+            handoff: Handoff::default(),
+            claim: AddressClaimer::new(serial, AddressClaimer::preferred_address(&serial)),
+            now: 0,
+            outbox: Vec::new(),
+            serial_requests: 0,
+            serial_reply: None,
+        };
+        node.flash_factory_image();
+        node.boot(BootReason::PowerOn);
+        node
+    }
+
+    fn flash_factory_image(&mut self) {
+        let erased = self
+            .flash
+            .read(APP.offset, VECTORS.len())
+            .is_ok_and(|v| v.iter().all(|b| *b == 0xFF));
+        if erased {
+            let info = AppInfo::new(FACTORY_VERSION, &VECTORS);
+            let _ = self.flash.write(APP.offset, &VECTORS);
+            let _ = self
+                .flash
+                .write(APP_INFO.offset, &<[u8; AppInfo::SIZE]>::from(info));
+        }
+    }
+
+    /// The layout of the identifier the frames come with, whole `SubId`s as in the legacy
+    /// layout by default.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    #[inline]
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    #[inline]
+    pub fn sub_id(&self) -> SubId {
+        self.sub_id
+    }
+
+    #[inline]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    #[inline]
+    pub fn handoff(&self) -> Handoff {
+        self.handoff
+    }
+
+    /// Version of the installed image, as the bootloader recorded it.
+    pub fn firmware_version(&self) -> Option<Version> {
+        app_image::info(&self.flash, APP_INFO.offset).map(|v| v.version)
+    }
+
+    /// Software reset: RAM state is lost, flash and the handoff record are kept.
+    pub fn reset(&mut self) {
+        self.boot(BootReason::Software);
+    }
+
+    /// What the bootloader does before the app starts, the app comes up right away.
+    fn boot(&mut self, boot_reason: BootReason) {
+        // the record doesn't survive a power loss
+        let previous = (boot_reason != BootReason::PowerOn).then_some(self.handoff);
+        let boot = helpers::boot::boot(
+            &mut self.flash,
+            previous,
+            boot_reason,
+            BOOTLOADER_VERSION,
+            false,
+        );
+        self.handoff = boot.handoff;
+        self.mode = match boot.decision {
+            // the bootloader resets and tries again
+            Decision::Reset => return self.boot(BootReason::Software),
+            Decision::Recovery(_) => Mode::Bootloader,
+            Decision::Jump => {
+                // as at the app's idle start
+                self.handoff.boot_attempts = 0;
+                Mode::Application
+            }
+        };
+
+        // the dyn_id the app stored, the bootloader restores it as well
+        let config = helpers::config::read(&self.flash, CONFIG.offset);
+        self.update = UpdateReceiver::new(PENDING.offset..PENDING.end());
+        self.outbox.clear();
+        self.serial_requests = 0;
+        self.serial_reply = None;
+        if self.mode == Mode::Bootloader {
+            self.sub_id = config
+                .dyn_id
+                .map_or(SubId(0), |v| SubId::for_node(&self.serial, v));
+            return;
+        }
+
+        // the app claims an address unless the host gave it one
+        self.claim =
+            AddressClaimer::new(self.serial, AddressClaimer::preferred_address(&self.serial));
+        let claim = match config.dyn_id {
+            Some(dyn_id) => self.claim.assign(dyn_id),
+            None => self.claim.start(self.now),
+        };
+        self.sub_id = self.claimed();
+        self.outbox = vec![Frame::Serial(Type::Data(self.serial)), claim];
+    }
+
+    /// The `SubId` of the claimed address, `SubId(0)` while claiming.
+    fn claimed(&self) -> SubId {
+        self.claim.sub_id().unwrap_or(SubId(0))
+    }
+
+    fn store(&mut self, config: Config) {
+        if helpers::config::read(&self.flash, CONFIG.offset) != config {
+            let _ = helpers::config::write(&mut self.flash, CONFIG.offset, config);
+        }
+    }
+
+    fn log(&mut self, record: Record) {
+        let _ = helpers::history::log(&mut self.flash, JOURNAL.offset, record);
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.gen_bool(p)
+    }
+
+    fn transmit(&mut self, frames: Vec<Frame>) -> Vec<Frame> {
+        let loss = self.knobs.tx_loss;
+        frames.into_iter().filter(|_| !self.chance(loss)).collect()
+    }
+
+    /// Frames the node answers `frame` with, that came with the `SubId` `to`.
+    pub fn on_frame(&mut self, frame: &Frame, to: SubId) -> Vec<Frame> {
+        if self.chance(self.knobs.rx_loss) {
+            return Vec::new();
+        }
+        if self.chance(self.knobs.reset_chance) {
+            self.reset();
+            return Vec::new();
+        }
+        match addressing::check(frame, to, self.layout.carried(self.sub_id)) {
+            Verdict::Act => {}
+            Verdict::Ignore => return Vec::new(),
+            Verdict::Reject(e) => return self.transmit(vec![Frame::NodeError(e)]),
+        }
+
+        let app = self.mode == Mode::Application;
+        let mut out = Vec::new();
+        match frame {
+            // many nodes answer at once, each waits for its own slot, an answer already due
+            // serves this request too
+            Frame::Serial(Type::Remote) if app => {
+                let round = self.serial_requests;
+                self.serial_requests = round.wrapping_add(1);
+                let due = self.now + self.serial.reply_delay_ms(round);
+                self.serial_reply.get_or_insert(due);
+            }
+            Frame::Serial(Type::Remote) => out.push(Frame::Serial(Type::Data(self.serial))),
+            Frame::Status(Type::Remote) => {
+                out.push(Frame::Status(Type::Data(Status::new(self.mode))));
+            }
+            Frame::DynId(value) if value.serial == self.serial => match app {
+                // the host overrides the claimed address
+                true => {
+                    out.push(self.claim.assign(value.dyn_id));
+                    self.sub_id = self.claimed();
+                    self.store(Config {
+                        dyn_id: Some(value.dyn_id),
+                    });
+                }
+                false => self.sub_id = SubId::for_node(&value.serial, value.dyn_id),
+            },
+            Frame::ClearDynId(serial) if app && *serial == self.serial => {
+                self.claim =
+                    AddressClaimer::new(*serial, AddressClaimer::preferred_address(serial));
+                out.push(self.claim.start(self.now));
+                self.sub_id = self.claimed();
+                self.store(Config::default());
+            }
+            Frame::AddressClaim(_) if app => {
+                out.extend(self.claim.on_frame(frame, self.now));
+                self.sub_id = self.claimed();
+            }
+            Frame::BootInfo(Type::Remote) if app => {
+                out.push(Frame::BootInfo(Type::Data(self.handoff.boot_info())));
+            }
+            Frame::FirmwareVersion(Type::Remote) if app => {
+                out.extend(
+                    self.firmware_version()
+                        .map(|v| Frame::FirmwareVersion(Type::Data(v))),
+                );
+            }
+            Frame::HardwareVersion(Type::Remote) if app => {
+                out.push(Frame::HardwareVersion(Type::Data(
+                    helpers::HARDWARE_VERSION,
+                )));
+            }
+            Frame::BootloaderVersion(Type::Remote) if app => {
+                out.push(Frame::BootloaderVersion(Type::Data(
+                    self.handoff.bootloader_version,
+                )));
+            }
+            Frame::UpdateHistoryRequest(index) if app => {
+                let entry = self
+                    .flash
+                    .read(JOURNAL.offset, JOURNAL.size as usize)
+                    .ok()
+                    .and_then(|page| helpers::history::get(page, *index as usize));
+                match entry {
+                    Some(entry) => {
+                        out.extend(entry.record.parts(*index).map(Frame::UpdateHistoryRecord))
+                    }
+                    None => out.push(Frame::UpdateHistoryRecord(RecordPart::Empty {
+                        index: *index,
+                    })),
+                }
+            }
+            Frame::EnterBootloader if app => {
+                self.handoff.enter_bootloader = true;
+                self.reset();
+            }
+            frame => {
+                for action in self.update.on_frame(frame) {
+                    if let Action::Send(frame) = action {
+                        out.push(frame);
+                    }
+                }
+            }
+        }
+
+        self.transmit(out)
+    }
+
+    /// Flash work left from `on_frame`, the address claim and answers to `Serial` requests
+    /// once their reply slot comes, like the app's idle loop and tasks. The clock never goes
+    /// back, an earlier `now` is taken as the last one.
+    pub fn poll(&mut self, now: u64) -> Vec<Frame> {
+        self.now = self.now.max(now);
+        let mut out = std::mem::take(&mut self.outbox);
+        if self.mode == Mode::Application {
+            self.claim.poll(self.now);
+            self.sub_id = self.claimed();
+        }
+        if self.serial_reply.is_some_and(|v| v <= self.now) {
+            self.serial_reply = None;
+            out.push(Frame::Serial(Type::Data(self.serial)));
+        }
+        for action in self.update.poll(&mut self.flash) {
+            match action {
+                Action::Send(frame) => out.push(frame),
+                Action::Finished(to) if self.mode == Mode::Application => {
+                    self.log(Record {
+                        boot_count: self.handoff.boot_count,
+                        from: self.firmware_version().unwrap_or_default(),
+                        to: to.unwrap_or_default(),
+                        outcome: match to {
+                            Some(_) => Outcome::Uploaded,
+                            None => Outcome::UploadFailed,
+                        },
+                    });
+                }
+                Action::Reboot => {
+                    if self.mode == Mode::Bootloader {
+                        // recovery lets the bootloader try a new image again
+                        self.handoff.install_attempts = 0;
+                    }
+                    self.reset();
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        self.transmit(out)
+    }
+}
@@ -0,0 +1,94 @@
+//! The host tools talking to the simulated nodes, through the same `CanTransport` they use
+//! on a real bus.
+
+use crate::bus::Bus;
+use crate::flash::SimFlash;
+use canbus_common::frame_id::SubId;
+use canbus_common::frames::{Frame, RawType};
+use canbus_common::identifier::{Header, Layout};
+use canbus_raspberry::can_bus::{from_can_frame, CanTransport, Channels};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Runs the nodes on the tokio clock, a millisecond apart, so tests with a paused clock
+/// take no time.
+pub struct SimTransport {
+    bus: Arc<Mutex<Bus<SimFlash>>>,
+    channels: Channels,
+    handler: JoinHandle<()>,
+}
+
+impl SimTransport {
+    pub fn new(bus: Bus<SimFlash>, layout: Layout) -> Self {
+        let bus = Arc::new(Mutex::new(bus));
+        let channels = Channels::new(layout);
+        let handler = tokio::spawn(Self::polling(bus.clone(), channels.clone()));
+        Self {
+            bus,
+            channels,
+            handler,
+        }
+    }
+
+    /// The nodes, for looking into them or resetting one.
+    pub fn bus(&self) -> MutexGuard<'_, Bus<SimFlash>> {
+        self.bus.lock().unwrap()
+    }
+
+    async fn polling(bus: Arc<Mutex<Bus<SimFlash>>>, channels: Channels) {
+        let start = Instant::now();
+        let mut interval = tokio::time::interval(Duration::from_millis(1));
+        loop {
+            interval.tick().await;
+            let now = start.elapsed().as_millis() as u64;
+            let sent = bus.lock().unwrap().poll(now);
+            publish(&channels, sent);
+        }
+    }
+}
+
+/// What the nodes sent, as it shows on the bus.
+fn publish(channels: &Channels, sent: Vec<(Frame, SubId)>) {
+    for (frame, sub_id) in sent {
+        let raw_id = channels.layout().encode(&Header::from_node(&frame, sub_id));
+        let frame = match frame.raw_frame().1 {
+            RawType::Data(v) => socketcan::CANFrame::new(raw_id, v.as_slice(), false, false),
+            RawType::Remote(len) => {
+                socketcan::CANFrame::new(raw_id, &vec![0; len as usize], true, false)
+            }
+        };
+        channels.publish(frame.unwrap());
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+impl CanTransport for SimTransport {
+    fn layout(&self) -> Layout {
+        self.channels.layout()
+    }
+
+    fn subscribe(&self) -> Receiver<(Frame, SubId)> {
+        self.channels.subscribe()
+    }
+
+    fn subscribe_raw(&self) -> Receiver<socketcan::CANFrame> {
+        self.channels.subscribe_raw()
+    }
+
+    async fn write_raw(&self, frame: socketcan::CANFrame) -> std::io::Result<()> {
+        // the nodes take it right away, what they answer is on the bus before this returns
+        if let Ok((frame, to)) = from_can_frame(&frame, self.layout()) {
+            let sent = self.bus().send(&frame, to);
+            publish(&self.channels, sent);
+        }
+        Ok(())
+    }
+}
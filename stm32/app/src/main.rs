@@ -23,30 +23,26 @@ use stm32f1xx_hal::{
 };
 use systick_monotonic::Systick;
 
-pub const DEVICE_SERIAL: canbus_common::frames::serial::Serial =
-    canbus_common::frames::serial::Serial([1, 2, 3, 4, 5]);
-pub const PAGE_SIZE: usize = 1024;
-pub const NEW_FW_BEGIN: usize = (20 + 53) * 1024;
+pub const DEVICE_SERIAL: canbus_common::frames::serial::Serial = helpers::DEVICE_SERIAL;
+pub const PAGE_SIZE: usize = memory_map::PAGE_SIZE as usize;
+// offsets from the start of flash as the flash writer takes them, not addresses
+pub const FW_INFO: usize = memory_map::APP_INFO.offset as usize;
+pub const JOURNAL: usize = memory_map::JOURNAL.offset as usize;
+pub const CONFIG: usize = memory_map::CONFIG.offset as usize;
+/// The frames of the product next to the core ones, handled by `util::app::on_frame`.
+pub type AppFrame = canbus_common::frames::app::NoApp;
 
 #[app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [SPI1, SPI2])]
 mod app {
     use super::*;
-    use bxcan::Fifo;
     
     use canbus_common::frames::Type;
+    use helpers::update_receiver::Action;
     
     use stm32f1xx_hal::gpio;
     use stm32f1xx_hal::gpio::Floating;
     use stm32f1xx_hal::pac::USART1;
 
-    #[derive(Default)]
-    pub struct FwUpload {
-        pub data: helpers::firmware_update::FirmwareUpdate<PAGE_SIZE, 5, { PAGE_SIZE + 5 }>,
-        pub paused: bool,
-        pub finished: bool,
-        pub has_pending_fw: bool,
-    }
-
     #[shared]
     struct Shared {
         led: PA2<Output<PushPull>>,
@@ -59,21 +55,28 @@ mod app {
             ),
         >,
 
+        /// Not split into tx and rx, the filters follow `dyn_id`.
+        can: bxcan::Can<Can<CAN1>>,
+        /// Follows `claim`, `SubId(0)` until the address is claimed. Set with
+        /// `util::can::set_sub_id`.
         dyn_id: canbus_common::frame_id::SubId,
+        claim: canbus_common::address_claim::AddressClaimer,
+        /// Settings for idle to write into flash.
+        config_write: Option<helpers::config::Config>,
 
         can_tx_queue: heapless::binary_heap::BinaryHeap<util::can::PriorityFrame, heapless::binary_heap::Max, 16>,
         tx_count: usize,
 
-        fw_upload: FwUpload,
-        pending_fw_version_required: bool,
+        update: helpers::update_receiver::UpdateReceiver,
     }
 
     #[local]
     struct Local {
-        can_tx: bxcan::Tx<Can<CAN1>>,
-        can_rx: bxcan::Rx0<Can<CAN1>>,
-
         flash: stm32f1xx_hal::flash::Parts,
+        /// Of the running image, it only changes with a reboot.
+        fw_version: Option<canbus_common::frames::version::Version>,
+        /// `Serial` requests received so far, picks the reply slot.
+        serial_requests: u8,
         //flash_writer: stm32f1xx_hal::flash::FlashWriter<'static>,
     }
 
@@ -102,6 +105,9 @@ mod app {
             .pclk2(72.MHz())
             .freeze(&mut flash.acr);
 
+        let fw_version = helpers::app_image::info(&helpers::flash::writer(&mut flash), FW_INFO as u32)
+            .map(|v| v.version);
+
         let mut afio = ctx.device.AFIO.constrain();
 
         let mono = Systick::new(ctx.core.SYST, clocks.sysclk().raw());
@@ -123,6 +129,7 @@ mod app {
             &clocks,
         );
         write!(serial, "stadte: {:?}\r\n", 1).unwrap();
+        write!(serial, "handoff: {:?}\r\n", helpers::handoff::read()).unwrap();
         // Schedule the blinking task
         //blink::spawn_after(Duration::<u64, 1, 1000>::from_ticks(1000)).unwrap();
         //pollbq::spawn_after(Duration::<u64, 1, 1000>::from_ticks(1000)).unwrap();
@@ -143,15 +150,8 @@ mod app {
             .set_bit_timing(0x001c0011)
             .leave_disabled();
 
-        can.modify_filters()
-            .enable_bank(0, Fifo::Fifo0, bxcan::filter::Mask32::accept_all());
-        /*.enable_bank(
-            0,
-            Mask32::frames_with_ext_id(
-                ExtendedId::new(2).unwrap(),
-                ExtendedId::new(0xFF).unwrap(),
-            ),
-        );*/
+        // no SubId yet, idle sets the filters again once the address is claimed
+        util::can::set_filters(&mut can, canbus_common::frame_id::SubId(0));
 
         // Sync to the bus and start normal operation.
         can.enable_interrupts(
@@ -159,8 +159,6 @@ mod app {
         );
         nb::block!(can.enable_non_blocking()).unwrap();
 
-        let (can_tx, can_rx, _) = can.split();
-
         let can_tx_queue = heapless::binary_heap::BinaryHeap::new();
 
         //rtic::pend(Interrupt::USB_HP_CAN_TX);
@@ -171,129 +169,156 @@ mod app {
                 led,
                 led2,
                 serial,
+                can,
                 dyn_id: canbus_common::frame_id::SubId(0),
+                claim: canbus_common::address_claim::AddressClaimer::new(
+                    DEVICE_SERIAL,
+                    canbus_common::address_claim::AddressClaimer::preferred_address(&DEVICE_SERIAL),
+                ),
+                config_write: None,
                 can_tx_queue,
                 tx_count: 0,
-                fw_upload: Default::default(),
-                pending_fw_version_required: false,
+                update: helpers::update_receiver::UpdateReceiver::new(
+                    memory_map::PENDING.offset..memory_map::PENDING.end(),
+                ),
             },
             Local {
-                can_tx,
-                can_rx,
                 flash,
+                fw_version,
+                serial_requests: 0,
             },
             init::Monotonics(mono),
         )
     }
 
-    #[idle(shared = [can_tx_queue, fw_upload, pending_fw_version_required, serial], local = [flash])]
+    #[idle(shared = [can_tx_queue, update, serial, claim, can, dyn_id, config_write], local = [flash])]
     fn idle(mut cx: idle::Context) -> ! {
+        // up and running, so the bootloader doesn't count this boot as a failed one
+        if let Some(mut handoff) = helpers::handoff::read() {
+            handoff.boot_attempts = 0;
+            helpers::handoff::write(handoff);
+        }
+
+        // a dyn_id from the host survives reboots, otherwise the address is claimed
+        let (claim, own) = cx.shared.claim.lock(|claim| {
+            let frame = match util::config::read().dyn_id {
+                Some(dyn_id) => claim.assign(dyn_id),
+                None => claim.start(monotonics::now().ticks()),
+            };
+            (frame, claim.sub_id().unwrap_or(canbus_common::frame_id::SubId(0)))
+        });
+        util::can::set_sub_id(&mut cx.shared.dyn_id, &mut cx.shared.can, own);
         cx.shared.can_tx_queue.lock(|can_tx_queue| {
             util::can::enqueue_frame(
                 can_tx_queue,
-                util::can::PriorityFrame(canbus_common::frames::Frame::Serial(Type::Data(
-                    DEVICE_SERIAL,
-                ))),
+                util::can::PriorityFrame::new(
+                    canbus_common::frames::Frame::Serial(Type::Data(
+                        DEVICE_SERIAL,
+                    )),
+                    own,
+                ),
             );
+            util::can::enqueue_frame(can_tx_queue, util::can::PriorityFrame::new(claim, own));
         });
 
         loop {
-            cx.shared.fw_upload.lock(|fw_upload: &mut FwUpload| {
-                if let Some(page) = fw_upload.data.get_page() {
-                    let page_p = (NEW_FW_BEGIN + (PAGE_SIZE * page.1)) as u32;
-                    //hprintln!("page {:?} {:?}", page.1, page_p);
-
-                    let mut writer: stm32f1xx_hal::flash::FlashWriter = cx.local.flash.writer(
-                        stm32f1xx_hal::flash::SectorSize::Sz1K,
-                        stm32f1xx_hal::flash::FlashSize::Sz128K,
-                    );
-                    //writer.change_verification(false);
-                    //let r = writer.erase(page_p, PAGE_SIZE);
-                    if let Err(e) = writer.page_erase(page_p) {
-                        cx.shared.serial.lock(|serial| {
-                            write!(serial, "erase {:?}\r\n", e).unwrap();
-                        });
-                    }
-
-                    if let Err(e) = writer.write(page_p, page.0) {
-                        cx.shared.serial.lock(|serial| {
-                            write!(serial, "write {:?}\r\n", e).unwrap();
-                        });
-                    }
-
-                    //hprintln!("read {:?} ", writer.read(page_p, 4).unwrap());
-
-                    fw_upload.data.remove_page();
-                    //hprintln!("removed_page {}", fw_upload.data.len());
-
-                    fw_upload.has_pending_fw = false;
-                }
+            let sub_id = cx.shared.claim.lock(|claim| {
+                claim.poll(monotonics::now().ticks());
+                claim.sub_id()
+            });
+            util::can::set_sub_id(
+                &mut cx.shared.dyn_id,
+                &mut cx.shared.can,
+                sub_id.unwrap_or(canbus_common::frame_id::SubId(0)),
+            );
 
-                if fw_upload.finished {
-                    fw_upload.finished = false;
-                    fw_upload.data.reset();
-                    //hprintln!("finished");
+            let flash = &mut *cx.local.flash;
+            if let Some(config) = cx.shared.config_write.lock(|v| v.take()) {
+                if let Err(e) = util::config::write(flash, config) {
                     cx.shared.serial.lock(|serial| {
-                        write!(serial, "Finished\r\n").unwrap();
+                        write!(serial, "config {:?}\r\n", e).unwrap();
                     });
                 }
+            }
+            let actions = cx
+                .shared
+                .update
+                .lock(|update| update.poll(&mut helpers::flash::writer(flash)));
+            let own = cx.shared.dyn_id.lock(|v| *v);
 
-                if fw_upload.paused {
-                    fw_upload.paused = false;
-
-                    cx.shared.can_tx_queue.lock(|can_tx_queue| {
+            for action in actions {
+                match action {
+                    Action::Send(frame) => cx.shared.can_tx_queue.lock(|can_tx_queue| {
                         util::can::enqueue_frame(
                             can_tx_queue,
-                            util::can::PriorityFrame(canbus_common::frames::Frame::FirmwareUploadPause(
-                                fw_upload.paused,
-                            )),
+                            util::can::PriorityFrame::new(frame, own),
                         );
-                    });
-                }
-            });
+                    }),
+                    Action::Finished(to) => {
+                        cx.shared.serial.lock(|serial| {
+                            write!(serial, "Finished\r\n").unwrap();
+                        });
 
-            if cx
-                .shared
-                .pending_fw_version_required
-                .lock(|pending_fw_version_required| *pending_fw_version_required)
-            {
-                let begin = NEW_FW_BEGIN as u32;
-
-                let pf = helpers::pending_fw::get(begin);
-
-                cx.shared.fw_upload.lock(|fw_upload: &mut FwUpload| {
-                    fw_upload.has_pending_fw = pf.is_some();
-                });
-
-                cx.shared.can_tx_queue.lock(|can_tx_queue| {
-                    util::can::enqueue_frame(
-                        can_tx_queue,
-                        util::can::PriorityFrame(canbus_common::frames::Frame::PendingFirmwareVersion(
-                            Type::Data(pf.map(|v| v.0)),
-                        )),
-                    );
-                });
-
-                //hprintln!("crc {} {}", crc, hasher.finalize());
-
-                cx.shared
-                    .pending_fw_version_required
-                    .lock(|pending_fw_version_required| {
-                        *pending_fw_version_required = false;
-                    })
+                        let from = helpers::app_image::info(&helpers::flash::writer(flash), FW_INFO as u32)
+                            .map(|v| v.version);
+                        let record = canbus_common::frames::history::Record {
+                            boot_count: helpers::handoff::read().map_or(0, |v| v.boot_count),
+                            from: from.unwrap_or_default(),
+                            to: to.unwrap_or_default(),
+                            outcome: match to {
+                                Some(_) => canbus_common::frames::history::Outcome::Uploaded,
+                                None => canbus_common::frames::history::Outcome::UploadFailed,
+                            },
+                        };
+                        if let Err(e) = util::history::log(flash, record) {
+                            cx.shared.serial.lock(|serial| {
+                                write!(serial, "history {:?}\r\n", e).unwrap();
+                            });
+                        }
+                    }
+                    Action::PageWriteFailed(n) => cx.shared.serial.lock(|serial| {
+                        write!(serial, "page {} write failed\r\n", n).unwrap();
+                    }),
+                    Action::NoPendingFirmware => cx.shared.serial.lock(|serial| {
+                        write!(serial, "Has no pending fw !!!\r\n").unwrap();
+                    }),
+                    Action::Reboot => {
+                        cx.shared.serial.lock(|serial| {
+                            write!(serial, "Reboot to upgrade...\r\n").unwrap();
+                        });
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                }
             }
         }
     }
 
+    /// Answers a `Serial` request once the reply slot of the node comes.
+    #[task(shared = [can_tx_queue, dyn_id])]
+    fn send_serial(mut cx: send_serial::Context) {
+        let own = cx.shared.dyn_id.lock(|v| *v);
+        cx.shared.can_tx_queue.lock(|can_tx_queue| {
+            util::can::enqueue_frame(
+                can_tx_queue,
+                util::can::PriorityFrame::new(
+                    canbus_common::frames::Frame::Serial(Type::Data(
+                        DEVICE_SERIAL,
+                    )),
+                    own,
+                ),
+            );
+        });
+    }
+
     use crate::util::can::can_tx;
     extern "Rust" {
-        #[task(binds = USB_HP_CAN_TX, local = [can_tx], shared = [can_tx_queue, tx_count, led2, dyn_id, serial])]
+        #[task(binds = USB_HP_CAN_TX, shared = [can, can_tx_queue, tx_count, led2, serial])]
         fn can_tx(mut cx: can_tx::Context);
     }
 
     use crate::util::can::can_rx0;
     extern "Rust" {
-        #[task(binds = USB_LP_CAN_RX0, local = [can_rx], shared = [can_tx_queue, led2, dyn_id, fw_upload, pending_fw_version_required, serial])]
+        #[task(binds = USB_LP_CAN_RX0, local = [fw_version, serial_requests], shared = [can, can_tx_queue, led2, dyn_id, claim, config_write, update, serial])]
         fn can_rx0(mut cx: can_rx0::Context);
     }
 }
@@ -1 +1,4 @@
-pub mod can;
\ No newline at end of file
+pub mod app;
+pub mod can;
+pub mod config;
+pub mod history;
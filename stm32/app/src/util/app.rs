@@ -0,0 +1,7 @@
+use canbus_common::frames::app::AnyFrame;
+
+/// Handles a frame of the product that passed `canbus_common::addressing::check`, the answer
+/// goes out with the node's `SubId`.
+pub fn on_frame(frame: crate::AppFrame) -> Option<AnyFrame<crate::AppFrame>> {
+    match frame {}
+}
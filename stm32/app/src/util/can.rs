@@ -1,25 +1,41 @@
-use stm32f1xx_hal::pac::Interrupt;
+use stm32f1xx_hal::can::Can;
+use stm32f1xx_hal::pac::{Interrupt, CAN1};
 use core::cmp::Ordering;
-use num_traits::ToPrimitive;
 use heapless::binary_heap;
 use canbus_common::{
     frames,
     frame_id
 };
-use helpers::firmware_update;
+use canbus_common::frames::app::AnyFrame;
+use canbus_common::identifier::Header;
 use rtic::mutex_prelude::*;
 use crate::app::{can_rx0, can_tx};
 use core::fmt::Write;
+use systick_monotonic::ExtU64;
 
 #[derive(Debug)]
-pub struct PriorityFrame(pub frames::Frame);
+pub struct PriorityFrame {
+    pub frame: AnyFrame<crate::AppFrame>,
+    /// The identifier it goes out with.
+    id: u32,
+    remote: bool,
+}
 
 impl PriorityFrame {
-    pub fn to_bx_frame(&self, sub_id: frame_id::SubId) -> bxcan::Frame {
-        let raw = self.0.raw_frame();
-        let raw_id = raw.0.as_raw(sub_id);
+    /// The frame as the node with `sub_id` sends it.
+    pub fn new(frame: impl Into<AnyFrame<crate::AppFrame>>, sub_id: frame_id::SubId) -> Self {
+        let frame = frame.into();
+        Self {
+            frame,
+            id: helpers::ID_LAYOUT.encode(&Header::from_node(&frame, sub_id)),
+            remote: matches!(frame.raw(), frames::RawType::Remote(_)),
+        }
+    }
 
-        match raw.1 {
+    pub fn to_bx_frame(&self) -> bxcan::Frame {
+        let raw_id = self.id;
+
+        match self.frame.raw() {
             canbus_common::frames::RawType::Data(v) => bxcan::Frame::new_data(
                 bxcan::ExtendedId::new(raw_id).unwrap(),
                 bxcan::Data::new(&v).unwrap(),
@@ -30,16 +46,18 @@ impl PriorityFrame {
         }
     }
 
-    pub fn from_bxcan_frame(f: &bxcan::Frame) -> Result<Self, ()> {
-        let id = match f.id() {
-            bxcan::Id::Extended(id) => {
-                canbus_common::frame_id::FrameId::try_from_u32_with_sub_id(id.as_raw()).ok_or(())
-            }
+    /// The frame with the header it came with.
+    pub fn from_bxcan_frame(f: &bxcan::Frame) -> Result<(Self, Header), ()> {
+        let (id, header) = match f.id() {
+            bxcan::Id::Extended(id) => helpers::ID_LAYOUT
+                .decode(id.as_raw())
+                .map(|header| (id.as_raw(), header))
+                .ok_or(()),
             _ => Err(()),
         }?;
 
-        let res = canbus_common::frames::Frame::parse_frame(
-            id.0,
+        let res = AnyFrame::parse_frame(
+            header.id,
             match f.data() {
                 Some(data) => canbus_common::frames::ParserType::Data(data),
                 None => canbus_common::frames::ParserType::Remote(f.dlc()),
@@ -47,20 +65,23 @@ impl PriorityFrame {
         )
             .map_err(|_e| ())?;
 
-        Ok(PriorityFrame(res))
+        Ok((
+            PriorityFrame {
+                frame: res,
+                id,
+                remote: f.is_remote_frame(),
+            },
+            header,
+        ))
     }
 }
 
 /// Ordering is based on the Identifier and frame type (data vs. remote) and can be used to sort
-/// frames by priority.
+/// frames by priority. The frame that wins the bus arbitration is the greatest, so the max heap
+/// of the tx queue sends it first.
 impl Ord for PriorityFrame {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.0.id().to_u16() > other.0.id().to_u16() {
-            return Ordering::Greater;
-        } else if self.0.id().to_u16() < other.0.id().to_u16() {
-            return Ordering::Less;
-        }
-        Ordering::Equal
+        (other.id, other.remote).cmp(&(self.id, self.remote))
     }
 }
 
@@ -87,145 +108,276 @@ pub fn enqueue_frame(queue: &mut binary_heap::BinaryHeap<PriorityFrame, binary_h
 }
 
 
+/// Lets through only what the node with `sub_id` handles, see
+/// `canbus_common::addressing::acceptance_filters`.
+pub fn set_filters(can: &mut bxcan::Can<Can<CAN1>>, sub_id: frame_id::SubId) {
+    let mut filters = can.modify_filters();
+    filters.clear();
+    for (n, (id, mask)) in canbus_common::addressing::acceptance_filters(sub_id, helpers::ID_LAYOUT)
+        .into_iter()
+        .enumerate()
+    {
+        filters.enable_bank(
+            n as u8,
+            bxcan::Fifo::Fifo0,
+            bxcan::filter::Mask32::frames_with_ext_id(
+                bxcan::ExtendedId::new(id).unwrap(),
+                bxcan::ExtendedId::new(mask).unwrap(),
+            ),
+        );
+    }
+}
+
+/// Gives the node `sub_id`, the filters are set again when it changes.
+pub fn set_sub_id(
+    dyn_id: &mut impl Mutex<T = frame_id::SubId>,
+    can: &mut impl Mutex<T = bxcan::Can<Can<CAN1>>>,
+    sub_id: frame_id::SubId,
+) {
+    if dyn_id.lock(|v| core::mem::replace(v, sub_id)) != sub_id {
+        can.lock(|can| set_filters(can, sub_id));
+    }
+}
+
+/// Leaves `config` for idle to write, unless it is in flash already.
+fn store_config(
+    config_write: &mut impl Mutex<T = Option<helpers::config::Config>>,
+    config: helpers::config::Config,
+) {
+    config_write.lock(|v| {
+        if v.unwrap_or_else(crate::util::config::read) != config {
+            *v = Some(config);
+        }
+    });
+}
+
 pub fn can_rx0(mut cx: can_rx0::Context) {
     let mut can_tx_queue = cx.shared.can_tx_queue;
+    let mut can = cx.shared.can;
 
     loop {
-        match cx.local.can_rx.receive() {
+        match can.lock(|can| can.receive()) {
             Ok(frame) => {
                 cx.shared.led2.lock(|_led| {
                     //led.set_high();
                 });
 
-                let id_is_ok = true;
+                let own = cx.shared.dyn_id.lock(|v| *v);
+                let frame = match PriorityFrame::from_bxcan_frame(&frame) {
+                    Ok((frame, header)) => {
+                        let carried = helpers::ID_LAYOUT.carried(own);
+                        match canbus_common::addressing::check(&frame.frame, header.to, carried) {
+                            canbus_common::addressing::Verdict::Act => frame,
+                            canbus_common::addressing::Verdict::Ignore => continue,
+                            canbus_common::addressing::Verdict::Reject(e) => {
+                                can_tx_queue.lock(|can_tx_queue| {
+                                    enqueue_frame(
+                                        can_tx_queue,
+                                        PriorityFrame::new(
+                                            canbus_common::frames::Frame::NodeError(e),
+                                            own,
+                                        ),
+                                    );
+                                });
+                                continue;
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        //hprintln!("parse_frame er");
+                        continue;
+                    }
+                };
 
-                match PriorityFrame::from_bxcan_frame(&frame) {
-                    Ok(frame) => match frame.0 {
-                        canbus_common::frames::Frame::Serial(serial) => if serial == frames::Type::Remote {
+                let frame = match frame.frame {
+                    AnyFrame::Core(frame) => frame,
+                    AnyFrame::App(frame) => {
+                        if let Some(answer) = crate::util::app::on_frame(frame) {
+                            can_tx_queue.lock(|can_tx_queue| {
+                                enqueue_frame(can_tx_queue, PriorityFrame::new(answer, own));
+                            });
+                        }
+                        continue;
+                    }
+                };
+
+                match frame {
+                    canbus_common::frames::Frame::Serial(serial) => if serial == frames::Type::Remote {
+                        // many nodes answer at once, each waits for its own slot
+                        let round = *cx.local.serial_requests;
+                        *cx.local.serial_requests = round.wrapping_add(1);
+                        // fails while an answer is already scheduled, which serves this request too
+                        let _ = crate::app::send_serial::spawn_after(
+                            crate::DEVICE_SERIAL.reply_delay_ms(round).millis(),
+                        );
+                    },
+                    canbus_common::frames::Frame::Status(frames::Type::Remote) => {
+                        can_tx_queue.lock(|can_tx_queue| {
+                            enqueue_frame(
+                                can_tx_queue,
+                                PriorityFrame::new(
+                                    canbus_common::frames::Frame::Status(
+                                        frames::Type::Data(frames::status::Status::new(
+                                            frames::status::Mode::Application,
+                                        )),
+                                    ),
+                                    own,
+                                ),
+                            );
+                        });
+                    }
+                    canbus_common::frames::Frame::BootInfo(frames::Type::Remote) => {
+                        let info = helpers::handoff::read().unwrap_or_default().boot_info();
+                        can_tx_queue.lock(|can_tx_queue| {
+                            enqueue_frame(
+                                can_tx_queue,
+                                PriorityFrame::new(
+                                    canbus_common::frames::Frame::BootInfo(
+                                        frames::Type::Data(info),
+                                    ),
+                                    own,
+                                ),
+                            );
+                        });
+                    }
+                    canbus_common::frames::Frame::FirmwareVersion(frames::Type::Remote) => {
+                        if let Some(version) = *cx.local.fw_version {
                             can_tx_queue.lock(|can_tx_queue| {
                                 enqueue_frame(
                                     can_tx_queue,
-                                    PriorityFrame(canbus_common::frames::Frame::Serial(
-                                        frames::Type::Data(crate::DEVICE_SERIAL),
-                                    )),
+                                    PriorityFrame::new(
+                                        canbus_common::frames::Frame::FirmwareVersion(
+                                            frames::Type::Data(version),
+                                        ),
+                                        own,
+                                    ),
                                 );
                             });
-                        },
-                        canbus_common::frames::Frame::DynId(value) => {
-                            if value.serial == crate::DEVICE_SERIAL {
-                                let crc = crc8_fast::calc(
-                                    &value.dyn_id.to_be_bytes(),
-                                    crc8_fast::calc(&value.serial.0, 0),
+                        }
+                    }
+                    canbus_common::frames::Frame::HardwareVersion(frames::Type::Remote) => {
+                        can_tx_queue.lock(|can_tx_queue| {
+                            enqueue_frame(
+                                can_tx_queue,
+                                PriorityFrame::new(
+                                    canbus_common::frames::Frame::HardwareVersion(
+                                        frames::Type::Data(helpers::HARDWARE_VERSION),
+                                    ),
+                                    own,
+                                ),
+                            );
+                        });
+                    }
+                    canbus_common::frames::Frame::BootloaderVersion(frames::Type::Remote) => {
+                        // unknown with a bootloader that doesn't pass the handoff
+                        if let Some(handoff) = helpers::handoff::read() {
+                            can_tx_queue.lock(|can_tx_queue| {
+                                enqueue_frame(
+                                    can_tx_queue,
+                                    PriorityFrame::new(
+                                        canbus_common::frames::Frame::BootloaderVersion(
+                                            frames::Type::Data(handoff.bootloader_version),
+                                        ),
+                                        own,
+                                    ),
                                 );
-
-                                cx.shared.dyn_id.lock(|v| {
-                                    *v = frame_id::SubId::from([crc, value.dyn_id]);
-                                });
-                            }
+                            });
                         }
-                        canbus_common::frames::Frame::PendingFirmwareVersion(frames::Type::Remote)
-                        if id_is_ok =>
-                            {
-                                cx.shared.pending_fw_version_required.lock(
-                                    |pending_fw_version_required| {
-                                        *pending_fw_version_required = true;
-                                    },
-                                )
-                            }
-                        canbus_common::frames::Frame::FirmwareUploadPart(value) if id_is_ok => {
-                            /*cx.shared.serial.lock(|serial| {
-                                write!(serial, "FirmwareUploadPart: {:?}\r\n", value).unwrap();
-                            });*/
-
-                            cx.shared.fw_upload.lock(|fw_upload| {
-                                match fw_upload.data.put_part(value.data, value.position()) {
-                                    Ok(_) => {
-                                        if fw_upload.data.page_is_ready() && !fw_upload.paused {
-                                            fw_upload.paused = true;
-
-                                            can_tx_queue.lock(|can_tx_queue| {
-                                                enqueue_frame(
-                                                    can_tx_queue,
-                                                    PriorityFrame(canbus_common::frames::Frame::FirmwareUploadPause(fw_upload.paused)),
-                                                );
-                                            });
-                                        }
-                                    }
-                                    Err(firmware_update::PutPartError::NotEnoughSpace) => {
-                                        if !fw_upload.paused {
-                                            fw_upload.paused = true;
+                    }
+                    canbus_common::frames::Frame::DynId(value) => {
+                        if value.serial == crate::DEVICE_SERIAL {
+                            // the host overrides the claimed address
+                            let (claim, sub_id) = cx.shared.claim.lock(|claim| {
+                                (claim.assign(value.dyn_id), claim.sub_id())
+                            });
+                            let sub_id = sub_id.unwrap_or(frame_id::SubId(0));
+                            set_sub_id(&mut cx.shared.dyn_id, &mut can, sub_id);
+                            can_tx_queue.lock(|can_tx_queue| {
+                                enqueue_frame(can_tx_queue, PriorityFrame::new(claim, sub_id));
+                            });
 
-                                            can_tx_queue.lock(|can_tx_queue| {
-                                                enqueue_frame(
-                                                    can_tx_queue,
-                                                    PriorityFrame(canbus_common::frames::Frame::FirmwareUploadPause(fw_upload.paused)),
-                                                );
-                                            });
-                                        }
-                                    }
-                                    Err(firmware_update::PutPartError::LessOfMinPart(p)) | Err(firmware_update::PutPartError::MoreOfMaxPart(p)) => {
-                                        can_tx_queue.lock(|can_tx_queue| {
-                                            enqueue_frame(
-                                                can_tx_queue,
-                                                PriorityFrame(canbus_common::frames::Frame::FirmwareUploadPartChangePos(
-                                                    canbus_common::frames::firmware::UploadPartChangePos::new(p).unwrap())
-                                                ),
-                                            );
-                                        });
-                                    }
-                                }
+                            store_config(&mut cx.shared.config_write, helpers::config::Config {
+                                dyn_id: Some(value.dyn_id),
                             });
                         }
-                        canbus_common::frames::Frame::FirmwareUploadFinished if id_is_ok => {
-                            cx.shared.fw_upload.lock(|fw_upload| {
-                                while !fw_upload.data.page_is_ready() {
-                                    fw_upload
-                                        .data
-                                        .put_part(
-                                            [0_u8; 5],
-                                            fw_upload.data.loaded_parts_count(),
-                                        )
-                                        .unwrap();
-                                }
-                                //hprintln!("qqqqqqqqq");
-
-                                fw_upload.paused = true;
-                                fw_upload.finished = true;
+                    }
+                    canbus_common::frames::Frame::ClearDynId(serial) => {
+                        if serial == crate::DEVICE_SERIAL {
+                            let now = crate::app::monotonics::now().ticks();
+                            let claim = cx.shared.claim.lock(|claim| {
+                                *claim = canbus_common::address_claim::AddressClaimer::new(
+                                    serial,
+                                    canbus_common::address_claim::AddressClaimer::preferred_address(&serial),
+                                );
+                                claim.start(now)
+                            });
+                            set_sub_id(&mut cx.shared.dyn_id, &mut can, frame_id::SubId(0));
+                            can_tx_queue.lock(|can_tx_queue| {
+                                enqueue_frame(can_tx_queue, PriorityFrame::new(claim, frame_id::SubId(0)));
+                            });
 
-                                can_tx_queue.lock(|can_tx_queue| {
-                                    enqueue_frame(
-                                        can_tx_queue,
-                                        PriorityFrame(
-                                            canbus_common::frames::Frame::FirmwareUploadPause(
-                                                fw_upload.paused,
-                                            ),
-                                        ),
-                                    );
-                                });
+                            store_config(&mut cx.shared.config_write, helpers::config::Config::default());
+                        }
+                    }
+                    frame @ canbus_common::frames::Frame::AddressClaim(_) => {
+                        let now = crate::app::monotonics::now().ticks();
+                        let (answer, sub_id) = cx.shared.claim.lock(|claim| {
+                            (claim.on_frame(&frame, now), claim.sub_id())
+                        });
+                        let sub_id = sub_id.unwrap_or(frame_id::SubId(0));
+                        set_sub_id(&mut cx.shared.dyn_id, &mut can, sub_id);
+                        if let Some(answer) = answer {
+                            can_tx_queue.lock(|can_tx_queue| {
+                                enqueue_frame(can_tx_queue, PriorityFrame::new(answer, sub_id));
                             });
                         }
-                        canbus_common::frames::Frame::FirmwareStartUpdate if id_is_ok => {
-                            cx.shared.fw_upload.lock(|fw_upload| {
-                                match fw_upload.has_pending_fw {
-                                    true => {
-                                        cx.shared.serial.lock(|serial| {
-                                            write!(serial, "Reboot to upgrade...\r\n").unwrap();
-                                        });
-                                        cortex_m::peripheral::SCB::sys_reset();
-                                    }
-                                    false => {
-                                        cx.shared.serial.lock(|serial| {
-                                            write!(serial, "Has no pending fw !!!\r\n").unwrap();
-                                        });
+                    }
+                    canbus_common::frames::Frame::UpdateHistoryRequest(index) => {
+                        can_tx_queue.lock(|can_tx_queue| {
+                            match crate::util::history::get(index as usize) {
+                                Some(record) => {
+                                    for part in record.parts(index) {
+                                        enqueue_frame(
+                                            can_tx_queue,
+                                            PriorityFrame::new(
+                                                canbus_common::frames::Frame::UpdateHistoryRecord(part),
+                                                own,
+                                            ),
+                                        );
                                     }
                                 }
-                            });
-                        }
-                        _ => {}
-                    },
+                                None => enqueue_frame(
+                                    can_tx_queue,
+                                    PriorityFrame::new(
+                                        canbus_common::frames::Frame::UpdateHistoryRecord(
+                                            frames::history::RecordPart::Empty { index },
+                                        ),
+                                        own,
+                                    ),
+                                ),
+                            }
+                        });
+                    }
+                    canbus_common::frames::Frame::EnterBootloader => {
+                        let mut handoff = helpers::handoff::read().unwrap_or_default();
+                        handoff.enter_bootloader = true;
+                        helpers::handoff::write(handoff);
 
-                    Err(_) => {
-                        //hprintln!("parse_frame er");
+                        cx.shared.serial.lock(|serial| {
+                            write!(serial, "Reboot to bootloader...\r\n").unwrap();
+                        });
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    frame => {
+                        // the update frames, flash work is left to idle
+                        let actions = cx.shared.update.lock(|update| update.on_frame(&frame));
+                        can_tx_queue.lock(|can_tx_queue| {
+                            for action in actions {
+                                if let helpers::update_receiver::Action::Send(frame) = action {
+                                    enqueue_frame(can_tx_queue, PriorityFrame::new(frame, own));
+                                }
+                            }
+                        });
                     }
                 }
             }
@@ -243,11 +395,11 @@ pub fn can_rx0(mut cx: can_rx0::Context) {
 }
 
 pub fn can_tx(mut cx: can_tx::Context) {
-    let tx = cx.local.can_tx;
+    let mut can = cx.shared.can;
     let mut tx_queue = cx.shared.can_tx_queue;
     //let _tx_count = cx.shared.tx_count;
 
-    tx.clear_interrupt_flags();
+    can.lock(|can| can.clear_tx_interrupt());
 
     cx.shared.led2.lock(|_led| {
         //led.set_high();
@@ -260,16 +412,15 @@ pub fn can_tx(mut cx: can_tx::Context) {
         //hprintln!("tx_queue {}", tx_queue.len());
         while let Some(frame) = tx_queue.peek() {
             //hprintln!("tx_queue1");
-            let sub_id = cx.shared.dyn_id.lock(|v| *v);
             /*hprintln!("tx_queue12");
             cx.shared.serial.lock(|serial| {
                 write!(serial, "tx_queue12: {:?} {:?}\r\n", sub_id, frame).unwrap();
                 //nb::block!(serial.write_str("fdddddddddd"));
                 //write!(serial, "123456789\r\n").unwrap();
             });*/
-            let f = frame.to_bx_frame(sub_id);
+            let f = frame.to_bx_frame();
             //hprintln!("tx_queue123");
-            let t = tx.transmit(&f);
+            let t = can.lock(|can| can.transmit(&f));
             //hprintln!("tx_queue1234");
             match t {
                 Ok(status) => match status.dequeued_frame() {
@@ -284,7 +435,7 @@ pub fn can_tx(mut cx: can_tx::Context) {
                         tx_queue.pop();
 
                         let f = PriorityFrame::from_bxcan_frame(pending_frame).unwrap();
-                        enqueue_frame(tx_queue, f);
+                        enqueue_frame(tx_queue, f.0);
                     }
                 },
                 Err(nb::Error::WouldBlock) => break,
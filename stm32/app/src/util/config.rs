@@ -0,0 +1,24 @@
+/// Read from init and the CAN interrupt, which have no access to the flash writer.
+fn page() -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts(
+            &*(memory_map::CONFIG.address() as *const u8),
+            helpers::config::Config::SIZE,
+        )
+    }
+}
+
+pub fn read() -> helpers::config::Config {
+    helpers::config::Config::try_from(page()).unwrap_or_default()
+}
+
+pub fn write(
+    flash: &mut stm32f1xx_hal::flash::Parts,
+    config: helpers::config::Config,
+) -> Result<(), stm32f1xx_hal::flash::Error> {
+    helpers::config::write(
+        &mut helpers::flash::writer(flash),
+        crate::CONFIG as u32,
+        config,
+    )
+}
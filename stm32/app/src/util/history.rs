@@ -0,0 +1,18 @@
+use canbus_common::frames::history;
+
+/// Read from the CAN interrupt, which has no access to the flash writer.
+fn page() -> &'static [u8] {
+    let address = memory_map::JOURNAL.address() as *const u8;
+    unsafe { core::slice::from_raw_parts(&*address, crate::PAGE_SIZE) }
+}
+
+pub fn get(index: usize) -> Option<history::Record> {
+    helpers::history::get(page(), index).map(|v| v.record)
+}
+
+pub fn log(
+    flash: &mut stm32f1xx_hal::flash::Parts,
+    record: history::Record,
+) -> Result<(), stm32f1xx_hal::flash::Error> {
+    helpers::history::log(&mut helpers::flash::writer(flash), crate::JOURNAL as u32, record)
+}
@@ -0,0 +1,306 @@
+use crate::flash::FlashStorage;
+use canbus_common::frames::version::Version;
+use core::ops::Range;
+
+/// Record kept in the last page of the application slot, written by the bootloader after
+/// it installed an image. Images flashed directly with st-flash don't have it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AppInfo {
+    pub version: Version,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl AppInfo {
+    pub const MAGIC: u32 = 0x41505049; // "APPI"
+    pub const SIZE: usize = 20;
+
+    pub fn new(version: Version, image: &[u8]) -> Self {
+        Self {
+            version,
+            len: image.len() as u32,
+            crc: crc32c_hw::compute(image),
+        }
+    }
+}
+
+impl From<AppInfo> for [u8; AppInfo::SIZE] {
+    fn from(v: AppInfo) -> Self {
+        let mut data = [0_u8; AppInfo::SIZE];
+        data[..4].clone_from_slice(&AppInfo::MAGIC.to_be_bytes());
+        data[4..12].clone_from_slice(&<[u8; 8]>::from(v.version));
+        data[12..16].clone_from_slice(&v.len.to_be_bytes());
+        data[16..20].clone_from_slice(&v.crc.to_be_bytes());
+        data
+    }
+}
+
+impl TryFrom<&[u8; AppInfo::SIZE]> for AppInfo {
+    type Error = ();
+
+    fn try_from(v: &[u8; AppInfo::SIZE]) -> Result<Self, Self::Error> {
+        if u32::from_be_bytes(<[u8; 4]>::try_from(&v[..4]).unwrap()) != AppInfo::MAGIC {
+            return Err(());
+        }
+
+        Ok(Self {
+            version: Version::from(<[u8; 8]>::try_from(&v[4..12]).unwrap()),
+            len: u32::from_be_bytes(<[u8; 4]>::try_from(&v[12..16]).unwrap()),
+            crc: u32::from_be_bytes(<[u8; 4]>::try_from(&v[16..20]).unwrap()),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ValidationError {
+    Erased,
+    InvalidStackPointer(u32),
+    InvalidResetVector(u32),
+    TooLarge(u32),
+    CrcMismatch,
+    Unreadable,
+    /// No info record behind the slot, so the image can't be checked.
+    NoInfo,
+}
+
+/// Checks the first two entries of the vector table: the initial stack pointer must point
+/// into RAM and the reset handler must be a thumb address inside the application slot.
+pub fn check_vectors(
+    stack_pointer: u32,
+    reset_vector: u32,
+    app: &Range<u32>,
+    ram: &Range<u32>,
+) -> Result<(), ValidationError> {
+    if stack_pointer == 0xFFFFFFFF && reset_vector == 0xFFFFFFFF {
+        return Err(ValidationError::Erased);
+    }
+
+    if stack_pointer <= ram.start || stack_pointer > ram.end || stack_pointer & 3 != 0 {
+        return Err(ValidationError::InvalidStackPointer(stack_pointer));
+    }
+
+    if reset_vector & 1 == 0 || !app.contains(&(reset_vector & !1)) {
+        return Err(ValidationError::InvalidResetVector(reset_vector));
+    }
+
+    Ok(())
+}
+
+/// Reads the info record at `location` without checking the image.
+pub fn info<F: FlashStorage>(flash: &F, location: u32) -> Option<AppInfo> {
+    let data = flash.read(location, AppInfo::SIZE).ok()?;
+    AppInfo::try_from(<&[u8; AppInfo::SIZE]>::try_from(data).unwrap()).ok()
+}
+
+/// Validates the installed application. `app` is the slot as flash offsets, the info record
+/// is expected at `app.end`. An app flashed directly with st-flash has no record and so no
+/// crc to check, it is only taken with `accept_unrecorded`, `Ok(None)` then. That is meant for
+/// the first flash of a board, before the bootloader installed any image.
+pub fn validate<F: FlashStorage>(
+    flash: &F,
+    app: Range<u32>,
+    ram: Range<u32>,
+    accept_unrecorded: bool,
+) -> Result<Option<AppInfo>, ValidationError> {
+    let vectors = flash
+        .read(app.start, 8)
+        .map_err(|_| ValidationError::Unreadable)?;
+    check_vectors(
+        u32::from_le_bytes(vectors[..4].try_into().unwrap()),
+        u32::from_le_bytes(vectors[4..].try_into().unwrap()),
+        &((memory_map::FLASH_BASE + app.start)..(memory_map::FLASH_BASE + app.end)),
+        &ram,
+    )?;
+
+    let info = match info(flash, app.end) {
+        Some(v) => v,
+        None if accept_unrecorded => return Ok(None),
+        None => return Err(ValidationError::NoInfo),
+    };
+
+    if info.len > app.end - app.start {
+        return Err(ValidationError::TooLarge(info.len));
+    }
+
+    let image = flash
+        .read(app.start, info.len as usize)
+        .map_err(|_| ValidationError::Unreadable)?;
+
+    match crc32c_hw::compute(image) == info.crc {
+        true => Ok(Some(info)),
+        false => Err(ValidationError::CrcMismatch),
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InstallError<E> {
+    Flash(E),
+    TooLarge(u32),
+    /// Offset of the first byte that differs after the copy.
+    Verify(u32),
+}
+
+/// Copies the image at `image` into the application slot `app` and writes the info record
+/// behind the slot. The old record is erased first, so an interrupted copy is never taken
+/// as a valid application.
+pub fn install<F: FlashStorage>(
+    flash: &mut F,
+    version: Version,
+    image: Range<u32>,
+    app: Range<u32>,
+) -> Result<AppInfo, InstallError<F::Error>> {
+    const CHUNK: u32 = 64;
+
+    let len = image.end - image.start;
+    if len > app.end - app.start {
+        return Err(InstallError::TooLarge(len));
+    }
+
+    flash.erase_page(app.end).map_err(InstallError::Flash)?;
+
+    let page_size = flash.page_size();
+    let mut buf = [0_u8; CHUNK as usize];
+    for page in (0..len).step_by(page_size as usize) {
+        flash
+            .erase_page(app.start + page)
+            .map_err(InstallError::Flash)?;
+
+        let page_end = (page + page_size).min(len);
+        for pos in (page..page_end).step_by(CHUNK as usize) {
+            let n = (page_end - pos).min(CHUNK) as usize;
+            buf[..n].clone_from_slice(
+                flash
+                    .read(image.start + pos, n)
+                    .map_err(InstallError::Flash)?,
+            );
+            crate::flash::write_padded(flash, app.start + pos, &buf[..n])
+                .map_err(InstallError::Flash)?;
+        }
+    }
+
+    let installed = flash
+        .read(app.start, len as usize)
+        .map_err(InstallError::Flash)?;
+    let pending = flash
+        .read(image.start, len as usize)
+        .map_err(InstallError::Flash)?;
+    if let Some(pos) = installed.iter().zip(pending).position(|(a, b)| a != b) {
+        return Err(InstallError::Verify(pos as u32));
+    }
+
+    let info = AppInfo::new(version, installed);
+    flash
+        .write(app.end, &<[u8; AppInfo::SIZE]>::from(info))
+        .map_err(InstallError::Flash)?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+    use memory_map::{APP_INFO, PENDING};
+
+    const APP: Range<u32> = 0x08005000..0x08012000;
+    const RAM: Range<u32> = 0x20000000..0x20005000;
+
+    #[test]
+    fn vectors() {
+        assert_eq!(
+            check_vectors(0xFFFFFFFF, 0xFFFFFFFF, &APP, &RAM),
+            Err(ValidationError::Erased)
+        );
+        assert_eq!(check_vectors(0x20005000, 0x08005131, &APP, &RAM), Ok(()));
+        assert_eq!(
+            check_vectors(0x20005004, 0x08005131, &APP, &RAM),
+            Err(ValidationError::InvalidStackPointer(0x20005004))
+        );
+        assert_eq!(
+            check_vectors(0x20000000, 0x08005131, &APP, &RAM),
+            Err(ValidationError::InvalidStackPointer(0x20000000))
+        );
+        assert_eq!(
+            check_vectors(0x20005000, 0x08005130, &APP, &RAM),
+            Err(ValidationError::InvalidResetVector(0x08005130))
+        );
+        assert_eq!(
+            check_vectors(0x20005000, 0x08000131, &APP, &RAM),
+            Err(ValidationError::InvalidResetVector(0x08000131))
+        );
+    }
+
+    #[test]
+    fn app_info() {
+        let info = AppInfo::new(
+            Version {
+                major: 1,
+                minor: 2,
+                path: 3,
+                build: 4,
+            },
+            &[1, 2, 3, 4, 5, 6],
+        );
+        assert_eq!(info.len, 6);
+
+        let arr: [u8; AppInfo::SIZE] = info.into();
+        assert_eq!(AppInfo::try_from(&arr), Ok(info));
+        assert_eq!(AppInfo::try_from(&[0xFF_u8; AppInfo::SIZE]), Err(()));
+    }
+
+    #[test]
+    fn install_and_validate() {
+        let version = Version {
+            major: 1,
+            minor: 3,
+            path: 0,
+            build: 0,
+        };
+        let mut image = Vec::new();
+        image.extend(0x20005000_u32.to_le_bytes());
+        image.extend((memory_map::APP.address() + 0x131).to_le_bytes());
+        image.extend((0..2500_u32).map(|v| v as u8));
+
+        let mut f = Box::new(MemFlash::<{ 128 * 1024 }>::new(memory_map::PAGE_SIZE));
+        let slot = memory_map::APP.offset..APP_INFO.offset;
+        assert_eq!(
+            validate(&*f, slot.clone(), RAM, false),
+            Err(ValidationError::Erased)
+        );
+
+        let data = crate::pending_fw::tests::with_header(version, &image);
+        for (n, page) in data.chunks(1024).enumerate() {
+            crate::pending_fw::write_page(&mut *f, PENDING.offset, n, page).unwrap();
+        }
+        let (v, pending) = crate::pending_fw::locate(&*f, PENDING.offset).unwrap();
+
+        let installed = install(&mut *f, v, pending.clone(), slot.clone()).unwrap();
+        assert_eq!(installed, AppInfo::new(version, &image));
+        assert_eq!(info(&*f, APP_INFO.offset), Some(installed));
+        assert_eq!(validate(&*f, slot.clone(), RAM, false), Ok(Some(installed)));
+
+        // installing over an existing app
+        assert_eq!(
+            install(&mut *f, v, pending.clone(), slot.clone()),
+            Ok(installed)
+        );
+
+        assert_eq!(
+            install(
+                &mut *f,
+                v,
+                pending.start..(pending.start + 60 * 1024),
+                slot.clone()
+            ),
+            Err(InstallError::TooLarge(60 * 1024))
+        );
+        assert_eq!(validate(&*f, slot.clone(), RAM, false), Ok(Some(installed)));
+
+        // without the record only when asked for, as flashed directly
+        f.erase_page(APP_INFO.offset).unwrap();
+        assert_eq!(
+            validate(&*f, slot.clone(), RAM, false),
+            Err(ValidationError::NoInfo)
+        );
+        assert_eq!(validate(&*f, slot, RAM, true), Ok(None));
+    }
+}
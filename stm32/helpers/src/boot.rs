@@ -0,0 +1,355 @@
+//! What the bootloader does before it starts the app: installs a pending image, keeps the
+//! journal and picks between the app and recovery mode. The simulator boots its nodes with
+//! it as well.
+
+use crate::app_image::{self, AppInfo, InstallError, ValidationError};
+use crate::flash::FlashStorage;
+use crate::handoff::{Handoff, MAX_INSTALL_ATTEMPTS};
+use canbus_common::frames::boot_info::{BootReason, UpdateResult};
+use canbus_common::frames::history::{Outcome, Record};
+use canbus_common::frames::version::Version;
+use memory_map::{APP, APP_INFO, JOURNAL, PENDING, RAM_BASE, RAM_SIZE};
+
+/// Boots the app gets to come up, before recovery mode is entered instead.
+pub const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// Start the app.
+    Jump,
+    /// Write the handoff record and reset, the install is tried again.
+    Reset,
+    /// Stay in the bootloader and wait for a new image over CAN.
+    Recovery(Recovery),
+}
+
+/// Why recovery mode is entered, the first that applies.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Recovery {
+    /// The app asked for it.
+    Requested,
+    /// A partly copied image may still have valid vectors and no info record.
+    CopyFailed,
+    /// The pending image failed to verify too often.
+    InstallFailed,
+    InvalidApp(ValidationError),
+    /// The app hangs or resets before it comes up, e.g. by the watchdog.
+    NotComingUp,
+}
+
+/// Install of the pending image.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Install<E> {
+    pub version: Version,
+    pub result: Result<AppInfo, InstallError<E>>,
+    /// The installed image could not be erased from the pending slot.
+    pub erase_error: Option<E>,
+}
+
+/// What a boot did, the bootloader reports it over serial.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Boot<E> {
+    /// To write before the jump or the reset.
+    pub handoff: Handoff,
+    pub decision: Decision,
+    pub install: Option<Install<E>>,
+    /// The pending image is left alone, it failed to install too often.
+    pub skipped: Option<Version>,
+    /// The installed app, `None` when the install is tried again first.
+    pub app: Option<Result<Option<AppInfo>, ValidationError>>,
+}
+
+/// Boots with the handoff record `previous` left, `None` after a power loss. The app is taken
+/// without an info record only with `accept_unrecorded`, see `app_image::validate`.
+pub fn boot<F: FlashStorage>(
+    flash: &mut F,
+    previous: Option<Handoff>,
+    boot_reason: BootReason,
+    bootloader_version: Version,
+    accept_unrecorded: bool,
+) -> Boot<F::Error> {
+    let enter_requested = previous.is_some_and(|v| v.enter_bootloader);
+    let mut handoff = Handoff {
+        boot_reason,
+        update_result: previous.map_or(UpdateResult::None, |v| v.carried_result()),
+        bootloader_version,
+        enter_bootloader: false,
+        boot_count: previous.map_or(0, |v| v.boot_count).wrapping_add(1),
+        boot_attempts: previous.map_or(0, |v| v.boot_attempts),
+        install_attempts: previous.map_or(0, |v| v.install_attempts),
+    };
+    let mut boot = Boot {
+        handoff,
+        decision: Decision::Jump,
+        install: None,
+        skipped: None,
+        app: None,
+    };
+
+    match crate::pending_fw::locate(flash, PENDING.offset) {
+        // every install erases and writes the app slot again, until a new image comes
+        Some((version, _)) if handoff.install_attempts >= MAX_INSTALL_ATTEMPTS => {
+            boot.skipped = Some(version);
+        }
+        Some((version, image)) => {
+            let from = app_image::info(flash, APP_INFO.offset)
+                .map(|v| v.version)
+                .unwrap_or_default();
+            let result = app_image::install(flash, version, image, APP.offset..APP_INFO.offset);
+            handoff.update_result = match result {
+                Ok(_) => {
+                    // a new app gets its own attempts
+                    handoff.boot_attempts = 0;
+                    handoff.install_attempts = 0;
+                    UpdateResult::Success
+                }
+                Err(InstallError::Verify(_)) => {
+                    handoff.install_attempts += 1;
+                    UpdateResult::VerifyError
+                }
+                Err(_) => UpdateResult::CopyError,
+            };
+            let mut install = Install {
+                version,
+                result,
+                erase_error: None,
+            };
+
+            if handoff.update_result == UpdateResult::VerifyError
+                && handoff.install_attempts < MAX_INSTALL_ATTEMPTS
+            {
+                // the pending image is still there, a request of the app still holds
+                boot.handoff = Handoff {
+                    enter_bootloader: enter_requested,
+                    ..handoff
+                };
+                boot.decision = Decision::Reset;
+                boot.install = Some(install);
+                return boot;
+            }
+
+            // only the last attempt goes into the journal, it would wear out otherwise, and
+            // there is nothing to report to when that fails, the install itself is done
+            let outcome = match handoff.update_result {
+                UpdateResult::CopyError => Outcome::CopyError,
+                UpdateResult::VerifyError => Outcome::VerifyError,
+                _ => Outcome::Installed,
+            };
+            let record = Record {
+                boot_count: handoff.boot_count,
+                from,
+                to: version,
+                outcome,
+            };
+            let _ = crate::history::log(flash, JOURNAL.offset, record);
+
+            // a failed image is kept, it can be started again from recovery mode
+            if handoff.update_result == UpdateResult::Success {
+                install.erase_error = flash.erase_page(PENDING.offset).err();
+            }
+            boot.install = Some(install);
+        }
+        None => {}
+    }
+
+    let app = app_image::validate(
+        flash,
+        APP.offset..APP_INFO.offset,
+        RAM_BASE..(RAM_BASE + RAM_SIZE),
+        accept_unrecorded,
+    );
+    let recovery = if enter_requested {
+        Some(Recovery::Requested)
+    } else if handoff.update_result == UpdateResult::CopyError {
+        Some(Recovery::CopyFailed)
+    } else if handoff.install_attempts >= MAX_INSTALL_ATTEMPTS {
+        Some(Recovery::InstallFailed)
+    } else if let Err(e) = app {
+        Some(Recovery::InvalidApp(e))
+    } else if handoff.boot_attempts >= MAX_BOOT_ATTEMPTS {
+        Some(Recovery::NotComingUp)
+    } else {
+        None
+    };
+    boot.decision = match recovery {
+        Some(v) => Decision::Recovery(v),
+        None => {
+            handoff.boot_attempts += 1;
+            Decision::Jump
+        }
+    };
+    boot.handoff = handoff;
+    boot.app = Some(app);
+    boot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+    use crate::pending_fw::tests::with_header;
+
+    type Flash = MemFlash<{ memory_map::FLASH_SIZE as usize }>;
+
+    fn version(build: u32) -> Version {
+        Version {
+            major: 1,
+            minor: 0,
+            path: 0,
+            build,
+        }
+    }
+
+    /// An image with vectors into RAM and the app slot, odd to get a padded last write.
+    fn image(fill: u8) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend((RAM_BASE + RAM_SIZE).to_le_bytes());
+        image.extend((APP.address() + 0x101).to_le_bytes());
+        image.extend([fill; 1501]);
+        image
+    }
+
+    fn upload(flash: &mut Flash, version: Version, image: &[u8]) {
+        let data = with_header(version, image);
+        for (n, page) in data.chunks(1024).enumerate() {
+            crate::pending_fw::write_page(flash, PENDING.offset, n, page).unwrap();
+        }
+    }
+
+    fn boot(flash: &mut Flash, previous: Option<Handoff>) -> Boot<crate::flash::MemFlashError> {
+        super::boot(flash, previous, BootReason::Software, version(0), false)
+    }
+
+    fn outcomes(flash: &Flash) -> Vec<Outcome> {
+        let journal = flash.read(JOURNAL.offset, 1024).unwrap();
+        (0..)
+            .map_while(|n| crate::history::get(journal, n))
+            .map(|v| v.record.outcome)
+            .collect()
+    }
+
+    #[test]
+    fn install() {
+        let mut f = Box::new(Flash::new(memory_map::PAGE_SIZE));
+        let b = boot(&mut f, None);
+        assert_eq!(
+            b.decision,
+            Decision::Recovery(Recovery::InvalidApp(ValidationError::Erased))
+        );
+
+        let image = image(0x42);
+        upload(&mut f, version(1), &image);
+        let b = boot(&mut f, Some(b.handoff));
+        assert_eq!(b.decision, Decision::Jump);
+        assert_eq!(b.install.map(|v| v.version), Some(version(1)));
+        assert_eq!(b.app, Some(Ok(Some(AppInfo::new(version(1), &image)))));
+        assert_eq!(b.handoff.update_result, UpdateResult::Success);
+        assert_eq!(b.handoff.boot_attempts, 1);
+        assert_eq!(crate::pending_fw::locate(&*f, PENDING.offset), None);
+        assert_eq!(outcomes(&f), [Outcome::Installed]);
+
+        // the app asked for recovery, the result isn't carried any further
+        let b = boot(
+            &mut f,
+            Some(Handoff {
+                enter_bootloader: true,
+                ..b.handoff
+            }),
+        );
+        assert_eq!(b.decision, Decision::Recovery(Recovery::Requested));
+        assert_eq!(b.handoff.update_result, UpdateResult::None);
+        assert!(!b.handoff.enter_bootloader);
+    }
+
+    #[test]
+    fn not_coming_up() {
+        let mut f = Box::new(Flash::new(memory_map::PAGE_SIZE));
+        upload(&mut f, version(1), &image(0x42));
+        let mut previous = None;
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            let b = boot(&mut f, previous);
+            assert_eq!(b.decision, Decision::Jump);
+            previous = Some(b.handoff);
+        }
+        let b = boot(&mut f, previous);
+        assert_eq!(b.decision, Decision::Recovery(Recovery::NotComingUp));
+
+        // the app came up and cleared it
+        let b = boot(
+            &mut f,
+            Some(Handoff {
+                boot_attempts: 0,
+                ..b.handoff
+            }),
+        );
+        assert_eq!(b.decision, Decision::Jump);
+    }
+
+    /// Flash with a byte in the app slot that stays erased when written.
+    struct Worn(Box<Flash>);
+
+    impl FlashStorage for Worn {
+        type Error = crate::flash::MemFlashError;
+
+        fn page_size(&self) -> u32 {
+            self.0.page_size()
+        }
+
+        fn read(&self, offset: u32, len: usize) -> Result<&[u8], Self::Error> {
+            self.0.read(offset, len)
+        }
+
+        fn erase_page(&mut self, offset: u32) -> Result<(), Self::Error> {
+            self.0.erase_page(offset)
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+            let worn = (APP.offset + 700).checked_sub(offset).map(|v| v as usize);
+            match worn.filter(|v| *v < data.len()) {
+                Some(pos) => {
+                    let mut data = data.to_vec();
+                    data[pos] = 0xFF;
+                    self.0.write(offset, &data)
+                }
+                None => self.0.write(offset, data),
+            }
+        }
+    }
+
+    /// A verify error resets and tries again, the last one goes into the journal and the
+    /// image is skipped from then on.
+    #[test]
+    fn install_gives_up() {
+        let mut f = Worn(Box::new(Flash::new(memory_map::PAGE_SIZE)));
+        upload(&mut f.0, version(1), &image(0x42));
+        let boot = |f: &mut Worn, previous| {
+            super::boot(f, Some(previous), BootReason::Software, version(0), false)
+        };
+
+        let mut b = boot(
+            &mut f,
+            Handoff {
+                enter_bootloader: true,
+                ..Default::default()
+            },
+        );
+        for attempt in 1..MAX_INSTALL_ATTEMPTS {
+            assert_eq!(b.decision, Decision::Reset);
+            assert_eq!(b.handoff.install_attempts, attempt);
+            // a request of the app still holds
+            assert!(b.handoff.enter_bootloader);
+            assert_eq!(b.app, None);
+            b = boot(&mut f, b.handoff);
+        }
+        assert_eq!(b.decision, Decision::Recovery(Recovery::Requested));
+        assert_eq!(b.handoff.update_result, UpdateResult::VerifyError);
+        assert_eq!(outcomes(&f.0), [Outcome::VerifyError]);
+
+        let b = boot(&mut f, b.handoff);
+        assert_eq!(b.decision, Decision::Recovery(Recovery::InstallFailed));
+        assert_eq!(b.skipped, Some(version(1)));
+        assert_eq!(b.install, None);
+        assert_eq!(b.handoff.update_result, UpdateResult::VerifyError);
+        assert_eq!(outcomes(&f.0), [Outcome::VerifyError]);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::flash::FlashStorage;
+
+/// Device settings kept in the config page. They change rarely, so the page is erased and
+/// the record written again on every change. An erased or broken page reads as the
+/// default settings.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Config {
+    /// Given by the host with `DynId`, used instead of claiming an address.
+    pub dyn_id: Option<u8>,
+}
+
+impl Config {
+    pub const SIZE: usize = 12;
+    const MAGIC: [u8; 4] = *b"CFG1";
+}
+
+impl From<Config> for [u8; Config::SIZE] {
+    fn from(v: Config) -> Self {
+        let mut data = [0_u8; Config::SIZE];
+        data[..4].clone_from_slice(&Config::MAGIC);
+        if let Some(dyn_id) = v.dyn_id {
+            data[4] = 1;
+            data[5] = dyn_id;
+        }
+        let crc = crc32c_hw::compute(&data[..8]);
+        data[8..12].clone_from_slice(&crc.to_be_bytes());
+        data
+    }
+}
+
+impl TryFrom<&[u8]> for Config {
+    type Error = ();
+
+    fn try_from(v: &[u8]) -> Result<Self, Self::Error> {
+        if v.len() != Config::SIZE
+            || v[..4] != Config::MAGIC
+            || u32::from_be_bytes(<[u8; 4]>::try_from(&v[8..12]).unwrap())
+                != crc32c_hw::compute(&v[..8])
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            dyn_id: match v[4] {
+                0 => None,
+                1 => Some(v[5]),
+                _ => return Err(()),
+            },
+        })
+    }
+}
+
+/// The settings in the config page at `location`.
+pub fn read<F: FlashStorage>(flash: &F, location: u32) -> Config {
+    flash
+        .read(location, Config::SIZE)
+        .ok()
+        .and_then(|v| Config::try_from(v).ok())
+        .unwrap_or_default()
+}
+
+/// Replaces the settings in the config page at `location`, the default ones just erase it.
+pub fn write<F: FlashStorage>(
+    flash: &mut F,
+    location: u32,
+    config: Config,
+) -> Result<(), F::Error> {
+    match config == Config::default() {
+        true => flash.erase_page(location),
+        false => crate::flash::commit_page(flash, location, &<[u8; Config::SIZE]>::from(config)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+
+    #[test]
+    fn config() {
+        let c = Config { dyn_id: Some(10) };
+        let arr: [u8; Config::SIZE] = c.into();
+        assert_eq!(Config::try_from(&arr[..]), Ok(c));
+
+        let mut broken = arr;
+        broken[5] = 11;
+        assert_eq!(Config::try_from(&broken[..]), Err(()));
+        assert_eq!(Config::try_from(&[0xFF_u8; Config::SIZE][..]), Err(()));
+    }
+
+    #[test]
+    fn read_write() {
+        let mut f = MemFlash::<2048>::new(1024);
+        assert_eq!(read(&f, 1024), Config::default());
+
+        write(&mut f, 1024, Config { dyn_id: Some(7) }).unwrap();
+        assert_eq!(read(&f, 1024), Config { dyn_id: Some(7) });
+        write(&mut f, 1024, Config { dyn_id: Some(0) }).unwrap();
+        assert_eq!(read(&f, 1024), Config { dyn_id: Some(0) });
+
+        write(&mut f, 1024, Config::default()).unwrap();
+        assert_eq!(read(&f, 1024), Config::default());
+        assert_eq!(f.read(1024, 1024).unwrap(), &[0xFF_u8; 1024][..]);
+    }
+}
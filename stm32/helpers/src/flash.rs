@@ -0,0 +1,161 @@
+/// Flash as seen by the update logic. Offsets are from the beginning of flash, the same
+/// way `stm32f1xx_hal::flash::FlashWriter` takes them.
+pub trait FlashStorage {
+    type Error: core::fmt::Debug;
+
+    fn page_size(&self) -> u32;
+    fn read(&self, offset: u32, len: usize) -> Result<&[u8], Self::Error>;
+    /// `offset` has to be at a page boundary.
+    fn erase_page(&mut self, offset: u32) -> Result<(), Self::Error>;
+    /// Only erased bytes can be written, by half-words: `offset` and the length are even.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Erases the page at `offset` and writes `data` from its beginning.
+pub fn commit_page<F: FlashStorage>(
+    flash: &mut F,
+    offset: u32,
+    data: &[u8],
+) -> Result<(), F::Error> {
+    flash.erase_page(offset)?;
+    write_padded(flash, offset, data)
+}
+
+/// Writes `data` of any length, an odd last byte goes with an erased one behind it.
+pub fn write_padded<F: FlashStorage>(
+    flash: &mut F,
+    offset: u32,
+    data: &[u8],
+) -> Result<(), F::Error> {
+    let even = data.len() & !1;
+    if even > 0 {
+        flash.write(offset, &data[..even])?;
+    }
+    match data.get(even) {
+        Some(last) => flash.write(offset + even as u32, &[*last, 0xFF]),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "stm32")]
+pub fn writer(parts: &mut stm32f1xx_hal::flash::Parts) -> stm32f1xx_hal::flash::FlashWriter<'_> {
+    parts.writer(
+        stm32f1xx_hal::flash::SectorSize::Sz1K,
+        stm32f1xx_hal::flash::FlashSize::Sz128K,
+    )
+}
+
+#[cfg(feature = "stm32")]
+impl FlashStorage for stm32f1xx_hal::flash::FlashWriter<'_> {
+    type Error = stm32f1xx_hal::flash::Error;
+
+    fn page_size(&self) -> u32 {
+        memory_map::PAGE_SIZE
+    }
+
+    fn read(&self, offset: u32, len: usize) -> Result<&[u8], Self::Error> {
+        stm32f1xx_hal::flash::FlashWriter::read(self, offset, len)
+    }
+
+    fn erase_page(&mut self, offset: u32) -> Result<(), Self::Error> {
+        self.page_erase(offset)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        stm32f1xx_hal::flash::FlashWriter::write(self, offset, data)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemFlashError {
+    OutOfRange,
+    Unaligned,
+    NotErased,
+}
+
+/// Flash kept in RAM, behaves like the stm32 one: erased bytes are 0xFF, writes go by
+/// half-words and only into erased memory.
+pub struct MemFlash<const SIZE: usize> {
+    data: [u8; SIZE],
+    page_size: u32,
+}
+
+impl<const SIZE: usize> MemFlash<SIZE> {
+    pub fn new(page_size: u32) -> Self {
+        Self {
+            data: [0xFF; SIZE],
+            page_size,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<core::ops::Range<usize>, MemFlashError> {
+        let begin = offset as usize;
+        match begin.checked_add(len) {
+            Some(end) if end <= SIZE => Ok(begin..end),
+            _ => Err(MemFlashError::OutOfRange),
+        }
+    }
+}
+
+impl<const SIZE: usize> FlashStorage for MemFlash<SIZE> {
+    type Error = MemFlashError;
+
+    fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn read(&self, offset: u32, len: usize) -> Result<&[u8], Self::Error> {
+        Ok(&self.data[self.range(offset, len)?])
+    }
+
+    fn erase_page(&mut self, offset: u32) -> Result<(), Self::Error> {
+        if offset & (self.page_size - 1) != 0 {
+            return Err(MemFlashError::Unaligned);
+        }
+        let range = self.range(offset, self.page_size as usize)?;
+        self.data[range].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        if offset & 1 != 0 || data.len() & 1 != 0 {
+            return Err(MemFlashError::Unaligned);
+        }
+        let range = self.range(offset, data.len())?;
+        if self.data[range.clone()].iter().any(|v| *v != 0xFF) {
+            return Err(MemFlashError::NotErased);
+        }
+        self.data[range].clone_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_flash() {
+        let mut f = MemFlash::<4096>::new(1024);
+        assert_eq!(f.read(1020, 4), Ok(&[0xFF_u8; 4][..]));
+        assert_eq!(f.read(4094, 4), Err(MemFlashError::OutOfRange));
+
+        assert_eq!(f.write(1024, &[1, 2, 3]), Err(MemFlashError::Unaligned));
+        assert_eq!(write_padded(&mut f, 1024, &[1, 2, 3]), Ok(()));
+        assert_eq!(f.read(1024, 4), Ok(&[1_u8, 2, 3, 0xFF][..]));
+        assert_eq!(f.write(1026, &[4, 5]), Err(MemFlashError::NotErased));
+        assert_eq!(f.write(1029, &[4, 5]), Err(MemFlashError::Unaligned));
+        assert_eq!(f.erase_page(1000), Err(MemFlashError::Unaligned));
+
+        assert_eq!(commit_page(&mut f, 1024, &[5, 6, 7]), Ok(()));
+        assert_eq!(f.read(1024, 4), Ok(&[5_u8, 6, 7, 0xFF][..]));
+        assert_eq!(
+            commit_page(&mut f, 4096, &[5, 6]),
+            Err(MemFlashError::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use canbus_common::frames::boot_info::{BootInfo, BootReason, UpdateResult};
+use canbus_common::frames::version::Version;
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+
+/// The last 64 bytes of RAM are left out of `memory.x` in both the app and the bootloader,
+/// so neither of them initializes or uses this area and the record survives a reset.
+pub const ADDRESS: u32 = memory_map::HANDOFF;
+
+/// Installs of a pending image that failed to verify, before the bootloader gives up on it
+/// until a new image is uploaded.
+pub const MAX_INSTALL_ATTEMPTS: u8 = 3;
+
+const _: () = assert!(Handoff::SIZE <= memory_map::HANDOFF_SIZE as usize);
+
+/// Record passed between the bootloader and the app over a reset.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Handoff {
+    pub boot_reason: BootReason,
+    pub update_result: UpdateResult,
+    pub bootloader_version: Version,
+    pub enter_bootloader: bool,
+    /// Boots since the last power loss.
+    pub boot_count: u32,
+    /// Jumps to the app since it last came up, the app clears it.
+    pub boot_attempts: u8,
+    /// Installs of the pending image that failed to verify.
+    pub install_attempts: u8,
+}
+
+impl Handoff {
+    pub const MAGIC: u32 = 0x48414E44; // "HAND"
+    pub const VERSION: u8 = 3;
+    pub const SIZE: usize = 28;
+
+    pub fn boot_info(&self) -> BootInfo {
+        BootInfo::new(self.boot_reason, self.update_result)
+    }
+
+    /// The result the next boot starts with: a failed install is reported until an install
+    /// succeeds, so the host learns about it even after more resets.
+    pub fn carried_result(&self) -> UpdateResult {
+        match self.update_result {
+            UpdateResult::CopyError | UpdateResult::VerifyError => self.update_result,
+            _ => UpdateResult::None,
+        }
+    }
+}
+
+impl Default for Handoff {
+    fn default() -> Self {
+        Self {
+            boot_reason: BootReason::Unknown,
+            update_result: UpdateResult::None,
+            bootloader_version: Version::default(),
+            enter_bootloader: false,
+            boot_count: 0,
+            boot_attempts: 0,
+            install_attempts: 0,
+        }
+    }
+}
+
+impl From<Handoff> for [u8; Handoff::SIZE] {
+    fn from(v: Handoff) -> Self {
+        let mut data = [0_u8; Handoff::SIZE];
+        data[..4].clone_from_slice(&Handoff::MAGIC.to_be_bytes());
+        data[4] = Handoff::VERSION;
+        data[5] = v.boot_reason.to_u8().unwrap();
+        data[6] = v.update_result.to_u8().unwrap();
+        data[7] = u8::from(v.enter_bootloader);
+        data[8..16].clone_from_slice(&<[u8; 8]>::from(v.bootloader_version));
+        data[16..20].clone_from_slice(&v.boot_count.to_be_bytes());
+        data[20] = v.boot_attempts;
+        data[21] = v.install_attempts;
+        let crc = crc32c_hw::compute(&data[..24]);
+        data[24..28].clone_from_slice(&crc.to_be_bytes());
+        data
+    }
+}
+
+impl TryFrom<&[u8; Handoff::SIZE]> for Handoff {
+    type Error = ();
+
+    fn try_from(v: &[u8; Handoff::SIZE]) -> Result<Self, Self::Error> {
+        if u32::from_be_bytes(<[u8; 4]>::try_from(&v[..4]).unwrap()) != Handoff::MAGIC
+            || v[4] != Handoff::VERSION
+            || u32::from_be_bytes(<[u8; 4]>::try_from(&v[24..28]).unwrap())
+                != crc32c_hw::compute(&v[..24])
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            boot_reason: BootReason::from_u8(v[5]).ok_or(())?,
+            update_result: UpdateResult::from_u8(v[6]).ok_or(())?,
+            enter_bootloader: v[7] != 0,
+            bootloader_version: Version::from(<[u8; 8]>::try_from(&v[8..16]).unwrap()),
+            boot_count: u32::from_be_bytes(<[u8; 4]>::try_from(&v[16..20]).unwrap()),
+            boot_attempts: v[20],
+            install_attempts: v[21],
+        })
+    }
+}
+
+/// Returns `None` after a power loss or when the bootloader doesn't support the record.
+pub fn read() -> Option<Handoff> {
+    let data = unsafe { core::ptr::read_volatile(ADDRESS as *const [u8; Handoff::SIZE]) };
+    Handoff::try_from(&data).ok()
+}
+
+pub fn write(v: Handoff) {
+    unsafe {
+        core::ptr::write_volatile(ADDRESS as *mut [u8; Handoff::SIZE], v.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handoff() {
+        let h = Handoff {
+            boot_reason: BootReason::Software,
+            update_result: UpdateResult::Success,
+            bootloader_version: Version {
+                major: 0,
+                minor: 1,
+                path: 0,
+                build: 0,
+            },
+            enter_bootloader: true,
+            boot_count: 3,
+            boot_attempts: 2,
+            install_attempts: 1,
+        };
+
+        let mut arr: [u8; Handoff::SIZE] = h.into();
+        assert_eq!(Handoff::try_from(&arr), Ok(h));
+
+        arr[6] = 2;
+        assert_eq!(Handoff::try_from(&arr), Err(()));
+
+        assert_eq!(Handoff::try_from(&[0_u8; Handoff::SIZE]), Err(()));
+    }
+
+    #[test]
+    fn carried_result() {
+        let result = |update_result| {
+            Handoff {
+                update_result,
+                ..Default::default()
+            }
+            .carried_result()
+        };
+        assert_eq!(result(UpdateResult::Success), UpdateResult::None);
+        assert_eq!(result(UpdateResult::None), UpdateResult::None);
+        assert_eq!(result(UpdateResult::VerifyError), UpdateResult::VerifyError);
+        assert_eq!(result(UpdateResult::CopyError), UpdateResult::CopyError);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::flash::FlashStorage;
+use canbus_common::frames::history::Record;
+
+/// Update history kept in one flash page. Entries are appended into erased slots, when the
+/// page is full it is erased and the newest half is written back.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub seq: u32,
+    pub record: Record,
+}
+
+impl Entry {
+    pub const SIZE: usize = 32;
+}
+
+impl From<Entry> for [u8; Entry::SIZE] {
+    fn from(v: Entry) -> Self {
+        let mut data = [0_u8; Entry::SIZE];
+        data[..4].clone_from_slice(&v.seq.to_be_bytes());
+        data[4..28].clone_from_slice(&<[u8; Record::SIZE]>::from(v.record));
+        let crc = crc32c_hw::compute(&data[..28]);
+        data[28..32].clone_from_slice(&crc.to_be_bytes());
+        data
+    }
+}
+
+impl TryFrom<&[u8]> for Entry {
+    type Error = ();
+
+    fn try_from(v: &[u8]) -> Result<Self, Self::Error> {
+        if v.len() != Entry::SIZE
+            || u32::from_be_bytes(<[u8; 4]>::try_from(&v[28..32]).unwrap())
+                != crc32c_hw::compute(&v[..28])
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            seq: u32::from_be_bytes(<[u8; 4]>::try_from(&v[..4]).unwrap()),
+            record: Record::try_from(<&[u8; Record::SIZE]>::try_from(&v[4..28]).unwrap())?,
+        })
+    }
+}
+
+/// Number of written slots, entries are never written after an erased slot.
+fn used_slots(page: &[u8]) -> usize {
+    page.chunks_exact(Entry::SIZE)
+        .take_while(|slot| slot.iter().any(|v| *v != 0xFF))
+        .count()
+}
+
+/// Valid entries of the page, oldest first.
+pub fn entries(page: &[u8]) -> impl DoubleEndedIterator<Item = Entry> + '_ {
+    page[..(used_slots(page) * Entry::SIZE)]
+        .chunks_exact(Entry::SIZE)
+        .filter_map(|slot| Entry::try_from(slot).ok())
+}
+
+/// `index` 0 is the newest entry.
+pub fn get(page: &[u8], index: usize) -> Option<Entry> {
+    entries(page).rev().nth(index)
+}
+
+/// What has to be written into the page to append a record.
+#[allow(clippy::large_enum_variant)] // no heap to box it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Append {
+    /// Write the entry at the offset.
+    Write(usize, [u8; Entry::SIZE]),
+    /// The page is full: erase it and write the entries from its start.
+    Compact(arrayvec::ArrayVec<[u8; Entry::SIZE], { Append::KEEP + 1 }>),
+}
+
+impl Append {
+    const KEEP: usize = 16;
+}
+
+pub fn append(page: &[u8], record: Record) -> Append {
+    let slots = page.len() / Entry::SIZE;
+    let used = used_slots(page);
+
+    let entry = Entry {
+        seq: entries(page).last().map_or(0, |v| v.seq.wrapping_add(1)),
+        record,
+    };
+
+    if used < slots {
+        return Append::Write(used * Entry::SIZE, entry.into());
+    }
+
+    // keep the newest half
+    let mut kept = arrayvec::ArrayVec::new();
+    let skip = entries(page)
+        .count()
+        .saturating_sub(Append::KEEP.min(slots / 2));
+    for e in entries(page).skip(skip) {
+        kept.push(e.into());
+    }
+    kept.push(entry.into());
+
+    Append::Compact(kept)
+}
+
+/// Appends the record to the journal page at `location`.
+pub fn log<F: FlashStorage>(flash: &mut F, location: u32, record: Record) -> Result<(), F::Error> {
+    let page = flash.read(location, flash.page_size() as usize)?;
+    match append(page, record) {
+        Append::Write(offset, data) => flash.write(location + offset as u32, &data),
+        Append::Compact(entries) => {
+            flash.erase_page(location)?;
+            for (n, data) in entries.iter().enumerate() {
+                flash.write(location + (n * Entry::SIZE) as u32, data)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canbus_common::frames::history::Outcome;
+    use canbus_common::frames::version::Version;
+
+    fn record(boot_count: u32) -> Record {
+        Record {
+            boot_count,
+            from: Version::default(),
+            to: Version {
+                major: 1,
+                minor: 0,
+                path: 0,
+                build: boot_count,
+            },
+            outcome: Outcome::Installed,
+        }
+    }
+
+    fn append_to(page: &mut [u8; 1024], r: Record) {
+        match append(page, r) {
+            Append::Write(offset, data) => {
+                page[offset..offset + data.len()].clone_from_slice(&data);
+            }
+            Append::Compact(entries) => {
+                page.fill(0xFF);
+                for (n, data) in entries.iter().enumerate() {
+                    page[(n * Entry::SIZE)..((n + 1) * Entry::SIZE)].clone_from_slice(data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn entry() {
+        let e = Entry {
+            seq: 5,
+            record: record(3),
+        };
+        let arr: [u8; Entry::SIZE] = e.into();
+        assert_eq!(Entry::try_from(&arr[..]), Ok(e));
+        assert_eq!(Entry::try_from(&[0xFF_u8; Entry::SIZE][..]), Err(()));
+    }
+
+    #[test]
+    fn ring() {
+        let mut page = [0xFF_u8; 1024];
+        assert_eq!(get(&page, 0), None);
+
+        for n in 0..32 {
+            append_to(&mut page, record(n));
+        }
+        assert_eq!(entries(&page).count(), 32);
+        assert_eq!(get(&page, 0).unwrap().record, record(31));
+        assert_eq!(get(&page, 31).unwrap().record, record(0));
+        assert_eq!(get(&page, 32), None);
+
+        // full, compacted to the newest half
+        append_to(&mut page, record(32));
+        assert_eq!(entries(&page).count(), 17);
+        assert_eq!(get(&page, 0).unwrap().record, record(32));
+        assert_eq!(get(&page, 0).unwrap().seq, 32);
+        assert_eq!(get(&page, 16).unwrap().record, record(16));
+    }
+
+    #[test]
+    fn log_to_flash() {
+        let mut f = crate::flash::MemFlash::<2048>::new(1024);
+        for n in 0..40 {
+            log(&mut f, 1024, record(n)).unwrap();
+        }
+        let page = f.read(1024, 1024).unwrap();
+        assert_eq!(get(page, 0).unwrap().record, record(39));
+        assert_eq!(entries(page).count(), 17 + 7);
+        assert_eq!(f.read(0, 1024).unwrap(), &[0xFF_u8; 1024][..]);
+    }
+}
@@ -1,5 +1,28 @@
 //#![feature(generic_const_exprs)]
 #![cfg_attr(not(test), no_std)]
 
+pub mod app_image;
+pub mod boot;
+pub mod config;
 pub mod firmware_update;
+pub mod flash;
+pub mod handoff;
+pub mod history;
 pub mod pending_fw;
+pub mod update_receiver;
+
+pub const DEVICE_SERIAL: canbus_common::frames::serial::Serial =
+    canbus_common::frames::serial::Serial([1, 2, 3, 4, 5]);
+
+pub const HARDWARE_VERSION: canbus_common::frames::version::Version =
+    canbus_common::frames::version::Version {
+        major: 1,
+        minor: 0,
+        path: 0,
+        build: 0,
+    };
+
+/// How the bootloader and the app lay out the CAN identifier, `Addressed` only for buses
+/// where every node and host has it.
+pub const ID_LAYOUT: canbus_common::identifier::Layout =
+    canbus_common::identifier::Layout::Legacy;
@@ -1,26 +1,120 @@
-pub fn get(location: u32) -> Option<(canbus_common::frames::version::Version, &'static [u8])> {
-    let flash_size = u32::from_be_bytes(*unsafe { &*(location as *const [u8; 4]) }); // without len and crc
+use crate::flash::FlashStorage;
+use canbus_common::frames::version::Version;
+use core::ops::Range;
+
+/// The largest image, what the app slot takes.
+pub const MAX_IMAGE_SIZE: u32 = memory_map::APP.size;
+
+/// Image uploaded into the slot at `location`: `[len][version][image][crc]`, where `len`
+/// counts the version and the image, and the crc covers everything before it.
+/// Returns the version and where the image lies.
+pub fn locate<F: FlashStorage>(flash: &F, location: u32) -> Option<(Version, Range<u32>)> {
+    // without len and crc
+    let flash_size = u32::from_be_bytes(flash.read(location, 4).ok()?.try_into().unwrap());
     // definitely to much
-    if flash_size > 512*1024 {
+    if !(8..=memory_map::PENDING.size).contains(&flash_size) {
+        return None;
+    }
+    // it would only fail to install
+    if flash_size - 8 > MAX_IMAGE_SIZE {
         return None;
     }
-
-    let version = canbus_common::frames::version::Version::from(*unsafe {
-        &*((location + 4) as *const [u8; 8])
-    });
 
     // len+version+flash
-    let flash_data = unsafe {
-        core::slice::from_raw_parts(&*(location as *const u8), (flash_size + 4) as usize)
-    };
+    let flash_data = flash.read(location, (flash_size + 4) as usize).ok()?;
+    let crc = u32::from_be_bytes(
+        flash
+            .read(location + flash_data.len() as u32, 4)
+            .ok()?
+            .try_into()
+            .unwrap(),
+    );
+
+    if crc32c_hw::compute(flash_data) != crc {
+        return None;
+    }
+
+    let version = Version::from(<[u8; 8]>::try_from(&flash_data[4..12]).unwrap());
+    Some((
+        version,
+        (location + 12)..(location + flash_data.len() as u32),
+    ))
+}
+
+/// The image size the first page of an upload announces.
+pub fn image_size(first_page: &[u8]) -> Option<u32> {
+    let len = u32::from_be_bytes(first_page.get(..4)?.try_into().unwrap());
+    len.checked_sub(8)
+}
 
-    let crc = u32::from_be_bytes(*unsafe {
-        &*((location + flash_data.len() as u32) as *const [u8; 4])
-    });
+pub fn get<F: FlashStorage>(flash: &F, location: u32) -> Option<(Version, &[u8])> {
+    let (version, image) = locate(flash, location)?;
+    let data = flash.read(image.start, image.len()).ok()?;
+    Some((version, data))
+}
 
-    if crc32c_hw::compute(flash_data) == crc {
-        return Some((version, &flash_data[12..(flash_data.len()-4)]));
+/// Commits page `n` of an upload into the slot at `location`.
+pub fn write_page<F: FlashStorage>(
+    flash: &mut F,
+    location: u32,
+    n: usize,
+    data: &[u8],
+) -> Result<(), F::Error> {
+    let offset = location + flash.page_size() * n as u32;
+    crate::flash::commit_page(flash, offset, data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+
+    /// Same format the host builds, see `stm32/add_header.rs`.
+    pub fn with_header(version: Version, image: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(((8 + image.len()) as u32).to_be_bytes());
+        data.extend(<[u8; 8]>::from(version));
+        data.extend(image);
+        let crc = crc32c_hw::compute(&data);
+        data.extend(crc.to_be_bytes());
+        data
     }
 
-    None
-}
\ No newline at end of file
+    #[test]
+    fn pending_fw() {
+        let version = Version {
+            major: 1,
+            minor: 2,
+            path: 3,
+            build: 4,
+        };
+        let image: Vec<u8> = (0..1500_u32).map(|v| v as u8).collect();
+        let data = with_header(version, &image);
+
+        let mut f = MemFlash::<4096>::new(1024);
+        assert_eq!(get(&f, 1024), None);
+
+        for (n, page) in data.chunks(1024).enumerate() {
+            write_page(&mut f, 1024, n, page).unwrap();
+        }
+        assert_eq!(locate(&f, 1024), Some((version, 1036..2536)));
+        assert_eq!(get(&f, 1024), Some((version, &image[..])));
+
+        // corrupted
+        f.erase_page(2048).unwrap();
+        assert_eq!(get(&f, 1024), None);
+    }
+
+    #[test]
+    fn too_large() {
+        let image = vec![0x11_u8; MAX_IMAGE_SIZE as usize + 1];
+        let data = with_header(Version::default(), &image);
+        assert_eq!(image_size(&data), Some(MAX_IMAGE_SIZE + 1));
+
+        let mut f = Box::new(MemFlash::<{ 128 * 1024 }>::new(1024));
+        for (n, page) in data.chunks(1024).enumerate() {
+            write_page(&mut *f, 1024, n, page).unwrap();
+        }
+        assert_eq!(locate(&*f, 1024), None);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::firmware_update::{FirmwareUpdate, PutPartError};
+use crate::flash::FlashStorage;
+use canbus_common::frames::firmware::UploadPartChangePos;
+use canbus_common::frames::version::Version;
+use canbus_common::frames::{Frame, Type};
+use core::ops::Range;
+
+const PAGE_SIZE: usize = memory_map::PAGE_SIZE as usize;
+const PART_SIZE: usize = 5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Send(Frame),
+    /// Reboot so that the bootloader installs the pending image.
+    Reboot,
+    /// The upload is over, with the version of the uploaded image or `None` when the image
+    /// in the slot is broken.
+    Finished(Option<Version>),
+    /// Start was requested without a valid pending image.
+    NoPendingFirmware,
+    PageWriteFailed(usize),
+}
+
+pub type Actions = arrayvec::ArrayVec<Action, 6>;
+
+/// Device side of a firmware upload. Frames are passed in from the receive interrupt,
+/// flash work is done in `poll` from wherever flash may be accessed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpdateReceiver {
+    upload: FirmwareUpdate<PAGE_SIZE, PART_SIZE, { PAGE_SIZE + PART_SIZE }>,
+    slot: Range<u32>,
+    paused: bool,
+    finished: bool,
+    version_requested: bool,
+    start_requested: bool,
+    /// The upload announced an image the app slot doesn't take, its pages are dropped.
+    oversize: bool,
+}
+
+impl UpdateReceiver {
+    /// `slot` is where the uploaded image is written, as flash offsets.
+    pub fn new(slot: Range<u32>) -> Self {
+        Self {
+            upload: Default::default(),
+            slot,
+            paused: false,
+            finished: false,
+            version_requested: false,
+            start_requested: false,
+            oversize: false,
+        }
+    }
+
+    fn pause(&mut self, actions: &mut Actions) {
+        if !self.paused {
+            self.paused = true;
+            actions.push(Action::Send(Frame::FirmwareUploadPause(true)));
+        }
+    }
+
+    pub fn on_frame(&mut self, frame: &Frame) -> Actions {
+        let mut actions = Actions::new();
+
+        match frame {
+            Frame::PendingFirmwareVersion(Type::Remote) => self.version_requested = true,
+            Frame::FirmwareUploadPart(value) => {
+                match self.upload.put_part(value.data, value.position()) {
+                    Ok(_) => {
+                        // hold the host until the page is written
+                        if self.upload.page_is_ready() {
+                            self.pause(&mut actions);
+                        }
+                    }
+                    Err(PutPartError::NotEnoughSpace) => self.pause(&mut actions),
+                    Err(PutPartError::LessOfMinPart(p)) | Err(PutPartError::MoreOfMaxPart(p)) => {
+                        actions.push(Action::Send(Frame::FirmwareUploadPartChangePos(
+                            UploadPartChangePos::new(p).unwrap(),
+                        )));
+                    }
+                }
+            }
+            Frame::FirmwareUploadFinished => {
+                // pad the last page
+                if self.upload.len() > 0 {
+                    while !self.upload.page_is_ready() {
+                        self.upload
+                            .put_part([0_u8; PART_SIZE], self.upload.loaded_parts_count())
+                            .unwrap();
+                    }
+                }
+
+                self.finished = true;
+                self.paused = false;
+                self.pause(&mut actions);
+            }
+            Frame::FirmwareStartUpdate => self.start_requested = true,
+            _ => {}
+        }
+
+        actions
+    }
+
+    pub fn poll<F: FlashStorage>(&mut self, flash: &mut F) -> Actions {
+        let mut actions = Actions::new();
+
+        if let Some((page, n)) = self.upload.get_page() {
+            if n == 0 {
+                self.oversize = crate::pending_fw::image_size(page)
+                    .is_some_and(|v| v > crate::pending_fw::MAX_IMAGE_SIZE);
+            }
+            let offset = self.slot.start + (PAGE_SIZE * n) as u32;
+            let written = !self.oversize
+                && offset + PAGE_SIZE as u32 <= self.slot.end
+                && crate::pending_fw::write_page(flash, self.slot.start, n, page).is_ok();
+            if !written {
+                actions.push(Action::PageWriteFailed(n));
+            }
+            self.upload.remove_page();
+        }
+
+        if self.finished {
+            self.finished = false;
+            self.upload.reset();
+            actions.push(Action::Finished(
+                crate::pending_fw::locate(flash, self.slot.start).map(|v| v.0),
+            ));
+        }
+
+        if self.paused && !self.upload.page_is_ready() {
+            self.paused = false;
+            actions.push(Action::Send(Frame::FirmwareUploadPause(false)));
+        }
+
+        if self.version_requested {
+            self.version_requested = false;
+            actions.push(Action::Send(Frame::PendingFirmwareVersion(Type::Data(
+                crate::pending_fw::locate(flash, self.slot.start).map(|v| v.0),
+            ))));
+        }
+
+        if self.start_requested {
+            self.start_requested = false;
+            actions.push(match crate::pending_fw::locate(flash, self.slot.start) {
+                Some(_) => Action::Reboot,
+                None => Action::NoPendingFirmware,
+            });
+        }
+
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+    use canbus_common::frames::firmware::UploadPart;
+
+    const SLOT: Range<u32> = 1024..(4 * 1024);
+
+    fn version() -> Version {
+        Version {
+            major: 2,
+            minor: 0,
+            path: 1,
+            build: 7,
+        }
+    }
+
+    fn part(data: &[u8], position: usize) -> Frame {
+        let mut part = [0_u8; PART_SIZE];
+        let chunk = data
+            .get((position * PART_SIZE)..)
+            .unwrap_or_default()
+            .iter()
+            .take(PART_SIZE);
+        for (d, s) in part.iter_mut().zip(chunk) {
+            *d = *s;
+        }
+        Frame::FirmwareUploadPart(UploadPart::new(position, part).unwrap())
+    }
+
+    fn send(frame: Frame) -> Action {
+        Action::Send(frame)
+    }
+
+    /// Sends the whole image the way the host does, polling after every frame.
+    fn upload<const N: usize>(
+        r: &mut UpdateReceiver,
+        f: &mut MemFlash<N>,
+        data: &[u8],
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for p in 0..data.len().div_ceil(PART_SIZE) {
+            actions.extend(r.on_frame(&part(data, p)));
+            actions.extend(r.poll(f));
+        }
+        actions.extend(r.on_frame(&Frame::FirmwareUploadFinished));
+        actions.extend(r.poll(f));
+        actions
+    }
+
+    #[test]
+    fn upload_pages() {
+        let image: Vec<u8> = (0..1500_u32).map(|v| v as u8).collect();
+        let data = crate::pending_fw::tests::with_header(version(), &image);
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(SLOT);
+
+        let actions = upload(&mut r, &mut f, &data);
+        assert_eq!(
+            actions,
+            [
+                // first page
+                send(Frame::FirmwareUploadPause(true)),
+                send(Frame::FirmwareUploadPause(false)),
+                // padded last page
+                send(Frame::FirmwareUploadPause(true)),
+                Action::Finished(Some(version())),
+                send(Frame::FirmwareUploadPause(false)),
+            ]
+        );
+        assert_eq!(
+            crate::pending_fw::get(&f, SLOT.start),
+            Some((version(), &image[..]))
+        );
+        // padding
+        assert_eq!(
+            f.read(SLOT.start + data.len() as u32, 2),
+            Ok(&[0_u8, 0][..])
+        );
+    }
+
+    #[test]
+    fn page_aligned_image() {
+        // ends with both a part and a page, nothing to pad
+        let image = vec![0x55_u8; 5 * 1024 - 16];
+        let data = crate::pending_fw::tests::with_header(version(), &image);
+
+        let mut f = MemFlash::<8192>::new(1024);
+        let mut r = UpdateReceiver::new(1024..(7 * 1024));
+        let actions = upload(&mut r, &mut f, &data);
+        assert!(actions.contains(&Action::Finished(Some(version()))));
+        // nothing written behind the image
+        assert_eq!(f.read(6 * 1024, 1024), Ok(&[0xFF_u8; 1024][..]));
+    }
+
+    #[test]
+    fn change_pos() {
+        let data = [1_u8; 100];
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(SLOT);
+
+        assert_eq!(r.on_frame(&part(&data, 0)).as_slice(), []);
+        assert_eq!(r.on_frame(&part(&data, 1)).as_slice(), []);
+        // skipped part 2
+        assert_eq!(
+            r.on_frame(&part(&data, 3)).as_slice(),
+            [send(Frame::FirmwareUploadPartChangePos(
+                UploadPartChangePos::new(2).unwrap()
+            ))]
+        );
+        // going back is fine while the parts are buffered
+        assert_eq!(r.on_frame(&part(&data, 1)).as_slice(), []);
+        assert_eq!(r.poll(&mut f).as_slice(), []);
+    }
+
+    #[test]
+    fn broken_upload() {
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(SLOT);
+        let actions = upload(&mut r, &mut f, &[7_u8; 300]);
+        assert!(actions.contains(&Action::Finished(None)));
+
+        assert_eq!(r.on_frame(&Frame::FirmwareStartUpdate).as_slice(), []);
+        assert_eq!(r.poll(&mut f).as_slice(), [Action::NoPendingFirmware]);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(1024..2048);
+        let actions = upload(&mut r, &mut f, &[7_u8; 1500]);
+        assert!(actions.contains(&Action::PageWriteFailed(1)));
+        assert_eq!(f.read(2048, 4), Ok(&[0xFF_u8; 4][..]));
+    }
+
+    #[test]
+    fn image_too_large_for_the_app() {
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(SLOT);
+        // only the announced size is over, the pages fit into the slot
+        let mut data = [7_u8; 1500];
+        data[..4].clone_from_slice(&(crate::pending_fw::MAX_IMAGE_SIZE + 9).to_be_bytes());
+        let actions = upload(&mut r, &mut f, &data);
+        assert!(actions.contains(&Action::PageWriteFailed(0)));
+        assert!(actions.contains(&Action::PageWriteFailed(1)));
+        assert!(actions.contains(&Action::Finished(None)));
+        assert_eq!(f.read(SLOT.start, 4), Ok(&[0xFF_u8; 4][..]));
+
+        // the next upload starts over
+        let data = crate::pending_fw::tests::with_header(version(), &[1, 2, 3, 4]);
+        let actions = upload(&mut r, &mut f, &data);
+        assert!(actions.contains(&Action::Finished(Some(version()))));
+    }
+
+    #[test]
+    fn pending_version_and_start() {
+        let mut f = MemFlash::<4096>::new(1024);
+        let mut r = UpdateReceiver::new(SLOT);
+
+        let request = Frame::PendingFirmwareVersion(Type::Remote);
+        assert_eq!(r.on_frame(&request).as_slice(), []);
+        assert_eq!(
+            r.poll(&mut f).as_slice(),
+            [send(Frame::PendingFirmwareVersion(Type::Data(None)))]
+        );
+        assert_eq!(r.poll(&mut f).as_slice(), []);
+
+        let data = crate::pending_fw::tests::with_header(version(), &[1, 2, 3, 4]);
+        upload(&mut r, &mut f, &data);
+
+        assert_eq!(r.on_frame(&request).as_slice(), []);
+        assert_eq!(r.on_frame(&Frame::FirmwareStartUpdate).as_slice(), []);
+        assert_eq!(
+            r.poll(&mut f).as_slice(),
+            [
+                send(Frame::PendingFirmwareVersion(Type::Data(Some(version())))),
+                Action::Reboot,
+            ]
+        );
+    }
+}
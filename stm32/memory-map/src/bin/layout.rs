@@ -0,0 +1,6 @@
+//! Prints the flash regions as `name offset size` lines, used by `build_flash.py`.
+fn main() {
+    for r in memory_map::REGIONS {
+        println!("{} {:#x} {:#x}", r.name, r.offset, r.size);
+    }
+}
@@ -0,0 +1,118 @@
+//! Flash and RAM layout shared by the bootloader, the app and the build scripts.
+//! `memory.x` of both binaries is generated from it, see their `build.rs`.
+#![no_std]
+
+use core::fmt;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Region {
+    pub name: &'static str,
+    /// From the beginning of flash.
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub const fn new(name: &'static str, offset: u32, size: u32) -> Self {
+        Self { name, offset, size }
+    }
+
+    #[inline]
+    pub const fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    #[inline]
+    pub const fn address(&self) -> u32 {
+        FLASH_BASE + self.offset
+    }
+
+    pub const fn overlaps(&self, other: &Region) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+}
+
+pub const FLASH_BASE: u32 = 0x0800_0000;
+pub const FLASH_SIZE: u32 = 128 * 1024;
+pub const PAGE_SIZE: u32 = 1024;
+
+pub const RAM_BASE: u32 = 0x2000_0000;
+pub const RAM_SIZE: u32 = 20 * 1024;
+/// Left out of RAM for the bootloader/app handoff record.
+pub const HANDOFF_SIZE: u32 = 64;
+pub const HANDOFF: u32 = RAM_BASE + RAM_SIZE - HANDOFF_SIZE;
+
+pub const BOOTLOADER: Region = Region::new("bootloader", 0, 20 * 1024);
+pub const APP: Region = Region::new("app", BOOTLOADER.end(), 52 * 1024);
+/// Written by the bootloader after an install, see `helpers::app_image`.
+pub const APP_INFO: Region = Region::new("app_info", APP.end(), PAGE_SIZE);
+pub const PENDING: Region = Region::new("pending", APP_INFO.end(), 53 * 1024);
+pub const CONFIG: Region = Region::new("config", PENDING.end(), PAGE_SIZE);
+pub const JOURNAL: Region = Region::new("journal", CONFIG.end(), PAGE_SIZE);
+
+pub const REGIONS: [Region; 6] = [BOOTLOADER, APP, APP_INFO, PENDING, CONFIG, JOURNAL];
+
+const _: () = {
+    assert!(PAGE_SIZE.is_power_of_two());
+    // length, version and crc around the image
+    assert!(APP.size + 16 <= PENDING.size, "pending doesn't take a full app");
+
+    let mut i = 0;
+    while i < REGIONS.len() {
+        let r = &REGIONS[i];
+        assert!(r.size > 0, "empty region");
+        assert!((r.offset | r.size) & (PAGE_SIZE - 1) == 0, "region is not page aligned");
+        assert!(r.end() <= FLASH_SIZE, "region is out of flash");
+
+        let mut j = i + 1;
+        while j < REGIONS.len() {
+            assert!(!r.overlaps(&REGIONS[j]), "regions overlap");
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+/// Linker script for a binary placed into `flash`. The linker fails when the image
+/// doesn't fit into the region.
+pub fn write_memory_x(w: &mut impl fmt::Write, flash: Region) -> fmt::Result {
+    write!(
+        w,
+        "/* Generated from memory-map, don't edit. Image region: {} */\n\
+         MEMORY\n\
+         {{\n  \
+         FLASH : ORIGIN = {:#010X}, LENGTH = {}\n  \
+         RAM : ORIGIN = {:#010X}, LENGTH = {}\n\
+         }}\n",
+        flash.name,
+        flash.address(),
+        flash.size,
+        RAM_BASE,
+        RAM_SIZE - HANDOFF_SIZE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!(APP.address(), 0x08005000);
+        assert_eq!(APP_INFO.offset, 72 * 1024);
+        assert_eq!(PENDING.offset, 73 * 1024);
+        assert_eq!(JOURNAL.end(), FLASH_SIZE);
+        assert!(APP.overlaps(&Region::new("x", 30 * 1024, PAGE_SIZE)));
+        assert!(!APP.overlaps(&APP_INFO));
+    }
+
+    #[test]
+    fn memory_x() {
+        extern crate std;
+
+        let mut s = std::string::String::new();
+        write_memory_x(&mut s, APP).unwrap();
+        assert!(s.contains("FLASH : ORIGIN = 0x08005000, LENGTH = 53248\n"));
+        assert!(s.contains("RAM : ORIGIN = 0x20000000, LENGTH = 20416\n"));
+    }
+}
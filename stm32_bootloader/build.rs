@@ -0,0 +1,13 @@
+use std::{env, fs, path::PathBuf};
+
+/// Puts `memory.x` generated from the shared memory map into the linker search path.
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let mut memory_x = String::new();
+    memory_map::write_memory_x(&mut memory_x, memory_map::BOOTLOADER).unwrap();
+    fs::write(out.join("memory.x"), memory_x).unwrap();
+
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -29,10 +29,20 @@ use stm32f1xx_hal::{
 };
 use cortex_m_rt::entry;
 use core::fmt::Write;
+use canbus_common::frames::boot_info::BootReason;
+use canbus_common::frames::version::Version;
 
-const PAGE_SIZE: u32 = 1024;
-const FW_BEGIN: u32 = 20 * 1024;
-const NEW_FW_BEGIN: u32 = FW_BEGIN + (53 * 1024);
+mod recovery;
+
+const FLASH_BASE: u32 = memory_map::FLASH_BASE;
+const FW_BEGIN: u32 = memory_map::APP.offset;
+
+const BOOTLOADER_VERSION: Version = Version {
+    major: 0,
+    minor: 1,
+    path: 0,
+    build: 0,
+};
 
 #[entry]
 fn main() -> ! {
@@ -46,6 +56,7 @@ fn main() -> ! {
 
     let mut afio = dev_p.AFIO.constrain();
     let mut gpioa = dev_p.GPIOA.split();
+    let mut led = gpioa.pa2.into_push_pull_output(&mut gpioa.crl);
 
     let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
     let rx = gpioa.pa10;
@@ -60,45 +71,117 @@ fn main() -> ! {
 
     serial.bwrite_all(b"...Bootloader stated...\r\n");
 
-    let pf = helpers::pending_fw::get(NEW_FW_BEGIN);
-    match pf {
-        Some(v) => {
-            write!(serial, "Updating firmware to {}.{}.{} {}\r\n", v.0.major, v.0.minor, v.0.path, v.0.build).unwrap();
-
-            let mut w = flash.writer(stm32f1xx_hal::flash::SectorSize::Sz1K, stm32f1xx_hal::flash::FlashSize::Sz128K);
-
-            for (page_n, data) in v.1.chunks(1024).enumerate() {
-                let page_p = FW_BEGIN + (PAGE_SIZE * page_n as u32);
-                if let Err(e) = w.page_erase(page_p) {
-                    write!(serial, "Erase error {:?}\r\n", e).unwrap();
-                }
+    let mut w = helpers::flash::writer(&mut flash);
+    let unrecorded = cfg!(feature = "unrecorded-app");
+    let boot = helpers::boot::boot(&mut w, helpers::handoff::read(), boot_reason(), BOOTLOADER_VERSION, unrecorded);
+    drop(w);
+    write!(serial, "Boot reason {:?}\r\n", boot.handoff.boot_reason).unwrap();
 
-                if let Err(e) = w.write(page_p, data) {
-                    write!(serial, "Write error {:?}\r\n", e).unwrap();
-                }
-            }
+    if boot.skipped.is_some() {
+        serial.bwrite_all(b"Pending firmware failed to install\r\n").unwrap();
+    }
+    if let Some(install) = boot.install {
+        let version = install.version;
+        write!(serial, "Updating firmware to {}.{}.{} {}\r\n", version.major, version.minor, version.path, version.build).unwrap();
+        match install.result {
+            Ok(_) => {}
+            Err(helpers::app_image::InstallError::Verify(pos)) => write!(serial, "Error from {:?}\r\n", pos).unwrap(),
+            Err(e) => write!(serial, "Copy error {:?}\r\n", e).unwrap(),
+        }
+        if let Some(e) = install.erase_error {
+            write!(serial, "FW erase error {:?}\r\n", e).unwrap();
+        }
+    }
+    match boot.app {
+        Some(Ok(Some(info))) => write!(serial, "Firmware {}.{}.{} {}\r\n", info.version.major, info.version.minor, info.version.path, info.version.build).unwrap(),
+        Some(Ok(None)) => serial.bwrite_all(b"Firmware without info\r\n").unwrap(),
+        Some(Err(e)) => write!(serial, "Invalid firmware {:?}\r\n", e).unwrap(),
+        None => {}
+    }
 
-            let flash_data = unsafe {
-                core::slice::from_raw_parts(&*(FW_BEGIN as *const u8), v.1.len())
-            };
+    helpers::handoff::write(boot.handoff);
+    let recovery = match boot.decision {
+        // the pending image is still there, try again
+        helpers::boot::Decision::Reset => cortex_m::peripheral::SCB::sys_reset(),
+        helpers::boot::Decision::Recovery(v) => Some(v),
+        helpers::boot::Decision::Jump => None,
+    };
 
-            for (pos, (v1, v2)) in flash_data.iter().zip(v.1).enumerate() {
-                if v1 != v2 {
-                    write!(serial, "Error from {:?}\r\n", pos).unwrap();
-                    cortex_m::peripheral::SCB::sys_reset();
+    if let Some(reason) = recovery {
+        // no usable app or the app asked for it, stay here and wait for a new one over CAN
+        match reason {
+            helpers::boot::Recovery::Requested => serial.bwrite_all(b"Requested by the app\r\n").unwrap(),
+            helpers::boot::Recovery::NotComingUp => write!(serial, "App didn't come up {} times\r\n", boot.handoff.boot_attempts).unwrap(),
+            _ => {}
+        }
+        serial.bwrite_all(b"Recovery mode\r\n").unwrap();
+
+        let mut cp = cortex_m::Peripherals::take().unwrap();
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+
+        let can = Can::new(dev_p.CAN1, dev_p.USB);
+
+        let mut gpiob = dev_p.GPIOB.split();
+        let can_rx_pin = gpiob.pb8.into_floating_input(&mut gpiob.crh);
+        let can_tx_pin = gpiob.pb9.into_alternate_push_pull(&mut gpiob.crh);
+        can.assign_pins((can_tx_pin, can_rx_pin), &mut afio.mapr);
+
+        // APB1 (PCLK1): 8MHz from HSI, same bit rate and sample point as the app
+        let mut can = bxcan::Can::builder(can)
+            .set_bit_timing(0x001c0003)
+            .leave_disabled();
+        can.modify_filters()
+            .enable_bank(0, bxcan::Fifo::Fifo0, bxcan::filter::Mask32::accept_all());
+        nb::block!(can.enable_non_blocking()).unwrap();
+
+        let config =
+            helpers::config::read(&helpers::flash::writer(&mut flash), memory_map::CONFIG.offset);
+        let mut recovery = recovery::Recovery::new(can, config.dyn_id);
+
+        // three short blinks and a pause
+        let blink = clocks.sysclk().raw() / 10;
+        let mut last = cortex_m::peripheral::DWT::cycle_count();
+        let mut step = 0_u32;
+        loop {
+            recovery.poll(&mut flash, &mut serial);
+
+            if cortex_m::peripheral::DWT::cycle_count().wrapping_sub(last) >= blink {
+                last = cortex_m::peripheral::DWT::cycle_count();
+                step = (step + 1) % 16;
+                match step < 6 && step % 2 == 0 {
+                    true => led.set_high(),
+                    false => led.set_low(),
                 }
             }
-
-            // erase
-            if let Err(e) = w.page_erase(NEW_FW_BEGIN) {
-                write!(serial, "FW erase error {:?}\r\n", e).unwrap();
-            }
-        },
-        None => {},
-    };
+        }
+    }
 
     serial.bwrite_all(b"Jump\r\n");
-    jump_to_main(0x8000000 + FW_BEGIN);
+    jump_to_main(FLASH_BASE + FW_BEGIN);
+
+    fn boot_reason() -> BootReason {
+        let csr = unsafe { &(*pac::RCC::ptr()).csr };
+        let flags = csr.read();
+        // the pin flag is set on every kind of reset, check it last
+        let reason = if flags.lpwrrstf().bit_is_set() {
+            BootReason::LowPower
+        } else if flags.wwdgrstf().bit_is_set() {
+            BootReason::WindowWatchdog
+        } else if flags.iwdgrstf().bit_is_set() {
+            BootReason::IndependentWatchdog
+        } else if flags.sftrstf().bit_is_set() {
+            BootReason::Software
+        } else if flags.porrstf().bit_is_set() {
+            BootReason::PowerOn
+        } else if flags.pinrstf().bit_is_set() {
+            BootReason::Pin
+        } else {
+            BootReason::Unknown
+        };
+        csr.modify(|_, w| w.rmvf().set_bit());
+        reason
+    }
 
     fn jump_to_main(address: u32) {
         unsafe {
@@ -0,0 +1,117 @@
+use canbus_common::frames::{self, Frame, Type};
+use canbus_common::identifier::Header;
+use canbus_common::{addressing, frame_id};
+use core::fmt::Write;
+use helpers::update_receiver::{Action, UpdateReceiver};
+use stm32f1xx_hal::{can::Can, flash, pac::CAN1};
+
+/// Minimal polling CAN node, enough to re-flash the device when the app is not usable.
+pub struct Recovery {
+    can: bxcan::Can<Can<CAN1>>,
+    sub_id: frame_id::SubId,
+    update: UpdateReceiver,
+}
+
+impl Recovery {
+    /// `dyn_id` is the one the app stored in the config page.
+    pub fn new(can: bxcan::Can<Can<CAN1>>, dyn_id: Option<u8>) -> Self {
+        Self {
+            can,
+            sub_id: dyn_id.map_or(frame_id::SubId(0), |v| {
+                frame_id::SubId::for_node(&helpers::DEVICE_SERIAL, v)
+            }),
+            update: UpdateReceiver::new(memory_map::PENDING.offset..memory_map::PENDING.end()),
+        }
+    }
+
+    pub fn poll<W: Write>(&mut self, flash: &mut flash::Parts, serial: &mut W) {
+        match self.can.receive() {
+            Ok(frame) => {
+                if let Some((frame, header)) = from_bx_frame(&frame) {
+                    let own = helpers::ID_LAYOUT.carried(self.sub_id);
+                    match addressing::check(&frame, header.to, own) {
+                        addressing::Verdict::Act => self.handle(frame),
+                        addressing::Verdict::Ignore => {}
+                        addressing::Verdict::Reject(e) => self.send(Frame::NodeError(e)),
+                    }
+                }
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(e)) => {
+                write!(serial, "rx overrun {:?}\r\n", e).unwrap();
+            }
+        }
+
+        for action in self.update.poll(&mut helpers::flash::writer(flash)) {
+            match action {
+                Action::Send(frame) => self.send(frame),
+                Action::Finished(_) => serial.write_str("Finished\r\n").unwrap(),
+                Action::PageWriteFailed(n) => write!(serial, "page {} write failed\r\n", n).unwrap(),
+                Action::NoPendingFirmware => serial.write_str("Has no pending fw !!!\r\n").unwrap(),
+                Action::Reboot => {
+                    // a new image, the bootloader tries to install it again
+                    if let Some(mut handoff) = helpers::handoff::read() {
+                        handoff.install_attempts = 0;
+                        helpers::handoff::write(handoff);
+                    }
+                    serial.write_str("Reboot to upgrade...\r\n").unwrap();
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, frame: Frame) {
+        let raw = frame.raw_frame();
+        let id = helpers::ID_LAYOUT.encode(&Header::from_node(&frame, self.sub_id));
+        let id = bxcan::ExtendedId::new(id).unwrap();
+        let f = match raw.1 {
+            frames::RawType::Data(v) => bxcan::Frame::new_data(id, bxcan::Data::new(&v).unwrap()),
+            frames::RawType::Remote(len) => bxcan::Frame::new_remote(id, len),
+        };
+        let _ = nb::block!(self.can.transmit(&f));
+    }
+
+    fn handle(&mut self, frame: Frame) {
+        match frame {
+            Frame::Serial(Type::Remote) => {
+                self.send(Frame::Serial(Type::Data(helpers::DEVICE_SERIAL)));
+            }
+            Frame::Status(Type::Remote) => {
+                self.send(Frame::Status(Type::Data(frames::status::Status::new(
+                    frames::status::Mode::Bootloader,
+                ))));
+            }
+            Frame::DynId(value) => {
+                if value.serial == helpers::DEVICE_SERIAL {
+                    self.sub_id = frame_id::SubId::for_node(&value.serial, value.dyn_id);
+                }
+            }
+            frame => {
+                for action in self.update.on_frame(&frame) {
+                    if let Action::Send(frame) = action {
+                        self.send(frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The frame with the header it came with.
+fn from_bx_frame(f: &bxcan::Frame) -> Option<(Frame, Header)> {
+    let header = match f.id() {
+        bxcan::Id::Extended(id) => helpers::ID_LAYOUT.decode(id.as_raw()),
+        _ => None,
+    }?;
+
+    Frame::parse_frame(
+        header.frame_id()?,
+        match f.data() {
+            Some(data) => frames::ParserType::Data(data),
+            None => frames::ParserType::Remote(f.dlc()),
+        },
+    )
+    .ok()
+    .map(|frame| (frame, header))
+}